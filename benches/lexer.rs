@@ -0,0 +1,45 @@
+//! Lexer throughput over the example programs.
+//!
+//! Run with `cargo bench --bench lexer`. Each example is also lexed at 4x and 16x its size, which
+//! should take roughly 4x and 16x as long if lexing is linear.
+
+use std::fs;
+use std::time::{Duration, Instant};
+
+use minlang2::lexer::Lexer;
+
+const ITERATIONS: u32 = 20;
+
+fn time_lex(source: &str) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let tokens = Lexer::new(source.to_string()).lex().expect("example should lex").tokens;
+        std::hint::black_box(tokens);
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let mut paths: Vec<_> = fs::read_dir("examples")
+        .expect("run from the crate root")
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "mini"))
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        let source = fs::read_to_string(&path).unwrap();
+        // Repeat everything after the header so the result still lexes
+        let (header, body) = source.split_at(source.find(']').map(|idx| idx + 1).unwrap_or(0));
+
+        for scale in [1, 4, 16] {
+            let scaled = format!("{}{}", header, body.repeat(scale));
+            let elapsed = time_lex(&scaled);
+            let mib_per_sec = scaled.len() as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+            println!(
+                "{:<32} x{:<3} {:>9} bytes {:>12.3?} {:>10.1} MiB/s",
+                path.display(), scale, scaled.len(), elapsed, mib_per_sec
+            );
+        }
+    }
+}
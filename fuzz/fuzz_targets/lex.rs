@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use minlang2::lexer::{Lexer, LexerOptions};
+
+fuzz_target!(|data: (bool, bool, &str)| {
+    let (keep_comments, strict, source) = data;
+    let options = LexerOptions { keep_comments, strict };
+
+    if let Ok(stream) = Lexer::with_options(source.to_string(), options).lex() {
+        for tok in stream.tokens {
+            assert!(source.get(tok.loc.start..tok.loc.end).is_some());
+        }
+    }
+});
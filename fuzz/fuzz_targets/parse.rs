@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use minlang2::lexer::Lexer;
+use minlang2::macros;
+use minlang2::parser::Parser;
+
+fuzz_target!(|source: &str| {
+    if let Ok(stream) = Lexer::new(source.to_string()).lex() {
+        if let Ok(tokens) = macros::expand(stream.tokens) {
+            let _ = Parser::new(tokens).parse_program();
+        }
+    }
+});
@@ -1,7 +1,17 @@
 // digit = "0".."9" ;
 // number = { digit } ;
+// letter = "a".."z" | "A".."Z" ;
+// name = letter, { letter | digit | "_" } ;
+// FunctionId = number | name ;
+// Register = "$", [number] ;
+// Cell = number | Register | name, ["=", number] ;
+// escape = "\\", ("n" | "t" | "r" | "0" | "\\" | "'") ;
+// Value = ["-"], number | "'", (escape | ? any character but "'", "\\" or newline ?), "'" ;
+// Comparison = "!" | "<" | "<=" | ">" | ">=" ;
+// Condition = [Comparison], Value | Register ;
 
-// TableStmt = "[", number, "]" ;
+// TableOption = "version", "=", ("1" | "2") | "cells", "=", ("native" | "byte") | "tape", "=", ("clamp" | "wrap") ;
+// TableStmt = "[", number, { ",", TableOption }, "]" ;
 // IncStmt = "+" ;
 // DecStmt = "-" ;
 // MulStmt = "*" ;
@@ -9,36 +19,122 @@
 // PrintStmt = "." ; 
 // MovRightStmt = ">" ;
 // MovLeftStmt = "<" ;
-// LoopStmt = "{", Block, "}", ["(", number | "$", ")"] ;
-// DefStmt = "[", number, "]" ;
-// IfStmt = "?", "(", number | "$", ")", Block, Eos ;
-// GotoStmt = "@", "(", number | "$", ")" ;
-// SaveStmt = "$" ;
+// LoopStmt = "{", Block, "}", ["(", Condition, ")"] ;
+// DefStmt = "[", Value, "]" ;
+// IfStmt = "?", "(", Condition, ")", Block, [":", Block], Eos ;
+// GotoStmt = "@", "(", Cell, ")" ;
+// SaveStmt = Register ;
 // PrintAsciiStmt = "&" ;
-// CopyStmt = "=", "(", number | "$", ")" ;
+// CopyStmt = "=", "(", Cell, ")" ;
 // ModuloStmt = "%" ;
-// DefFnStmt = ":", "(", number, ")", Block, Eos ;
-// CallFnStmt = "^", "(", number, ")" ;
+// DefFnStmt = ":", "(", FunctionId, ")", ["(", number, ")"], Block, Eos ;
+// CallFnStmt = "^", "(", FunctionId, ")" ;
 // PrintNewlStmt = "_" ;
 // FlipStmt = "~" ;
+// InputStmt = "!" ;
+// BreakStmt = "|" ;
+// ContinueStmt = "`" ;
+// ReturnStmt = "^" ; (* not followed by "(" *)
 // Eos = ";" ;
 
+// LineComment = "#", { ? any character except newline ? } ;
+// BlockComment = "#|", { BlockComment | ? any character ? }, "|#" ;
+
+// Expanded before parsing:
+// MacroName = "\\", name ;
+// MacroDef = ":", MacroName, ["(", [MacroName, { ",", MacroName }], ")"], Block, Eos ;
+// MacroCall = MacroName, ["(", [Tokens, { ",", Tokens }], ")"] ;
+// Include = '"', { ? any character but '"' ? }, '"' ;
+
 // SourceFile = TableStmt, "\n" | "\r", Block ;
 // Block = { Statement } ;
 // Statement = TableStmt | IncStmt | DecStmt | MulStmt | DivStmt | PrintStmt | MovRightStmt | MovLeftStmt | LoopStmt | FlipStmt
-//             | DefStmt | IfStmt | GotoStmt | SaveStmt | PrintAsciiStmt | CopyStmt | ModuloStmt | DefFnStmt | CallFnStmt | PrintNewlStmt ;
+//             | DefStmt | IfStmt | GotoStmt | SaveStmt | PrintAsciiStmt | CopyStmt | ModuloStmt | DefFnStmt | CallFnStmt | PrintNewlStmt
+//             | InputStmt | BreakStmt | ContinueStmt | ReturnStmt ;
+
+use std::fmt::Display;
 
-use crate::lexer::Token;
+use crate::lexer::{Token, TokenKind};
+use crate::program::Header;
 
-#[derive(Debug, Clone, Copy)]
-pub(crate) enum ParameterKind {
-    Numeric(usize),
-    Saved,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParameterKind {
+    Numeric(isize),
+    /// The cell a save register points at.
+    Saved(usize),
 }
 
-#[derive(Debug, Clone)]
-pub(crate) enum StatementKind {
-    Table(usize),
+impl Display for ParameterKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Numeric(val) => f.write_fmt(format_args!("{}", val)),
+            Self::Saved(0) => f.write_fmt(format_args!("$")),
+            Self::Saved(register) => f.write_fmt(format_args!("${}", register)),
+        }
+    }
+}
+
+/// How the current cell is compared against a condition's parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Comparison {
+    #[default]
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl Comparison {
+    pub(crate) fn holds(&self, value: isize, against: isize) -> bool {
+        match self {
+            Self::Equal => value == against,
+            Self::NotEqual => value != against,
+            Self::Less => value < against,
+            Self::LessEqual => value <= against,
+            Self::Greater => value > against,
+            Self::GreaterEqual => value >= against,
+        }
+    }
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Equal => Ok(()),
+            Self::NotEqual => f.write_fmt(format_args!("!")),
+            Self::Less => f.write_fmt(format_args!("<")),
+            Self::LessEqual => f.write_fmt(format_args!("<=")),
+            Self::Greater => f.write_fmt(format_args!(">")),
+            Self::GreaterEqual => f.write_fmt(format_args!(">=")),
+        }
+    }
+}
+
+/// What an if tests, or what a loop runs until: the current cell compared against a parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub(crate) comparison: Comparison,
+    pub(crate) param: ParameterKind,
+}
+
+impl Condition {
+    /// The current cell equals `param`, which is all conditions could say before comparisons.
+    pub(crate) fn equal(param: ParameterKind) -> Condition {
+        Condition { comparison: Comparison::Equal, param }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}{}", self.comparison, self.param))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatementKind {
+    Table(Header),
     Inc,
     Dec,
     Mul,
@@ -46,29 +142,105 @@ pub(crate) enum StatementKind {
     MovR,
     MovL,
     Print,
-    Loop(Vec<Statement>, Option<ParameterKind>),
-    Define(usize),
-    If(ParameterKind, Vec<Statement>),
+    /// Runs its body until the condition holds, or the current cell is zero without one.
+    Loop(Vec<Statement>, Option<Condition>),
+    Define(isize),
+    /// Runs the first block if the condition holds, and the else block, if any, if it doesn't.
+    If(Condition, Vec<Statement>, Option<Vec<Statement>>),
     Goto(ParameterKind),
-    Save,
+    /// Points a save register at the current cell.
+    Save(usize),
     PrintAscii,
     Copy(ParameterKind),
     Modulo,
-    DefineFn(usize, Vec<Statement>),
+    /// A function's id, how many local cells it gets if it runs in its own frame, and its body.
+    DefineFn(usize, Option<usize>, Vec<Statement>),
     CallFn(usize),
     PrintNewline,
     FlipSign,
+    Input,
+    Break,
+    Continue,
+    Return,
+    /// Only produced by `Parser::with_comments`; does nothing when run.
+    Comment(String),
     End,
 }
 
+impl StatementKind {
+    /// The token a statement of this kind starts with, standing in for the one it was parsed
+    /// from when it's read back from something other than source.
+    pub(crate) fn token_kind(&self) -> TokenKind {
+        match self {
+            StatementKind::Table(header) => TokenKind::Table(*header),
+            StatementKind::Inc => TokenKind::Inc,
+            StatementKind::Dec => TokenKind::Dec,
+            StatementKind::Mul => TokenKind::Mul,
+            StatementKind::Div => TokenKind::Div,
+            StatementKind::MovR => TokenKind::MovR,
+            StatementKind::MovL => TokenKind::MovL,
+            StatementKind::Print => TokenKind::Print,
+            StatementKind::Loop(..) => TokenKind::StartLoop,
+            StatementKind::Define(_) => TokenKind::StartDefine,
+            StatementKind::If(..) => TokenKind::Question,
+            StatementKind::Goto(_) => TokenKind::Goto,
+            StatementKind::Save(register) => TokenKind::Save(*register),
+            StatementKind::PrintAscii => TokenKind::PrintAscii,
+            StatementKind::Copy(_) => TokenKind::CopyTo,
+            StatementKind::Modulo => TokenKind::Modulo,
+            StatementKind::DefineFn(..) => TokenKind::FuncDef,
+            StatementKind::CallFn(_) | StatementKind::Return => TokenKind::FuncCall,
+            StatementKind::PrintNewline => TokenKind::Newline,
+            StatementKind::FlipSign => TokenKind::Tilde,
+            StatementKind::Input => TokenKind::Bang,
+            StatementKind::Break => TokenKind::Pipe,
+            StatementKind::Continue => TokenKind::Backtick,
+            StatementKind::Comment(text) => TokenKind::Comment(text.clone()),
+            StatementKind::End => TokenKind::Eos,
+        }
+    }
+}
+
+/// A name written in place of a function id or cell index, or a character written in place of
+/// a number. The statement holds the number it stands for; how it was written is kept so tools
+/// like the formatter can write it back out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Name {
+    pub(crate) name: String,
+    /// Whether the name is bound to its cell here, as in `@(counter = 3)`.
+    pub(crate) binds: bool,
+}
+
 #[derive(Debug, Clone)]
-pub(crate) struct Statement {
-    pub(crate) kind: StatementKind,
+pub struct Statement {
+    pub kind: StatementKind,
     pub(crate) token: Token,
+    pub(crate) name: Option<Name>,
 }
 
 impl Statement {
     pub(crate) fn new(kind: StatementKind, token: Token) -> Statement {
-        Statement { kind, token }
+        Statement { kind, token, name: None }
+    }
+
+    pub(crate) fn named(kind: StatementKind, token: Token, name: Option<Name>) -> Statement {
+        Statement { kind, token, name }
     }
-}
\ No newline at end of file
+}
+
+/// Statements compare by shape only; where in the source they came from doesn't matter.
+impl PartialEq for Statement {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+/// How many statements a block holds, counting everything nested in it. This is how many
+/// instructions it takes up in bytecode and in the VM, which number them in the same order.
+pub(crate) fn size(stmts: &[Statement]) -> usize {
+    stmts.iter().map(|stmt| 1 + match &stmt.kind {
+        StatementKind::Loop(body, _) | StatementKind::DefineFn(_, _, body) => size(body),
+        StatementKind::If(_, then, otherwise) => size(then) + otherwise.as_deref().map_or(0, size),
+        _ => 0,
+    }).sum()
+}
@@ -0,0 +1,16 @@
+//! Language server for minlang, over stdio.
+
+use std::io;
+use std::process::exit;
+
+use minlang2::lsp::Server;
+
+fn main() {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+
+    if let Err(err) = Server::new().serve(stdin.lock(), stdout.lock()) {
+        eprintln!("minlang2-lsp: {}", err);
+        exit(1);
+    }
+}
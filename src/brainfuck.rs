@@ -0,0 +1,258 @@
+//! Translation between brainfuck and minlang.
+//!
+//! Brainfuck's eight commands all have a direct minlang counterpart: `+ - < >` are the same,
+//! `[ ]` is a `{ }` loop against zero, `.` is `&` and `,` is `!`. Brainfuck cells are bytes that
+//! wrap around, so translated programs get a table of `CellWidth::Byte` cells.
+//!
+//! Going the other way only works for programs that stick to those operations, on a table of
+//! byte cells whose cursor stops at the ends like brainfuck's tape. Defines and
+//! function calls are expanded since brainfuck has neither, but anything that needs a spare
+//! cell or an absolute position, like `.`, `?` or `@`, can't be translated, and neither can
+//! leaving a loop or function early.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use crate::ast::{Comparison, Condition, ParameterKind, Statement, StatementKind};
+use crate::lexer::{Token, TokenKind, TokenLocation};
+use crate::parser::MAX_NESTING;
+use crate::program::{CellWidth, Header, Program, Table, TapePolicy};
+
+/// The traditional size of a brainfuck tape.
+pub(crate) const TAPE_SIZE: usize = 30000;
+
+#[derive(Debug)]
+pub enum BrainfuckError {
+    UnmatchedOpen(TokenLocation),
+    UnmatchedClose(TokenLocation),
+    NestedTooDeeply(TokenLocation),
+    Untranslatable(Token),
+    /// A table whose cells or tape behave differently from brainfuck's.
+    UntranslatableTable(Header),
+    RecursiveCall(Token),
+    UndefinedFunction(Token),
+}
+
+impl Display for BrainfuckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BrainfuckError::UnmatchedOpen(loc) => {
+                f.write_fmt(format_args!("Unmatched '[' at line {} col {}.", loc.line, loc.col))
+            },
+            BrainfuckError::UnmatchedClose(loc) => {
+                f.write_fmt(format_args!("Unmatched ']' at line {} col {}.", loc.line, loc.col))
+            },
+            BrainfuckError::NestedTooDeeply(loc) => {
+                f.write_fmt(format_args!("Loops nested more than {} deep at line {} col {}.", MAX_NESTING, loc.line, loc.col))
+            },
+            BrainfuckError::Untranslatable(token) => {
+                f.write_fmt(format_args!("'{}' at line {} col {} has no brainfuck equivalent.", token.kind, token.loc.line, token.loc.col))
+            },
+            BrainfuckError::UntranslatableTable(header) => {
+                f.write_fmt(format_args!("A [{}] table has no brainfuck equivalent; brainfuck needs byte cells and a tape that stops at its ends, as in [n, cells=byte].", header))
+            },
+            BrainfuckError::RecursiveCall(token) => {
+                f.write_fmt(format_args!("Recursive call at line {} col {} can't be inlined into brainfuck.", token.loc.line, token.loc.col))
+            },
+            BrainfuckError::UndefinedFunction(token) => {
+                f.write_fmt(format_args!("Call to an undefined function at line {} col {}.", token.loc.line, token.loc.col))
+            },
+        }
+    }
+}
+
+/// Translate brainfuck source into a minlang program. Anything that isn't a command is ignored.
+pub fn from_brainfuck(source: &str) -> Result<Program, BrainfuckError> {
+    // Each open loop's statements so far, with the token that opened it
+    let mut blocks: Vec<(Option<Token>, Vec<Statement>)> = vec![(None, Vec::new())];
+    let (mut line, mut col) = (1usize, 1usize);
+
+    for (start, chr) in source.char_indices() {
+        let loc = TokenLocation::new(line, col, start, start + chr.len_utf8());
+        if chr == '\n' {
+            line += 1;
+            col = 1;
+        } else {
+            col += 1;
+        }
+
+        let (token_kind, kind) = match chr {
+            '+' => (TokenKind::Inc, StatementKind::Inc),
+            '-' => (TokenKind::Dec, StatementKind::Dec),
+            '>' => (TokenKind::MovR, StatementKind::MovR),
+            '<' => (TokenKind::MovL, StatementKind::MovL),
+            '.' => (TokenKind::PrintAscii, StatementKind::PrintAscii),
+            ',' => (TokenKind::Bang, StatementKind::Input),
+            '[' => {
+                if blocks.len() > MAX_NESTING {
+                    return Err(BrainfuckError::NestedTooDeeply(loc));
+                }
+                blocks.push((Some(Token::new(TokenKind::StartLoop, loc)), Vec::new()));
+                continue;
+            },
+            ']' => {
+                if blocks.len() == 1 {
+                    return Err(BrainfuckError::UnmatchedClose(loc));
+                }
+                let (open, body) = blocks.pop().unwrap();
+                blocks.last_mut().unwrap().1.push(Statement::new(StatementKind::Loop(body, None), open.unwrap()));
+                continue;
+            },
+            _ => continue,
+        };
+
+        blocks.last_mut().unwrap().1.push(Statement::new(kind, Token::new(token_kind, loc)));
+    }
+
+    if blocks.len() > 1 {
+        let (open, _) = blocks.pop().unwrap();
+        return Err(BrainfuckError::UnmatchedOpen(open.unwrap().loc));
+    }
+
+    let mut table = Table::new(TAPE_SIZE);
+    table.width = CellWidth::Byte;
+    let (_, statements) = blocks.pop().unwrap();
+
+    Ok(Program::new(statements, BTreeMap::new(), table))
+}
+
+fn push_statements(stmts: &[Statement], program: &Program, calls: &mut Vec<usize>, out: &mut String) -> Result<(), BrainfuckError> {
+    for stmt in stmts {
+        match &stmt.kind {
+            StatementKind::Inc => out.push('+'),
+            StatementKind::Dec => out.push('-'),
+            StatementKind::MovR => out.push('>'),
+            StatementKind::MovL => out.push('<'),
+            StatementKind::PrintAscii => out.push('.'),
+            StatementKind::Input => out.push(','),
+            StatementKind::Loop(body, None | Some(Condition { comparison: Comparison::Equal, param: ParameterKind::Numeric(0) })) => {
+                out.push('[');
+                push_statements(body, program, calls, out)?;
+                out.push(']');
+            },
+            StatementKind::Define(val) => {
+                // Clear the cell, then count up to the value, which the cell wraps to
+                out.push_str("[-]");
+                out.push_str(&"+".repeat(val.rem_euclid(256) as usize));
+            },
+            StatementKind::CallFn(id) => {
+                if calls.contains(id) {
+                    return Err(BrainfuckError::RecursiveCall(stmt.token.clone()));
+                }
+                let (locals, body) = match program.functions.get(id).map(|def| &def.kind) {
+                    Some(StatementKind::DefineFn(_, locals, body)) => (locals, body),
+                    _ => return Err(BrainfuckError::UndefinedFunction(stmt.token.clone())),
+                };
+                // A frame has to be found at run time, and brainfuck can't address cells that way
+                if locals.is_some() {
+                    return Err(BrainfuckError::Untranslatable(stmt.token.clone()));
+                }
+
+                calls.push(*id);
+                push_statements(body, program, calls, out)?;
+                calls.pop();
+            },
+            // Defining a function doesn't run it, and comments don't run at all
+            StatementKind::DefineFn(..) | StatementKind::Comment(_) | StatementKind::End => {},
+            _ => return Err(BrainfuckError::Untranslatable(stmt.token.clone())),
+        }
+    }
+
+    Ok(())
+}
+
+/// Translate a minlang program into brainfuck, if it only uses operations brainfuck has and its
+/// table behaves like brainfuck's.
+pub fn to_brainfuck(program: &Program) -> Result<String, BrainfuckError> {
+    if program.table.width != CellWidth::Byte || program.table.tape != TapePolicy::Clamp {
+        return Err(BrainfuckError::UntranslatableTable(program.header()));
+    }
+
+    let mut out = String::new();
+    push_statements(&program.statements, program, &mut Vec::new(), &mut out)?;
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::printer::Printer;
+    use crate::program::{CellWidth, Program};
+    use crate::vm::Vm;
+
+    use super::{from_brainfuck, to_brainfuck, BrainfuckError};
+
+    const HELLO: &str = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+
+    #[test]
+    fn test_hello_world() {
+        let program = from_brainfuck(HELLO).unwrap();
+        assert_eq!(program.table.width, CellWidth::Byte);
+
+        let mut vm = Vm::with_output(program, Vec::new());
+        vm.run(false);
+        assert_eq!(vm.output, b"Hello World!\n");
+    }
+
+    #[test]
+    fn test_cells_wrap() {
+        let mut vm = Vm::with_io(from_brainfuck("-.>,.,.").unwrap(), &b"A"[..], Vec::new());
+        vm.run(false);
+
+        // 0 - 1 wraps to 255, and input past the end reads zero
+        assert_eq!(vm.output, "\u{FF}A\0".as_bytes());
+    }
+
+    #[test]
+    fn test_unmatched_brackets() {
+        assert!(matches!(from_brainfuck("+[\n[-]"), Err(BrainfuckError::UnmatchedOpen(loc)) if loc.line == 1 && loc.col == 2));
+        assert!(matches!(from_brainfuck("+]"), Err(BrainfuckError::UnmatchedClose(_))));
+    }
+
+    #[test]
+    fn test_to_brainfuck() {
+        let program = Program::parse_str("[8, cells=byte]\n:(0)+++;[2]{>^(0)<-}>&!");
+        assert_eq!(to_brainfuck(&program).unwrap(), "[-]++[>+++<-]>.,");
+
+        assert!(matches!(to_brainfuck(&Program::parse_str("[8, cells=byte]\n+.")), Err(BrainfuckError::Untranslatable(_))));
+        assert!(matches!(to_brainfuck(&Program::parse_str("[8, cells=byte]\n:(0)^(0);^(0)")), Err(BrainfuckError::RecursiveCall(_))));
+    }
+
+    #[test]
+    fn test_table_must_match() {
+        assert_eq!(to_brainfuck(&Program::parse_str("[8, cells=byte]\n[-1]")).unwrap(), format!("[-]{}", "+".repeat(255)));
+
+        // Native cells don't wrap, so neither of these means what its brainfuck would
+        for source in ["[8]\n[-1]", "[8]\n[300]", "[8]\n+"] {
+            assert!(matches!(to_brainfuck(&Program::parse_str(source)), Err(BrainfuckError::UntranslatableTable(_))), "{}", source);
+        }
+        let err = to_brainfuck(&Program::parse_str("[8, cells=byte, tape=wrap]\n>")).unwrap_err();
+        assert_eq!(err.to_string(), "A [8, cells=byte, tape=wrap] table has no brainfuck equivalent; brainfuck needs byte cells and a tape that stops at its ends, as in [n, cells=byte].");
+    }
+
+    #[test]
+    fn test_hello_world_round_trips() {
+        let program = from_brainfuck(HELLO).unwrap();
+        let printed = Printer::new().print_program(&program);
+
+        assert_eq!(to_brainfuck(&Program::parse_str(&printed)).unwrap(), HELLO);
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(body in "[-+<>.,]{0,16}", nested in "[-+<>.,]{0,16}") {
+            let source = format!("{}[{}]{}", body, nested, body);
+            let program = from_brainfuck(&source).unwrap();
+
+            // brainfuck -> minlang -> brainfuck
+            prop_assert_eq!(&to_brainfuck(&program).unwrap(), &source);
+
+            // and the minlang survives being printed and parsed on the way
+            let reparsed = Program::parse_str(&Printer::new().print_program(&program));
+            prop_assert_eq!(&reparsed.statements, &program.statements);
+            prop_assert_eq!(to_brainfuck(&reparsed).unwrap(), source);
+        }
+    }
+}
@@ -0,0 +1,756 @@
+//! A binary form of compiled programs, so they can be shipped and run without their source.
+//!
+//! Every integer is little-endian. A file is laid out as:
+//!
+//! ```text
+//! magic          b"MINB"
+//! format         u16, FORMAT_VERSION
+//! flags          u16, bit 0 set if there's a debug table
+//! header         table size u32, language version u8 (1 or 2), cells u8 (0 native, 1 byte),
+//!                tape u8 (0 clamp, 1 wrap), reserved u8
+//! functions      count u32, then per function: id u64, index u32 of its define_fn instruction
+//! instructions   count u32, byte length u32, then the instructions
+//! debug table    only if flagged: file count u32, then per file: path (u32 length, UTF-8),
+//!                where it was included (a location); then one location per instruction
+//! ```
+//!
+//! Instructions are written in order, with a block's instructions straight after the one that
+//! owns it, and are numbered in that order from 0. Each is an opcode byte and its operands:
+//!
+//! - `loop`: has-condition u8, condition if so, body statement count u32
+//! - `define`: value i64
+//! - `if`: condition, then-block statement count u32, has-else u8, else-block statement count u32
+//! - `goto`, `copy`: parameter
+//! - `save`: register u8
+//! - `define_fn`: id u64, locals u64 (`u64::MAX` if it has no frame), body statement count u32
+//! - `call_fn`: id u64
+//! - `comment`: text (u32 length, UTF-8)
+//! - `table`: a header, laid out as above
+//!
+//! A parameter is a tag u8 (0 for a number, 1 for a save register) and an i64; a condition is
+//! a comparison u8 (0 `=` through 5 `>=`, in the order of [`Comparison`]) and a parameter. A
+//! location is line, column, start and end as u32s and a file u32, 0 for the file that was
+//! compiled or n for the nth file in the debug table. The macro call a statement came from
+//! isn't kept. Without a debug table, every statement is placed at line 0, column 0.
+//!
+//! Loading checks everything the parser would have, and that the file is exactly as long as
+//! its sections say, so a corrupt or truncated file is an error rather than a strange program.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::ast::{Comparison, Condition, ParameterKind, Statement, StatementKind};
+use crate::lexer::{SourceFile, Token, TokenLocation};
+use crate::parser::MAX_NESTING;
+use crate::program::{CellWidth, Header, Program, Table, TapePolicy, Version, MAX_LOCALS, SAVE_REGISTERS};
+
+pub(crate) const MAGIC: &[u8; 4] = b"MINB";
+/// Bumped whenever an opcode or a section above changes. There's no reading older files, since a
+/// program can always be built again from its source.
+pub(crate) const FORMAT_VERSION: u16 = 1;
+
+const FLAG_DEBUG: u16 = 1;
+/// The locals of a function that runs in its caller's table rather than a frame.
+const NO_FRAME: u64 = u64::MAX;
+
+const OP_TABLE: u8 = 0;
+const OP_INC: u8 = 1;
+const OP_DEC: u8 = 2;
+const OP_MUL: u8 = 3;
+const OP_DIV: u8 = 4;
+const OP_MOV_R: u8 = 5;
+const OP_MOV_L: u8 = 6;
+const OP_PRINT: u8 = 7;
+const OP_LOOP: u8 = 8;
+const OP_DEFINE: u8 = 9;
+const OP_IF: u8 = 10;
+const OP_GOTO: u8 = 11;
+const OP_SAVE: u8 = 12;
+const OP_PRINT_ASCII: u8 = 13;
+const OP_COPY: u8 = 14;
+const OP_MODULO: u8 = 15;
+const OP_DEFINE_FN: u8 = 16;
+const OP_CALL_FN: u8 = 17;
+const OP_PRINT_NEWLINE: u8 = 18;
+const OP_FLIP_SIGN: u8 = 19;
+const OP_INPUT: u8 = 20;
+const OP_BREAK: u8 = 21;
+const OP_CONTINUE: u8 = 22;
+const OP_RETURN: u8 = 23;
+const OP_COMMENT: u8 = 24;
+const OP_END: u8 = 25;
+
+const COMPARISONS: [Comparison; 6] = [
+    Comparison::Equal,
+    Comparison::NotEqual,
+    Comparison::Less,
+    Comparison::LessEqual,
+    Comparison::Greater,
+    Comparison::GreaterEqual,
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BytecodeError {
+    /// The file doesn't start with `MAGIC`.
+    NotBytecode,
+    /// The file was built by a version of minlang2 with a different instruction encoding.
+    UnsupportedVersion(u16),
+    /// The file ended at this byte while reading the named thing.
+    Truncated(usize, &'static str),
+    /// Something at this byte makes no sense, and why.
+    Corrupt(usize, String),
+}
+
+impl Display for BytecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BytecodeError::NotBytecode => f.write_fmt(format_args!("Not a minlang2 bytecode file.")),
+            BytecodeError::UnsupportedVersion(version) => f.write_fmt(format_args!(
+                "Bytecode format version {} isn't supported; this version of minlang2 reads version {}.",
+                version, FORMAT_VERSION
+            )),
+            BytecodeError::Truncated(offset, what) => f.write_fmt(format_args!("File is truncated: it ends at byte {} in the middle of {}.", offset, what)),
+            BytecodeError::Corrupt(offset, why) => f.write_fmt(format_args!("File is corrupt at byte {}: {}.", offset, why)),
+        }
+    }
+}
+
+type BytecodeResult<T> = Result<T, BytecodeError>;
+
+/// Builds a file up section by section.
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+    /// Every instruction's location, in order.
+    locations: Vec<TokenLocation>,
+    /// The index each top-level function's define_fn instruction got.
+    functions: Vec<(usize, usize)>,
+    files: Vec<SourceFile>,
+}
+
+pub(crate) fn put_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&u32::try_from(value).expect("Too large to fit in bytecode").to_le_bytes());
+}
+
+pub(crate) fn put_u64(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u64).to_le_bytes());
+}
+
+pub(crate) fn put_str(out: &mut Vec<u8>, text: &str) {
+    put_u32(out, text.len());
+    out.extend_from_slice(text.as_bytes());
+}
+
+fn put_header(out: &mut Vec<u8>, header: &Header) {
+    put_u32(out, header.size);
+    out.push(match header.version { Version::V1 => 1, Version::V2 => 2 });
+    out.push(match header.width { CellWidth::Native => 0, CellWidth::Byte => 1 });
+    out.push(match header.tape { TapePolicy::Clamp => 0, TapePolicy::Wrap => 1 });
+    out.push(0);
+}
+
+fn put_param(out: &mut Vec<u8>, param: &ParameterKind) {
+    let (tag, value) = match param {
+        ParameterKind::Numeric(val) => (0, *val as i64),
+        ParameterKind::Saved(register) => (1, *register as i64),
+    };
+    out.push(tag);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_condition(out: &mut Vec<u8>, condition: &Condition) {
+    out.push(COMPARISONS.iter().position(|comparison| *comparison == condition.comparison).unwrap() as u8);
+    put_param(out, &condition.param);
+}
+
+impl Writer {
+    fn block(&mut self, stmts: &[Statement], top_level: bool) {
+        for stmt in stmts {
+            self.statement(stmt, top_level);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Statement, top_level: bool) {
+        let index = self.locations.len();
+        self.locations.push(stmt.token.loc.clone());
+
+        let out = &mut self.bytes;
+        match &stmt.kind {
+            StatementKind::Table(header) => {
+                out.push(OP_TABLE);
+                put_header(out, header);
+            },
+            StatementKind::Inc => out.push(OP_INC),
+            StatementKind::Dec => out.push(OP_DEC),
+            StatementKind::Mul => out.push(OP_MUL),
+            StatementKind::Div => out.push(OP_DIV),
+            StatementKind::MovR => out.push(OP_MOV_R),
+            StatementKind::MovL => out.push(OP_MOV_L),
+            StatementKind::Print => out.push(OP_PRINT),
+            StatementKind::Loop(body, until) => {
+                out.push(OP_LOOP);
+                out.push(until.is_some() as u8);
+                if let Some(until) = until {
+                    put_condition(out, until);
+                }
+                put_u32(out, body.len());
+                self.block(body, false);
+            },
+            StatementKind::Define(val) => {
+                out.push(OP_DEFINE);
+                out.extend_from_slice(&(*val as i64).to_le_bytes());
+            },
+            StatementKind::If(condition, then, otherwise) => {
+                out.push(OP_IF);
+                put_condition(out, condition);
+                put_u32(out, then.len());
+                out.push(otherwise.is_some() as u8);
+                put_u32(out, otherwise.as_ref().map_or(0, Vec::len));
+                self.block(then, false);
+                self.block(otherwise.as_deref().unwrap_or_default(), false);
+            },
+            StatementKind::Goto(param) => {
+                out.push(OP_GOTO);
+                put_param(out, param);
+            },
+            StatementKind::Save(register) => out.extend_from_slice(&[OP_SAVE, *register as u8]),
+            StatementKind::PrintAscii => out.push(OP_PRINT_ASCII),
+            StatementKind::Copy(param) => {
+                out.push(OP_COPY);
+                put_param(out, param);
+            },
+            StatementKind::Modulo => out.push(OP_MODULO),
+            StatementKind::DefineFn(id, locals, body) => {
+                out.push(OP_DEFINE_FN);
+                put_u64(out, *id);
+                out.extend_from_slice(&locals.map_or(NO_FRAME, |locals| locals as u64).to_le_bytes());
+                put_u32(out, body.len());
+                if top_level {
+                    self.functions.push((*id, index));
+                }
+                self.block(body, false);
+            },
+            StatementKind::CallFn(id) => {
+                out.push(OP_CALL_FN);
+                put_u64(out, *id);
+            },
+            StatementKind::PrintNewline => out.push(OP_PRINT_NEWLINE),
+            StatementKind::FlipSign => out.push(OP_FLIP_SIGN),
+            StatementKind::Input => out.push(OP_INPUT),
+            StatementKind::Break => out.push(OP_BREAK),
+            StatementKind::Continue => out.push(OP_CONTINUE),
+            StatementKind::Return => out.push(OP_RETURN),
+            StatementKind::Comment(text) => {
+                out.push(OP_COMMENT);
+                put_str(out, text);
+            },
+            StatementKind::End => out.push(OP_END),
+        }
+    }
+
+    /// The number a location's file goes by in the debug table, adding it if it's new.
+    fn file_number(&mut self, loc: &TokenLocation) -> usize {
+        let Some(file) = &loc.file else { return 0; };
+        if let Some(idx) = self.files.iter().position(|known| known == file.as_ref()) {
+            return idx + 1;
+        }
+
+        // Where a file was included from has to be in the table first
+        self.file_number(&file.included_at);
+        self.files.push(file.as_ref().clone());
+        self.files.len()
+    }
+
+    fn put_location(&mut self, out: &mut Vec<u8>, loc: &TokenLocation) {
+        for value in [loc.line, loc.col, loc.start, loc.end] {
+            put_u32(out, value);
+        }
+        let file = self.file_number(loc);
+        put_u32(out, file);
+    }
+
+    fn debug_table(&mut self) -> Vec<u8> {
+        let mut lines = Vec::new();
+        for loc in self.locations.clone() {
+            self.put_location(&mut lines, &loc);
+        }
+
+        let mut out = Vec::new();
+        put_u32(&mut out, self.files.len());
+        for file in self.files.clone() {
+            put_str(&mut out, &file.path.to_string_lossy());
+            self.put_location(&mut out, &file.included_at);
+        }
+        out.extend(lines);
+
+        out
+    }
+}
+
+/// The bytecode for a program, with a debug table mapping instructions back to the source if
+/// `debug` is set.
+pub fn write(program: &Program, debug: bool) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.block(&program.statements, true);
+
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    out.extend_from_slice(&(if debug { FLAG_DEBUG } else { 0 }).to_le_bytes());
+    put_header(&mut out, &program.header());
+
+    put_u32(&mut out, writer.functions.len());
+    for (id, index) in &writer.functions {
+        put_u64(&mut out, *id);
+        put_u32(&mut out, *index);
+    }
+
+    put_u32(&mut out, writer.locations.len());
+    put_u32(&mut out, writer.bytes.len());
+    out.extend_from_slice(&writer.bytes);
+
+    if debug {
+        let table = writer.debug_table();
+        out.extend(table);
+    }
+
+    out
+}
+
+/// Reads a file back, checking as it goes.
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize, what: &'static str) -> BytecodeResult<&'a [u8]> {
+        if self.bytes.len() - self.pos < count {
+            return Err(BytecodeError::Truncated(self.bytes.len(), what));
+        }
+
+        self.pos += count;
+        Ok(&self.bytes[self.pos - count..self.pos])
+    }
+
+    fn corrupt<T>(&self, at: usize, why: impl Into<String>) -> BytecodeResult<T> {
+        Err(BytecodeError::Corrupt(at, why.into()))
+    }
+
+    pub(crate) fn u8(&mut self, what: &'static str) -> BytecodeResult<u8> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    pub(crate) fn u16(&mut self, what: &'static str) -> BytecodeResult<u16> {
+        Ok(u16::from_le_bytes(self.take(2, what)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u32(&mut self, what: &'static str) -> BytecodeResult<usize> {
+        Ok(u32::from_le_bytes(self.take(4, what)?.try_into().unwrap()) as usize)
+    }
+
+    pub(crate) fn u64(&mut self, what: &'static str) -> BytecodeResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8, what)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self, what: &'static str) -> BytecodeResult<i64> {
+        Ok(i64::from_le_bytes(self.take(8, what)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn usize(&mut self, what: &'static str) -> BytecodeResult<usize> {
+        let at = self.pos;
+        match usize::try_from(self.u64(what)?) {
+            Ok(value) => Ok(value),
+            Err(_) => self.corrupt(at, format!("{} is too large for this machine", what)),
+        }
+    }
+
+    pub(crate) fn isize(&mut self, what: &'static str) -> BytecodeResult<isize> {
+        let at = self.pos;
+        match isize::try_from(self.i64(what)?) {
+            Ok(value) => Ok(value),
+            Err(_) => self.corrupt(at, format!("{} is too large for this machine", what)),
+        }
+    }
+
+    /// A yes or no byte.
+    pub(crate) fn flag(&mut self, what: &'static str) -> BytecodeResult<bool> {
+        let at = self.pos;
+        match self.u8(what)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => self.corrupt(at, format!("{} should be 0 or 1, not {}", what, other)),
+        }
+    }
+
+    pub(crate) fn str(&mut self, what: &'static str) -> BytecodeResult<String> {
+        let len = self.u32(what)?;
+        let at = self.pos;
+        match std::str::from_utf8(self.take(len, what)?) {
+            Ok(text) => Ok(text.to_string()),
+            Err(_) => self.corrupt(at, format!("{} isn't valid UTF-8", what)),
+        }
+    }
+
+    fn header(&mut self) -> BytecodeResult<Header> {
+        let size = self.u32("the table size")?;
+
+        let mut header = Header::new(size);
+        let at = self.pos;
+        header.version = match self.u8("the language version")? {
+            1 => Version::V1,
+            2 => Version::V2,
+            other => return self.corrupt(at, format!("there's no language version {}", other)),
+        };
+        let at = self.pos;
+        header.width = match self.u8("the cell model")? {
+            0 => CellWidth::Native,
+            1 => CellWidth::Byte,
+            other => return self.corrupt(at, format!("there's no cell model {}", other)),
+        };
+        let at = self.pos;
+        header.tape = match self.u8("the tape policy")? {
+            0 => TapePolicy::Clamp,
+            1 => TapePolicy::Wrap,
+            other => return self.corrupt(at, format!("there's no tape policy {}", other)),
+        };
+        self.u8("the header")?;
+
+        Ok(header)
+    }
+
+    fn register(&mut self, value: i64, at: usize) -> BytecodeResult<usize> {
+        match usize::try_from(value) {
+            Ok(register) if register < SAVE_REGISTERS => Ok(register),
+            _ => self.corrupt(at, format!("there's no save register {}", value)),
+        }
+    }
+
+    fn param(&mut self) -> BytecodeResult<ParameterKind> {
+        let at = self.pos;
+        let tag = self.u8("a parameter")?;
+        let value_at = self.pos;
+        let value = self.i64("a parameter")?;
+        match tag {
+            0 => match isize::try_from(value) {
+                Ok(value) => Ok(ParameterKind::Numeric(value)),
+                Err(_) => self.corrupt(value_at, "a parameter is too large for this machine"),
+            },
+            1 => Ok(ParameterKind::Saved(self.register(value, value_at)?)),
+            other => self.corrupt(at, format!("there's no parameter kind {}", other)),
+        }
+    }
+
+    /// A parameter naming a cell, which the parser never lets be negative.
+    fn cell(&mut self) -> BytecodeResult<ParameterKind> {
+        let at = self.pos;
+        match self.param()? {
+            ParameterKind::Numeric(idx) if idx < 0 => self.corrupt(at, format!("cell {} is negative", idx)),
+            param => Ok(param),
+        }
+    }
+
+    fn condition(&mut self) -> BytecodeResult<Condition> {
+        let at = self.pos;
+        let comparison = match COMPARISONS.get(self.u8("a condition")? as usize) {
+            Some(comparison) => *comparison,
+            None => return self.corrupt(at, "there's no such comparison"),
+        };
+
+        Ok(Condition { comparison, param: self.param()? })
+    }
+
+    fn location(&mut self, files: &[Rc<SourceFile>]) -> BytecodeResult<TokenLocation> {
+        let mut loc = TokenLocation::new(self.u32("a location")?, self.u32("a location")?, self.u32("a location")?, self.u32("a location")?);
+        let at = self.pos;
+        loc.file = match self.u32("a location")? {
+            0 => None,
+            file => match files.get(file - 1) {
+                Some(file) => Some(file.clone()),
+                None => return self.corrupt(at, format!("there's no file {} in the debug table", file)),
+            },
+        };
+
+        Ok(loc)
+    }
+
+    /// The debug table's files, then its locations, which there should be `count` of.
+    fn debug_table(&mut self, count: usize) -> BytecodeResult<Vec<TokenLocation>> {
+        let mut files = Vec::new();
+        for _ in 0..self.u32("the debug table")? {
+            let path = PathBuf::from(self.str("a file path")?);
+            // A file can only have been included from one listed before it
+            let included_at = self.location(&files)?;
+            files.push(Rc::new(SourceFile { path, included_at }));
+        }
+
+        (0..count).map(|_| self.location(&files)).collect()
+    }
+}
+
+/// Turns the instruction stream back into statements.
+struct Decoder<'a> {
+    reader: Reader<'a>,
+    locations: Vec<TokenLocation>,
+    /// The byte each instruction decoded so far started at.
+    offsets: Vec<usize>,
+    /// Every function called so far, and the byte its call starts at.
+    calls: Vec<(usize, usize)>,
+}
+
+impl Decoder<'_> {
+    fn block(&mut self, len: usize, depth: usize) -> BytecodeResult<Vec<Statement>> {
+        if depth > MAX_NESTING {
+            return self.reader.corrupt(self.reader.pos, format!("blocks are nested more than {} deep", MAX_NESTING));
+        }
+
+        (0..len).map(|_| self.statement(depth)).collect()
+    }
+
+    fn statement(&mut self, depth: usize) -> BytecodeResult<Statement> {
+        let at = self.reader.pos;
+        let loc = self.locations.get(self.offsets.len()).cloned().unwrap_or_else(|| TokenLocation::new(0, 0, 0, 0));
+        self.offsets.push(at);
+
+        let reader = &mut self.reader;
+        let kind = match reader.u8("an instruction")? {
+            OP_TABLE => StatementKind::Table(reader.header()?),
+            OP_INC => StatementKind::Inc,
+            OP_DEC => StatementKind::Dec,
+            OP_MUL => StatementKind::Mul,
+            OP_DIV => StatementKind::Div,
+            OP_MOV_R => StatementKind::MovR,
+            OP_MOV_L => StatementKind::MovL,
+            OP_PRINT => StatementKind::Print,
+            OP_LOOP => {
+                let until = if reader.flag("a loop")? { Some(reader.condition()?) } else { None };
+                let len = reader.u32("a loop")?;
+                StatementKind::Loop(self.block(len, depth + 1)?, until)
+            },
+            OP_DEFINE => StatementKind::Define(reader.isize("a define")?),
+            OP_IF => {
+                let condition = reader.condition()?;
+                let then_len = reader.u32("an if")?;
+                let has_else = reader.flag("an if")?;
+                let else_len = reader.u32("an if")?;
+                let then = self.block(then_len, depth + 1)?;
+                let otherwise = if has_else { Some(self.block(else_len, depth + 1)?) } else { None };
+                StatementKind::If(condition, then, otherwise)
+            },
+            OP_GOTO => StatementKind::Goto(reader.cell()?),
+            OP_SAVE => {
+                let at = reader.pos;
+                let register = reader.u8("a save")?;
+                StatementKind::Save(reader.register(register as i64, at)?)
+            },
+            OP_PRINT_ASCII => StatementKind::PrintAscii,
+            OP_COPY => StatementKind::Copy(reader.cell()?),
+            OP_MODULO => StatementKind::Modulo,
+            OP_DEFINE_FN => {
+                let id = reader.usize("a function id")?;
+                let locals_at = reader.pos;
+                let locals = match reader.u64("a function's locals")? {
+                    NO_FRAME => None,
+                    locals if locals <= MAX_LOCALS as u64 => Some(locals as usize),
+                    locals => return reader.corrupt(locals_at, format!("a function asks for {} locals, more than the {} allowed", locals, MAX_LOCALS)),
+                };
+                let len = reader.u32("a function")?;
+                StatementKind::DefineFn(id, locals, self.block(len, depth + 1)?)
+            },
+            OP_CALL_FN => {
+                let id = reader.usize("a function id")?;
+                self.calls.push((id, at));
+                StatementKind::CallFn(id)
+            },
+            OP_PRINT_NEWLINE => StatementKind::PrintNewline,
+            OP_FLIP_SIGN => StatementKind::FlipSign,
+            OP_INPUT => StatementKind::Input,
+            OP_BREAK => StatementKind::Break,
+            OP_CONTINUE => StatementKind::Continue,
+            OP_RETURN => StatementKind::Return,
+            OP_COMMENT => StatementKind::Comment(reader.str("a comment")?),
+            OP_END => StatementKind::End,
+            other => return reader.corrupt(at, format!("there's no instruction {}", other)),
+        };
+
+        Ok(Statement::new(kind.clone(), Token::new(kind.token_kind(), loc)))
+    }
+}
+
+/// A program loaded from bytecode, with what's needed to show how it was laid out.
+pub(crate) struct Loaded {
+    pub(crate) program: Program,
+    /// The byte each instruction starts at, in instruction order.
+    pub(crate) offsets: Vec<usize>,
+    /// Whether the file had a debug table, so statements know where they came from.
+    pub(crate) debug: bool,
+}
+
+/// Load a program from its bytecode, ready to check and run.
+pub fn read(bytes: &[u8]) -> BytecodeResult<Program> {
+    Ok(load(bytes)?.program)
+}
+
+/// Load a program from its bytecode, keeping where each instruction was.
+pub(crate) fn load(bytes: &[u8]) -> BytecodeResult<Loaded> {
+    let mut reader = Reader { bytes, pos: 0 };
+
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(BytecodeError::NotBytecode);
+    }
+    reader.pos = MAGIC.len();
+
+    match reader.u16("the format version")? {
+        FORMAT_VERSION => {},
+        version => return Err(BytecodeError::UnsupportedVersion(version)),
+    }
+    let at = reader.pos;
+    let flags = reader.u16("the flags")?;
+    if flags & !FLAG_DEBUG != 0 {
+        return reader.corrupt(at, format!("unknown flags {:#06x}", flags));
+    }
+    let header = reader.header()?;
+
+    let mut entries = Vec::new();
+    for _ in 0..reader.u32("the function table")? {
+        let at = reader.pos;
+        entries.push((reader.usize("a function id")?, reader.u32("the function table")?, at));
+    }
+
+    let count = reader.u32("the instructions")?;
+    let len = reader.u32("the instructions")?;
+    let start = reader.pos;
+    let stream = reader.take(len, "the instructions")?;
+
+    let locations = if flags & FLAG_DEBUG != 0 { reader.debug_table(count)? } else { Vec::new() };
+    if reader.pos != bytes.len() {
+        return reader.corrupt(reader.pos, "there's more after the end of the program");
+    }
+
+    // Decode against the whole file, so errors give offsets into it
+    let mut decoder = Decoder { reader: Reader { bytes: &bytes[..start + len], pos: start }, locations, offsets: Vec::new(), calls: Vec::new() };
+    let mut statements = Vec::new();
+    let mut defined = BTreeMap::new();
+    while decoder.reader.pos < start + stream.len() {
+        let index = decoder.offsets.len();
+        let stmt = decoder.statement(0)?;
+        if let StatementKind::DefineFn(id, ..) = stmt.kind {
+            defined.insert(index, (id, statements.len()));
+        }
+        statements.push(stmt);
+    }
+    if decoder.offsets.len() != count {
+        return decoder.reader.corrupt(start - 8, format!("the file says it has {} instructions, but it has {}", count, decoder.offsets.len()));
+    }
+
+    // The function table has to list exactly the top-level definitions
+    let mut functions = BTreeMap::new();
+    for (id, index, at) in entries {
+        match defined.remove(&index) {
+            Some((defined_id, stmt)) if defined_id == id => {
+                functions.insert(id, statements[stmt].clone());
+            },
+            _ => return decoder.reader.corrupt(at, format!("instruction {} isn't the top-level definition of function {}", index, id)),
+        }
+    }
+    if let Some((id, _)) = defined.values().next() {
+        return decoder.reader.corrupt(start, format!("function {} is missing from the function table", id));
+    }
+    // A call to a function that isn't there would otherwise only fail once it runs
+    if let Some((id, at)) = decoder.calls.iter().find(|(id, _)| !functions.contains_key(id)) {
+        return decoder.reader.corrupt(*at, format!("it calls function {}, but no function has that id", id));
+    }
+
+    let mut program = Program::new(statements, functions, Table::from_header(&header));
+    program.version = header.version;
+
+    Ok(Loaded { program, offsets: decoder.offsets, debug: flags & FLAG_DEBUG != 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{Statement, StatementKind};
+    use crate::parser::MAX_NESTING;
+    use crate::program::{assert_same_program, with_deep_stack, Program, MAX_LOCALS, SAMPLE_SOURCE};
+
+    use super::{load, read, write, BytecodeError, FORMAT_VERSION, OP_CALL_FN, OP_DEC};
+
+    #[test]
+    fn test_round_trip() {
+        let program = Program::parse_str(SAMPLE_SOURCE);
+
+        for debug in [true, false] {
+            assert_same_program(&read(&write(&program, debug)).unwrap(), &program);
+        }
+
+        let loaded = read(&write(&program, true)).unwrap();
+        let locs = |program: &Program| program.statements.iter().map(|stmt| stmt.token.loc.clone()).collect::<Vec<_>>();
+        assert_eq!(locs(&loaded), locs(&program));
+        assert_eq!(read(&write(&program, false)).unwrap().statements[1].token.loc.line, 0);
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        with_deep_stack(|| {
+            let mut program = Program::parse_str(&format!("[4]\n{}{}", "{".repeat(MAX_NESTING), "}".repeat(MAX_NESTING)));
+            assert!(read(&write(&program, false)).is_ok());
+
+            // Nothing the parser accepts is this deep, so wrap its loops in one more by hand
+            let outer = program.statements.pop().unwrap();
+            program.statements.push(Statement::new(StatementKind::Loop(vec![outer.clone()], None), outer.token));
+            let err = read(&write(&program, false)).err().unwrap();
+            assert!(err.to_string().ends_with(&format!("blocks are nested more than {} deep.", MAX_NESTING)), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_truncated() {
+        let bytes = write(&Program::parse_str(SAMPLE_SOURCE), true);
+        for len in 0..bytes.len() {
+            assert!(read(&bytes[..len]).is_err(), "{} of {} bytes loaded", len, bytes.len());
+        }
+
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(matches!(read(&longer), Err(BytecodeError::Corrupt(..))));
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let bytes = write(&Program::parse_str("[4]\n:(1) + ; ^(1) -"), false);
+        assert_eq!(read(b"[4]\n+").err(), Some(BytecodeError::NotBytecode));
+
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(read(&newer).err(), Some(BytecodeError::UnsupportedVersion(FORMAT_VERSION + 1)));
+
+        // The program ends with a dec, which is the last byte
+        let mut bad_op = bytes.clone();
+        *bad_op.last_mut().unwrap() = 200;
+        let err = read(&bad_op).err().unwrap();
+        assert_eq!(err.to_string(), format!("File is corrupt at byte {}: there's no instruction 200.", bytes.len() - 1));
+        assert_eq!(*bytes.last().unwrap(), OP_DEC);
+
+        // Point the function table's one entry at the call instead of the definition
+        let mut bad_table = bytes.clone();
+        bad_table[28..32].copy_from_slice(&2u32.to_le_bytes());
+        assert!(matches!(read(&bad_table), Err(BytecodeError::Corrupt(20, _))));
+
+        // Locals have to fit in a frame; they follow the definition's opcode and function id
+        let mut many_locals = bytes.clone();
+        let locals = load(&bytes).unwrap().offsets[0] + 9;
+        many_locals[locals..locals + 8].copy_from_slice(&(MAX_LOCALS as u64 + 1).to_le_bytes());
+        assert!(matches!(read(&many_locals), Err(BytecodeError::Corrupt(at, _)) if at == locals));
+
+        // Calls are checked against the function table, even inside blocks
+        let mut bad_call = write(&Program::parse_str("[4]\n:(1) + ; { ^(1) }(!0)"), false);
+        let call = load(&bad_call).unwrap().offsets[3];
+        assert_eq!(bad_call[call], OP_CALL_FN);
+        bad_call[call + 1] = 2;
+        assert_eq!(
+            read(&bad_call).err().unwrap().to_string(),
+            format!("File is corrupt at byte {}: it calls function 2, but no function has that id.", call)
+        );
+    }
+}
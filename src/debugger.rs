@@ -0,0 +1,244 @@
+//! A debugger that can run a program backward as well as forward.
+//!
+//! The VM records what it takes to undo every step, so stepping back is as cheap as stepping
+//! forward, and so is going back to the step that last wrote a cell or to any step by number.
+//! The debugger also remembers how much the program had printed before each step so its
+//! output can be taken back with it.
+
+use std::collections::BTreeSet;
+use std::io::Read;
+
+use crate::disasm;
+use crate::program::Program;
+use crate::vm::{Change, Vm};
+
+pub(crate) const HELP: &str = "\
+step [n]          s   run n steps, 1 by default
+back [n]          b   undo n steps, 1 by default
+goto <step>       g   run forward or backward to just before a step
+last-write <cell> w   run backward to just before the last step that wrote a cell
+continue          c   run until a breakpoint or the end
+reverse           r   run backward until a breakpoint or the start
+break <line>      bp  set or clear a breakpoint on a source line
+table             t   show the table
+output            o   show what the program has printed
+help              h   show this
+quit              q   stop debugging
+";
+
+pub struct Debugger {
+    pub vm: Vm<Vec<u8>>,
+    /// The lines of the program's source, to quote where it's stopped.
+    source: Option<Vec<String>>,
+    /// How much had been printed before each step that can be undone.
+    printed: Vec<usize>,
+    /// Source lines to stop at when running either way.
+    pub(crate) breakpoints: BTreeSet<usize>,
+}
+
+impl Debugger {
+    pub fn new(program: Program, input: impl Read + 'static, source: Option<&str>) -> Debugger {
+        let mut vm = Vm::with_io(program, input, Vec::new());
+        vm.record();
+
+        Debugger { vm, source: source.map(|text| text.lines().map(str::to_string).collect()), printed: Vec::new(), breakpoints: BTreeSet::new() }
+    }
+
+    /// Run one step. Returns whether there was one to run.
+    pub(crate) fn step(&mut self) -> bool {
+        if self.vm.pc.is_none() {
+            return false;
+        }
+
+        self.printed.push(self.vm.output.len());
+        self.vm.step();
+        true
+    }
+
+    /// Undo one step, along with anything it printed. Returns whether there was one to undo.
+    pub(crate) fn step_back(&mut self) -> bool {
+        if !self.vm.step_back() {
+            return false;
+        }
+
+        let len = self.printed.pop().unwrap();
+        self.vm.output.truncate(len);
+        true
+    }
+
+    /// Run forward or backward to just before step `step` runs, or as close as the program gets.
+    pub fn goto(&mut self, step: u64) {
+        while self.vm.steps < step && self.step() {}
+        while self.vm.steps > step && self.step_back() {}
+    }
+
+    /// Run backward to just before the last step that wrote `cell`, and give what the cell held
+    /// before and after it. Stops at the start if no step did.
+    pub(crate) fn back_to_write(&mut self, cell: usize) -> Option<(isize, isize)> {
+        while let Some(undo) = self.vm.history.as_ref().and_then(|history| history.last()) {
+            let old = undo.changes.iter().rev().find_map(|change| match change {
+                Change::Cell(idx, old) if *idx == cell => Some(*old),
+                _ => None,
+            });
+            let new = self.vm.program.table.array.get(cell).copied();
+
+            self.step_back();
+            if let (Some(old), Some(new)) = (old, new) {
+                return Some((old, new));
+            }
+        }
+
+        None
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        self.vm.pc.is_some_and(|pc| self.breakpoints.contains(&self.vm.code.instructions[pc].loc.line))
+    }
+
+    /// Run until the next instruction is on a breakpoint's line, or the program ends.
+    pub fn run_forward(&mut self) {
+        while self.step() && !self.at_breakpoint() {}
+    }
+
+    /// Run backward until the next instruction is on a breakpoint's line, or back at the start.
+    pub(crate) fn run_backward(&mut self) {
+        while self.step_back() && !self.at_breakpoint() {}
+    }
+
+    /// Where the program is stopped, and what runs next.
+    pub fn status(&self) -> String {
+        let table = &self.vm.program.table;
+        let mut status = format!("step {}, cell {} = {}", self.vm.steps, table.curr, table[table.curr]);
+
+        match self.vm.pc {
+            Some(pc) => {
+                let instruction = &self.vm.code.instructions[pc];
+                let loc = &instruction.loc;
+                status.push_str(&format!("\nnext {}: {}, line {} col {}", pc, disasm::text(&instruction.kind), loc.line, loc.col));
+
+                if let (None, Some(lines)) = (&loc.file, &self.source) {
+                    if let Some(text) = loc.line.checked_sub(1).and_then(|line| lines.get(line)) {
+                        status.push_str(&format!(": {}", text.trim()));
+                    }
+                }
+            },
+            None => status.push_str("\nthe program has finished"),
+        }
+
+        status
+    }
+
+    /// Carry out a debugger command, and give what to show for it.
+    pub fn command(&mut self, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&name, args)) = words.split_first() else { return self.status(); };
+
+        let count = |default: u64| match args.first() {
+            Some(arg) => arg.parse::<u64>().map_err(|_| format!("Expected a number, not '{}'.", arg)),
+            None => Ok(default),
+        };
+        let required = |what: &str| match args.first() {
+            Some(arg) => arg.parse::<u64>().map_err(|_| format!("Expected {}, not '{}'.", what, arg)),
+            None => Err(format!("Expected {}.", what)),
+        };
+
+        let result = match name {
+            "step" | "s" => count(1).map(|count| {
+                for _ in 0..count {
+                    if !self.step() {
+                        break;
+                    }
+                }
+                self.status()
+            }),
+            "back" | "b" => count(1).map(|count| {
+                for _ in 0..count {
+                    if !self.step_back() {
+                        break;
+                    }
+                }
+                self.status()
+            }),
+            "goto" | "g" => required("a step number").map(|step| {
+                self.goto(step);
+                self.status()
+            }),
+            "last-write" | "w" => required("a cell").map(|cell| match self.back_to_write(cell as usize) {
+                Some((old, new)) => format!("cell {} went from {} to {}\n{}", cell, old, new, self.status()),
+                None => format!("no step wrote cell {}\n{}", cell, self.status()),
+            }),
+            "continue" | "c" => {
+                self.run_forward();
+                Ok(self.status())
+            },
+            "reverse" | "r" => {
+                self.run_backward();
+                Ok(self.status())
+            },
+            "break" | "bp" => required("a line").map(|line| {
+                let line = line as usize;
+                if self.breakpoints.remove(&line) {
+                    format!("cleared the breakpoint on line {}", line)
+                } else {
+                    self.breakpoints.insert(line);
+                    format!("set a breakpoint on line {}", line)
+                }
+            }),
+            "table" | "t" => {
+                let table = &self.vm.program.table;
+                Ok(format!("{:?}\ncursor {}, saved {:?}", table.array, table.curr, table.saved))
+            },
+            "output" | "o" => Ok(String::from_utf8_lossy(&self.vm.output).into_owned()),
+            "help" | "h" => Ok(HELP.trim_end().to_string()),
+            _ => Err(format!("Unknown command '{}'; try 'help'.", name)),
+        };
+
+        result.unwrap_or_else(|err| err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::program::Program;
+
+    use super::Debugger;
+
+    #[test]
+    fn test_step_back() {
+        let source = "[3]\n:(1)(1) > [3] { - } ;\n! ^(1) . > ! & _";
+        let mut debugger = Debugger::new(Program::parse_str(source), &b"ab"[..], Some(source));
+        debugger.run_forward();
+        let (table, output) = (debugger.vm.program.table.array.clone(), debugger.vm.output.clone());
+        let steps = debugger.vm.steps;
+
+        debugger.goto(0);
+        assert_eq!(debugger.vm.steps, 0);
+        assert_eq!(debugger.vm.program.table.array, vec![0; 3]);
+        assert!(debugger.vm.output.is_empty() && debugger.vm.calls.is_empty() && debugger.vm.loops.is_empty());
+
+        // Input read by undone steps is read again
+        debugger.goto(steps);
+        assert_eq!((&debugger.vm.program.table.array, &debugger.vm.output), (&table, &output));
+        assert_eq!(output, b"97b\n");
+        assert!(debugger.vm.pc.is_none());
+    }
+
+    #[test]
+    fn test_back_to_write() {
+        let source = "[2]\n[5] > [7] < + > - <\n";
+        let mut debugger = Debugger::new(Program::parse_str(source), std::io::empty(), Some(source));
+        debugger.run_forward();
+
+        assert_eq!(debugger.back_to_write(0), Some((5, 6)));
+        assert_eq!(debugger.vm.steps, 4);
+        assert!(debugger.status().ends_with("next 4: inc, line 2 col 13: [5] > [7] < + > - <"), "{}", debugger.status());
+        assert_eq!(debugger.back_to_write(0), Some((0, 5)));
+        assert_eq!(debugger.back_to_write(0), None);
+        assert_eq!(debugger.vm.steps, 0);
+
+        debugger.breakpoints.insert(2);
+        debugger.run_forward();
+        assert_eq!(debugger.vm.steps, 1);
+        assert_eq!(debugger.command("w 1"), "no step wrote cell 1\nstep 0, cell 0 = 0\nnext 0: define 5, line 2 col 1: [5] > [7] < + > - <");
+    }
+}
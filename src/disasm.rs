@@ -0,0 +1,240 @@
+//! A readable listing of a bytecode file, for seeing exactly what a program compiled to.
+//!
+//! Each instruction gets a line with its number, the byte it starts at and its operands,
+//! indented by how deeply it's nested. Instructions that send control somewhere else say
+//! where, by instruction number, and top-level functions are set off with the boundaries
+//! `Program::functions` gives them. If the file has a debug table, each line ends with where
+//! the instruction came from, and with the text of that line if the source is at hand.
+
+use std::collections::BTreeMap;
+
+use crate::ast::{size, Statement, StatementKind};
+use crate::bytecode::{self, BytecodeError, Loaded, FORMAT_VERSION};
+use crate::lexer::TokenLocation;
+
+/// Where instruction text stops and comments start, if the instruction is short enough.
+const COMMENT_COLUMN: usize = 44;
+
+/// `first-last`, or `empty`, for a block starting at instruction `start`.
+fn range(start: usize, stmts: &[Statement]) -> String {
+    match size(stmts) {
+        0 => "empty".to_string(),
+        1 => start.to_string(),
+        len => format!("{}-{}", start, start + len - 1),
+    }
+}
+
+struct Disassembler<'a> {
+    loaded: &'a Loaded,
+    /// The lines of the file that was compiled, if we have it.
+    source: Option<Vec<&'a str>>,
+    /// The instruction each top-level function is defined at.
+    functions: BTreeMap<usize, usize>,
+    out: String,
+    index: usize,
+}
+
+impl Disassembler<'_> {
+    fn location(&self, loc: &TokenLocation) -> String {
+        let mut note = match &loc.file {
+            Some(file) => format!("{} line {} col {}", file.path.display(), loc.line, loc.col),
+            None => format!("line {} col {}", loc.line, loc.col),
+        };
+
+        if let (None, Some(lines)) = (&loc.file, &self.source) {
+            if let Some(text) = loc.line.checked_sub(1).and_then(|line| lines.get(line)) {
+                note.push_str(&format!(": {}", text.trim()));
+            }
+        }
+
+        note
+    }
+
+    fn line(&mut self, index: usize, depth: usize, text: String, jumps: Option<String>, loc: &TokenLocation) {
+        let mut line = format!("{:>5}  {:06x}  {}{}", index, self.loaded.offsets[index], "  ".repeat(depth), text);
+
+        let mut comments = Vec::new();
+        comments.extend(jumps);
+        if self.loaded.debug {
+            comments.push(self.location(loc));
+        }
+        if !comments.is_empty() {
+            let width = COMMENT_COLUMN.max(line.chars().count() + 2);
+            line = format!("{:<width$}; {}", line, comments.join("; "), width = width);
+        }
+
+        self.out.push_str(line.trim_end());
+        self.out.push('\n');
+    }
+
+    /// `innermost` is the instruction number of the innermost loop and of the one after it.
+    fn block(&mut self, stmts: &[Statement], depth: usize, innermost: Option<(usize, usize)>) {
+        for stmt in stmts {
+            self.statement(stmt, depth, innermost);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Statement, depth: usize, innermost: Option<(usize, usize)>) {
+        let index = self.index;
+        self.index += 1;
+        let after = index + size(std::slice::from_ref(stmt));
+        let loc = &stmt.token.loc;
+
+        match &stmt.kind {
+            StatementKind::Loop(body, _) => {
+                let jumps = format!("body {}, exits to {}", range(index + 1, body), after);
+                self.line(index, depth, text(&stmt.kind), Some(jumps), loc);
+                self.block(body, depth + 1, Some((index, after)));
+            },
+            StatementKind::If(_, then, otherwise) => {
+                let else_start = index + 1 + size(then);
+                let jumps = match otherwise {
+                    Some(otherwise) => format!("then {}, else {}, joins at {}", range(index + 1, then), range(else_start, otherwise), after),
+                    None => format!("then {}, joins at {}", range(index + 1, then), after),
+                };
+                self.line(index, depth, text(&stmt.kind), Some(jumps), loc);
+                self.block(then, depth + 1, innermost);
+                if let Some(otherwise) = otherwise {
+                    self.line_marker(depth, "else");
+                    self.block(otherwise, depth + 1, innermost);
+                }
+            },
+            StatementKind::DefineFn(id, _, body) => {
+                let top_level = depth == 0 && self.loaded.program.functions.contains_key(id);
+                if top_level {
+                    self.out.push_str(&format!("\nfunction {}, instructions {}-{}:\n", id, index, after - 1));
+                }
+
+                let jumps = format!("body {}, skips to {}", range(index + 1, body), after);
+                self.line(index, depth, text(&stmt.kind), Some(jumps), loc);
+                // A function's body runs wherever it's called from, so loops around it don't count
+                self.block(body, depth + 1, None);
+
+                if top_level {
+                    self.out.push_str(&format!("end of function {}\n\n", id));
+                }
+            },
+            StatementKind::CallFn(id) => {
+                let jumps = match self.functions.get(id) {
+                    Some(target) => format!("jumps to {}", target),
+                    None => "no such function".to_string(),
+                };
+                self.line(index, depth, text(&stmt.kind), Some(jumps), loc);
+            },
+            StatementKind::Break => {
+                let jumps = innermost.map(|(_, exit)| format!("jumps to {}", exit));
+                self.line(index, depth, text(&stmt.kind), jumps, loc);
+            },
+            StatementKind::Continue => {
+                let jumps = innermost.map(|(start, _)| format!("jumps to {}", start));
+                self.line(index, depth, text(&stmt.kind), jumps, loc);
+            },
+            StatementKind::Return => self.line(index, depth, text(&stmt.kind), Some("back to the caller".to_string()), loc),
+            kind => self.line(index, depth, text(kind), None, loc),
+        }
+    }
+
+    /// A line that isn't an instruction, lined up with the instructions at `depth`.
+    fn line_marker(&mut self, depth: usize, text: &str) {
+        self.out.push_str(&format!("{:>5}  {:6}  {}{}\n", "", "", "  ".repeat(depth), text));
+    }
+}
+
+/// The text of an instruction, without where it jumps.
+pub(crate) fn text(kind: &StatementKind) -> String {
+    match kind {
+        StatementKind::Table(header) => format!("table {}", header),
+        StatementKind::Inc => "inc".to_string(),
+        StatementKind::Dec => "dec".to_string(),
+        StatementKind::Mul => "mul".to_string(),
+        StatementKind::Div => "div".to_string(),
+        StatementKind::MovR => "mov_right".to_string(),
+        StatementKind::MovL => "mov_left".to_string(),
+        StatementKind::Print => "print".to_string(),
+        StatementKind::Define(val) => format!("define {}", val),
+        StatementKind::Goto(param) => format!("goto {}", param),
+        StatementKind::Save(register) => format!("save ${}", register),
+        StatementKind::PrintAscii => "print_ascii".to_string(),
+        StatementKind::Copy(param) => format!("copy {}", param),
+        StatementKind::Modulo => "modulo".to_string(),
+        StatementKind::PrintNewline => "print_newline".to_string(),
+        StatementKind::FlipSign => "flip_sign".to_string(),
+        StatementKind::Input => "input".to_string(),
+        StatementKind::Comment(text) => format!("comment {:?}", text),
+        StatementKind::End => "end".to_string(),
+        StatementKind::Loop(_, Some(until)) => format!("loop until {}", until),
+        StatementKind::Loop(_, None) => "loop".to_string(),
+        StatementKind::If(condition, ..) => format!("if {}", condition),
+        StatementKind::DefineFn(id, Some(locals), _) => format!("define_fn {}, locals {}", id, locals),
+        StatementKind::DefineFn(id, None, _) => format!("define_fn {}", id),
+        StatementKind::CallFn(id) => format!("call_fn {}", id),
+        StatementKind::Break => "break".to_string(),
+        StatementKind::Continue => "continue".to_string(),
+        StatementKind::Return => "return".to_string(),
+    }
+}
+
+/// List the instructions in a bytecode file. `source` is the text of the file it was compiled
+/// from, to quote next to each instruction if the file has a debug table.
+pub fn disassemble(bytes: &[u8], source: Option<&str>) -> Result<String, BytecodeError> {
+    let loaded = bytecode::load(bytes)?;
+
+    let mut functions = BTreeMap::new();
+    let mut index = 0;
+    for stmt in &loaded.program.statements {
+        if let StatementKind::DefineFn(id, ..) = stmt.kind {
+            functions.insert(id, index);
+        }
+        index += size(std::slice::from_ref(stmt));
+    }
+
+    let header = format!(
+        "; bytecode format {}, table [{}]\n; {} instructions, {} functions, {}\n",
+        FORMAT_VERSION,
+        loaded.program.header(),
+        loaded.offsets.len(),
+        loaded.program.functions.len(),
+        if loaded.debug { "with debug info" } else { "no debug info" },
+    );
+
+    let mut disassembler = Disassembler { loaded: &loaded, source: source.map(|text| text.lines().collect()), functions, out: header, index: 0 };
+    disassembler.block(&loaded.program.statements, 0, None);
+
+    Ok(disassembler.out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::write;
+    use crate::program::Program;
+
+    use super::disassemble;
+
+    #[test]
+    fn test_disassemble() {
+        let source = "[4]\n:(1) ?(>2) ^ : - ; ;\n{ | ` }(3) ^(1)";
+        let program = Program::parse_str(source);
+
+        let listing = disassemble(&write(&program, false), None).unwrap();
+        assert_eq!(listing, "\
+; bytecode format 1, table [4]
+; 8 instructions, 1 functions, no debug info
+
+function 1, instructions 0-3:
+    0  000028  define_fn 1                  ; body 1-3, skips to 4
+    1  00003d    if >2                      ; then 2, else 3, joins at 4
+    2  000051      return                   ; back to the caller
+                 else
+    3  000052      dec
+end of function 1
+
+    4  000053  loop until 3                 ; body 5-6, exits to 7
+    5  000063    break                      ; jumps to 7
+    6  000064    continue                   ; jumps to 4
+    7  000065  call_fn 1                    ; jumps to 0
+");
+
+        let listing = disassemble(&write(&program, true), Some(source)).unwrap();
+        assert!(listing.contains("\n    3  000052      dec                      ; line 2 col 16: :(1) ?(>2) ^ : - ; ;\n"), "{}", listing);
+    }
+}
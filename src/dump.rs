@@ -0,0 +1,183 @@
+//! The table as a program left it, in forms for reading, diffing and feeding to other tools.
+//!
+//! Every form says where the cursor and the save registers point as well as what the cells
+//! hold:
+//!
+//! - [`compact`] lists the table size, the cursor, `$` and any other register that isn't on
+//!   cell 0, then every non-zero cell as `[index] value`, one to a line
+//! - [`hex`] lays the cells out eight to a row like `hexdump -C`, in hex, in decimal and as
+//!   ASCII, with rows that repeat the one before collapsed into a `*`
+//! - [`json`] is an object with `size`, `cursor`, `saved` (all 16 registers), `width` and
+//!   `cells` (every cell, including any past `size`)
+//! - [`csv`] has a row for every cell: its index, its value, whether the cursor is on it, and
+//!   the registers pointing at it separated by spaces
+
+use std::fmt::Write;
+
+use serde_json::{json, Value};
+
+use crate::program::{CellWidth, Table};
+
+/// How many cells `hex` puts on a row.
+const ROW: usize = 8;
+
+/// The registers pointing at `idx`, as `$` and `$n`.
+fn registers(table: &Table, idx: usize) -> Vec<String> {
+    table.saved.iter().enumerate().filter(|(_, saved)| **saved == idx).map(|(register, _)| match register {
+        0 => "$".to_string(),
+        register => format!("${}", register),
+    }).collect()
+}
+
+/// Where the cursor and registers are, on one line.
+fn pointers(table: &Table) -> String {
+    let mut line = format!("cursor {}, saved {}", table.curr, table.saved[0]);
+    for (register, idx) in table.saved.iter().enumerate().skip(1).filter(|(_, idx)| **idx != 0) {
+        write!(line, ", saved ${} {}", register, idx).unwrap();
+    }
+    line
+}
+
+pub fn compact(table: &Table) -> String {
+    let mut out = String::new();
+
+    writeln!(out, "size {}", table.size).unwrap();
+    writeln!(out, "curr {}", table.curr).unwrap();
+    writeln!(out, "saved {}", table.saved[0]).unwrap();
+    // Other registers only show up once they've been moved off cell 0
+    for (register, idx) in table.saved.iter().enumerate().skip(1).filter(|(_, idx)| **idx != 0) {
+        writeln!(out, "saved ${} {}", register, idx).unwrap();
+    }
+
+    for (idx, value) in table.array.iter().enumerate().filter(|(_, value)| **value != 0) {
+        writeln!(out, "[{}] {}", idx, value).unwrap();
+    }
+
+    out
+}
+
+fn to_hex(value: isize) -> String {
+    match value {
+        value if value < 0 => format!("-{:x}", value.unsigned_abs()),
+        value => format!("{:x}", value),
+    }
+}
+
+pub fn hex(table: &Table) -> String {
+    let mut out = format!("{}\n", pointers(table));
+
+    // Every cell gets the width of the widest, so the columns line up
+    let hex_width = table.array.iter().map(|value| to_hex(*value).len()).max().unwrap_or(0).max(2);
+    let dec_width = table.array.iter().map(|value| value.to_string().len()).max().unwrap_or(0);
+    let index_width = format!("{:x}", table.array.len()).len().max(4);
+
+    let mut collapsed = false;
+    for (row, cells) in table.array.chunks(ROW).enumerate() {
+        let start = row * ROW;
+        let last = start + cells.len() == table.array.len();
+        if start > 0 && !last && cells == &table.array[start - ROW..start] {
+            if !collapsed {
+                out.push_str("*\n");
+                collapsed = true;
+            }
+            continue;
+        }
+        collapsed = false;
+
+        let mut hex = String::new();
+        let mut dec = String::new();
+        for column in 0..ROW {
+            match cells.get(column) {
+                Some(value) => {
+                    write!(hex, " {:>width$}", to_hex(*value), width = hex_width).unwrap();
+                    write!(dec, " {:>width$}", value, width = dec_width).unwrap();
+                },
+                None => {
+                    write!(hex, " {:width$}", "", width = hex_width).unwrap();
+                    write!(dec, " {:width$}", "", width = dec_width).unwrap();
+                },
+            }
+        }
+        let ascii: String = cells.iter().map(|value| match *value {
+            value @ 0x20..=0x7e => value as u8 as char,
+            _ => '.',
+        }).collect();
+
+        writeln!(out, "{:0width$x} {}  {}  |{}|", start, hex, dec, ascii, width = index_width).unwrap();
+    }
+
+    out
+}
+
+pub fn json(table: &Table) -> Value {
+    json!({
+        "size": table.size,
+        "cursor": table.curr,
+        "saved": table.saved,
+        "width": match table.width {
+            CellWidth::Native => "native",
+            CellWidth::Byte => "byte",
+        },
+        "cells": table.array,
+    })
+}
+
+pub fn csv(table: &Table) -> String {
+    let mut out = "index,value,cursor,saved\n".to_string();
+    for (idx, value) in table.array.iter().enumerate() {
+        writeln!(out, "{},{},{},{}", idx, value, idx == table.curr, registers(table, idx).join(" ")).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::program::Table;
+
+    use super::{compact, csv, hex, json};
+
+    fn table() -> Table {
+        let mut table = Table::new(36);
+        table.array[0] = 72;
+        table.array[1] = 105;
+        table.array[3] = -300;
+        table.array[35] = 1;
+        table.curr = 3;
+        table.saved[2] = 1;
+        table
+    }
+
+    #[test]
+    fn test_compact() {
+        assert_eq!(compact(&table()), "size 36\ncurr 3\nsaved 0\nsaved $2 1\n[0] 72\n[1] 105\n[3] -300\n[35] 1\n");
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(hex(&table()), "\
+cursor 3, saved 0, saved $2 1
+0000    48   69    0 -12c    0    0    0    0     72  105    0 -300    0    0    0    0  |Hi......|
+0008     0    0    0    0    0    0    0    0      0    0    0    0    0    0    0    0  |........|
+*
+0020     0    0    0    1                          0    0    0    1                      |....|
+");
+    }
+
+    #[test]
+    fn test_json() {
+        let value = json(&table());
+        assert_eq!(value["cursor"], 3);
+        assert_eq!(value["saved"][2], 1);
+        assert_eq!(value["cells"][3], -300);
+        assert_eq!(value["cells"].as_array().unwrap().len(), 36);
+    }
+
+    #[test]
+    fn test_csv() {
+        let csv = csv(&table());
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 37);
+        assert_eq!(lines[..3], ["index,value,cursor,saved", "0,72,false,$ $1 $3 $4 $5 $6 $7 $8 $9 $10 $11 $12 $13 $14 $15", "1,105,false,$2"]);
+        assert_eq!(lines[4], "3,-300,true,");
+    }
+}
@@ -0,0 +1,241 @@
+//! Generates minlang programs that print a given piece of text.
+//!
+//! Each character is built in one of a handful of cells and printed with `&`. A character can
+//! be built in any cell by walking to it with `<`/`>`, then either adjusting the value already
+//! there with the shortest chain of `+`, `-`, `*` and `/`, or overwriting it with a `[n]`
+//! define, so cells holding earlier characters get reused. Newlines use `_`.
+//!
+//! Multiplication loops like `{>+++++<-}`, which older generated programs such as
+//! `examples/out.mini` are full of, are never considered: for any byte they are longer than
+//! the `[n]` define that sets the same value.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::vm::Vm;
+
+/// Largest value the search for `+ - * /` chains will pass through.
+const SEARCH_LIMIT: isize = 512;
+
+pub const DEFAULT_CELLS: usize = 8;
+
+#[derive(Debug)]
+pub enum GenerateError {
+    /// `&` prints a cell as a byte, so only characters up to U+00FF can be printed.
+    Unprintable(char),
+    /// The generated program didn't print what it was meant to. This is a bug in the generator.
+    Mismatch(String),
+}
+
+impl Display for GenerateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GenerateError::Unprintable(chr) => {
+                f.write_fmt(format_args!("Character '{}' (U+{:04X}) can't be printed by minlang, which prints bytes.", chr, *chr as u32))
+            },
+            GenerateError::Mismatch(reason) => {
+                f.write_fmt(format_args!("Generated program failed verification: {}.", reason))
+            },
+        }
+    }
+}
+
+/// The shortest chain of `+ - * /` turning `from` into `to`, if one exists within the search
+/// limit.
+fn arithmetic(from: isize, to: isize) -> Option<String> {
+    if from == to {
+        return Some(String::new());
+    }
+
+    let span = (2 * SEARCH_LIMIT + 1) as usize;
+    let slot = |value: isize| (value + SEARCH_LIMIT) as usize;
+    let mut came_from: Vec<Option<(isize, char)>> = vec![None; span];
+    let mut queue = VecDeque::from([from]);
+    came_from[slot(from)] = Some((from, ' '));
+
+    while let Some(value) = queue.pop_front() {
+        for (next, op) in [(value + 1, '+'), (value - 1, '-'), (value * 2, '*'), (value / 2, '/')] {
+            if next.abs() > SEARCH_LIMIT || came_from[slot(next)].is_some() {
+                continue;
+            }
+            came_from[slot(next)] = Some((value, op));
+
+            if next == to {
+                let mut ops = Vec::new();
+                let mut at = to;
+                while at != from {
+                    let (prev, op) = came_from[slot(at)].unwrap();
+                    ops.push(op);
+                    at = prev;
+                }
+                return Some(ops.into_iter().rev().collect());
+            }
+            queue.push_back(next);
+        }
+    }
+
+    None
+}
+
+/// The shortest code turning a cell holding `from` into one holding `to`.
+fn transform(from: isize, to: isize) -> String {
+    let define = format!("[{}]", to);
+    match arithmetic(from, to) {
+        Some(ops) if ops.len() <= define.len() => ops,
+        _ => define,
+    }
+}
+
+fn movement(from: usize, to: usize) -> String {
+    if to >= from {
+        ">".repeat(to - from)
+    } else {
+        "<".repeat(from - to)
+    }
+}
+
+/// A partial program in the beam search: which cells hold what, where the cursor is, and how
+/// we got here.
+#[derive(Clone)]
+struct Candidate {
+    values: Vec<isize>,
+    curr: usize,
+    len: usize,
+    // Index into the step arena of the last step taken
+    last: Option<usize>,
+}
+
+/// How many partial programs to keep between characters. Wider finds shorter programs, slower.
+const BEAM_WIDTH: usize = 32;
+
+/// Generate a program printing `text`, building characters in up to `cells` cells.
+///
+/// Rather than greedily taking the cheapest step for each character, which tends to keep
+/// overwriting one cell, the generator keeps the shortest few partial programs at each
+/// character so that keeping a value around for later can win out.
+///
+/// The program is run in the VM before it is returned, to make sure it prints exactly `text`.
+pub fn generate_print(text: &str, cells: usize) -> Result<String, GenerateError> {
+    let cells = cells.max(1);
+    let mut transforms: HashMap<(isize, isize), String> = HashMap::new();
+    // Every step taken by any candidate, with the step before it
+    let mut steps: Vec<(Option<usize>, String)> = Vec::new();
+    let mut beam = vec![Candidate { values: vec![0; cells], curr: 0, len: 0, last: None }];
+
+    for chr in text.chars() {
+        if chr as u32 > 0xFF {
+            return Err(GenerateError::Unprintable(chr));
+        }
+
+        let mut next: HashMap<(usize, Vec<isize>), Candidate> = HashMap::new();
+        for candidate in &beam {
+            // Newlines don't need a cell
+            let options: Vec<(usize, String)> = if chr == '\n' {
+                vec![(candidate.curr, "_".into())]
+            } else {
+                let target = chr as isize;
+                (0..cells).map(|cell| {
+                    let change = transforms.entry((candidate.values[cell], target)).or_insert_with(|| transform(candidate.values[cell], target));
+                    (cell, format!("{}{}&", movement(candidate.curr, cell), change))
+                }).collect()
+            };
+
+            for (cell, step) in options {
+                let mut values = candidate.values.clone();
+                if chr != '\n' {
+                    values[cell] = chr as isize;
+                }
+                let len = candidate.len + step.len();
+
+                let key = (cell, values);
+                if next.get(&key).is_some_and(|other| other.len <= len) {
+                    continue;
+                }
+                steps.push((candidate.last, step));
+                next.insert(key.clone(), Candidate { values: key.1, curr: cell, len, last: Some(steps.len() - 1) });
+            }
+        }
+
+        beam = next.into_values().collect();
+        beam.sort_by(|a, b| a.len.cmp(&b.len).then_with(|| a.values.cmp(&b.values)).then_with(|| a.curr.cmp(&b.curr)));
+        beam.truncate(BEAM_WIDTH);
+    }
+
+    // Walk the best candidate's steps back to the start
+    let mut code = Vec::new();
+    let mut at = beam.first().and_then(|best| best.last);
+    while let Some(idx) = at {
+        code.push(steps[idx].1.as_str());
+        at = steps[idx].0;
+    }
+    code.reverse();
+
+    let source = format!("[{}]\n{}\n", cells, code.concat());
+    verify(&source, text)?;
+
+    Ok(source)
+}
+
+/// Run a generated program and check it prints `expected`.
+fn verify(source: &str, expected: &str) -> Result<(), GenerateError> {
+    let tokens = Lexer::new(source.to_string()).lex().map_err(|err| GenerateError::Mismatch(err.to_string()))?.tokens;
+    let program = Parser::new(tokens).parse_program().map_err(|err| GenerateError::Mismatch(err.to_string()))?;
+
+    let mut vm = Vm::with_output(program, Vec::new());
+    vm.run(false);
+
+    if vm.output != expected.as_bytes() {
+        return Err(GenerateError::Mismatch(format!("expected {:?}, got {:?}", expected, String::from_utf8_lossy(&vm.output))));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{arithmetic, generate_print, transform, GenerateError, DEFAULT_CELLS};
+
+    #[test]
+    fn test_arithmetic_is_shortest() {
+        assert_eq!(arithmetic(0, 3), Some("+++".into()));
+        assert_eq!(arithmetic(50, 100), Some("*".into()));
+        assert_eq!(arithmetic(101, 50), Some("/".into()));
+        assert_eq!(arithmetic(3, 24).map(|ops| ops.len()), Some(3));
+    }
+
+    #[test]
+    fn test_transform_prefers_defines_for_big_jumps() {
+        assert_eq!(transform(0, 104), "[104]");
+        assert_eq!(transform(104, 101), "---");
+        assert_eq!(transform(104, 104), "");
+    }
+
+    #[test]
+    fn test_generate_hello() {
+        let source = generate_print("Hello, world!\n", DEFAULT_CELLS).unwrap();
+
+        assert_eq!(source, "[8]\n[72]&[101]&>[108]&&+++&>[44]&[32]&--**-&<&+++&-/--*&<-&[33]&_\n");
+        // Keeping 'l' and 'o' around beats defining every character from scratch
+        let naive: usize = "Hello, world!".chars().map(|chr| format!("[{}]&", chr as u32).len()).sum();
+        assert!(source.len() - "[8]\n_\n".len() < naive);
+    }
+
+    #[test]
+    fn test_unprintable() {
+        assert!(matches!(generate_print("λ", DEFAULT_CELLS), Err(GenerateError::Unprintable('λ'))));
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_generated_programs_print_their_text(text in "[\\x00-\\xFF]{0,64}", cells in 1usize..10) {
+            // Verification inside generate_print is the real assertion here
+            prop_assert!(generate_print(&text, cells).is_ok());
+        }
+    }
+}
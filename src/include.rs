@@ -0,0 +1,267 @@
+//! Includes: a `"path"` on its own pulls another file's tokens in at that spot.
+//!
+//! A path is looked for next to the file including it, then in each search path in order.
+//! Each file is only pulled in once, however many files include it, so a library can include
+//! what it needs without worrying about who else did. A file including itself, directly or
+//! through others, is an error. An included file's `[n]` header is ignored: the table belongs
+//! to the program being compiled.
+//!
+//! Every token from an included file carries the file in its location, so errors in it say
+//! where it is and how it was included.
+
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::lexer::{LexError, Lexer, SourceFile, Token, TokenKind, TokenLocation};
+
+#[derive(Debug)]
+pub enum IncludeError {
+    NotFound(Token, Vec<PathBuf>),
+    Unreadable(Token, PathBuf, std::io::Error),
+    Cycle(Token, Vec<PathBuf>),
+    Lex(LexError),
+}
+
+impl IncludeError {
+    /// Where the error was found: the include, or the bad token in the included file.
+    pub fn location(&self) -> &TokenLocation {
+        match self {
+            IncludeError::NotFound(token, _)
+            | IncludeError::Unreadable(token, _, _)
+            | IncludeError::Cycle(token, _) => &token.loc,
+            IncludeError::Lex(err) => err.location(),
+        }
+    }
+}
+
+impl Display for IncludeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IncludeError::NotFound(token, searched) => {
+                let searched: Vec<String> = searched.iter().map(|path| path.display().to_string()).collect();
+                f.write_fmt(format_args!("Can't find {} included at {}; looked for {}.", token.kind, token.loc.describe(), searched.join(", ")))
+            },
+            IncludeError::Unreadable(token, path, err) => {
+                f.write_fmt(format_args!("Can't read {} included at {}: {}.", path.display(), token.loc.describe(), err))
+            },
+            IncludeError::Cycle(token, chain) => {
+                let chain: Vec<String> = chain.iter().map(|path| path.display().to_string()).collect();
+                f.write_fmt(format_args!("{} at {} includes a file that is already being included: {}.", token.kind, token.loc.describe(), chain.join(" -> ")))
+            },
+            // The lexer's message already says which file it's in
+            IncludeError::Lex(err) => return f.write_fmt(format_args!("{}", err)),
+        }?;
+
+        f.write_str(&self.location().origin_note())
+    }
+}
+
+type IncludeResult<T> = Result<T, IncludeError>;
+
+struct Includer<'a> {
+    search_paths: &'a [PathBuf],
+    // The files being included, outermost first, as found and canonicalized
+    stack: Vec<(PathBuf, PathBuf)>,
+    // Every file pulled in so far, canonicalized
+    included: HashSet<PathBuf>,
+}
+
+impl Includer<'_> {
+    /// Where `name` is, searching next to the including file and then the search paths,
+    /// or every place that was tried.
+    fn find(&self, name: &str, dir: Option<&Path>) -> Result<PathBuf, Vec<PathBuf>> {
+        let name = Path::new(name);
+        if name.is_absolute() {
+            return if name.is_file() { Ok(name.to_path_buf()) } else { Err(vec![name.to_path_buf()]) };
+        }
+
+        let mut candidates = vec![dir.unwrap_or(Path::new("")).join(name)];
+        candidates.extend(self.search_paths.iter().map(|search| search.join(name)));
+
+        match candidates.iter().find(|path| path.is_file()) {
+            Some(path) => Ok(path.clone()),
+            None => Err(candidates),
+        }
+    }
+
+    fn splice(&mut self, tokens: Vec<Token>, dir: Option<&Path>, out: &mut Vec<Token>) -> IncludeResult<()> {
+        for tok in tokens {
+            let TokenKind::Str(name) = &tok.kind else {
+                out.push(tok);
+                continue;
+            };
+
+            let path = self.find(name, dir).map_err(|searched| IncludeError::NotFound(tok.clone(), searched))?;
+            let canonical = fs::canonicalize(&path).map_err(|err| IncludeError::Unreadable(tok.clone(), path.clone(), err))?;
+
+            if self.stack.iter().any(|(_, open)| *open == canonical) {
+                let mut chain: Vec<PathBuf> = self.stack.iter().map(|(found, _)| found.clone()).collect();
+                chain.push(path);
+                return Err(IncludeError::Cycle(tok, chain));
+            }
+            if !self.included.insert(canonical.clone()) {
+                continue;
+            }
+
+            let text = fs::read_to_string(&path).map_err(|err| IncludeError::Unreadable(tok.clone(), path.clone(), err))?;
+            let file = Rc::new(SourceFile { path: path.clone(), included_at: tok.loc.clone() });
+            let mut tokens = Lexer::new(text).in_file(file).lex().map_err(IncludeError::Lex)?.tokens;
+            // The lexer always starts with the table header, which only the main file gets to set
+            tokens.remove(0);
+
+            self.stack.push((path.clone(), canonical));
+            self.splice(tokens, path.parent(), out)?;
+            self.stack.pop();
+        }
+
+        Ok(())
+    }
+}
+
+/// Replace every include in `tokens` with the tokens of the file it names.
+///
+/// `path` is the file `tokens` were lexed from, if any; includes are looked for next to it, or
+/// in the working directory without one, before trying `search_paths`.
+pub fn include(tokens: Vec<Token>, path: Option<&Path>, search_paths: &[PathBuf]) -> IncludeResult<Vec<Token>> {
+    if !tokens.iter().any(|tok| matches!(tok.kind, TokenKind::Str(_))) {
+        return Ok(tokens);
+    }
+
+    let mut includer = Includer { search_paths, stack: Vec::new(), included: HashSet::new() };
+    // The main file counts as included, so a library including it back is a cycle
+    if let Some(canonical) = path.and_then(|path| fs::canonicalize(path).ok()) {
+        includer.stack.push((path.unwrap().to_path_buf(), canonical.clone()));
+        includer.included.insert(canonical);
+    }
+
+    let mut out = Vec::with_capacity(tokens.len());
+    includer.splice(tokens, path.and_then(Path::parent), &mut out)?;
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::lexer::{Lexer, TokenKind};
+    use crate::parser::{ParseError, Parser};
+    use crate::program::Header;
+
+    use super::{include, IncludeError};
+
+    /// A scratch directory holding the given files, removed when dropped.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str, files: &[(&str, &str)]) -> Scratch {
+            let dir = std::env::temp_dir().join(format!("minlang2-include-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            for (path, text) in files {
+                let path = dir.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, text).unwrap();
+            }
+            Scratch(dir)
+        }
+
+        fn compile(&self, main: &str, search: &[&str]) -> Result<Vec<TokenKind>, IncludeError> {
+            let path = self.0.join(main);
+            let tokens = Lexer::new(fs::read_to_string(&path).unwrap()).lex().unwrap().tokens;
+            let search: Vec<PathBuf> = search.iter().map(|dir| self.0.join(dir)).collect();
+
+            Ok(include(tokens, Some(&path), &search)?.into_iter().map(|tok| tok.kind).collect())
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_include_searches_paths() {
+        let scratch = Scratch::new("search", &[
+            ("main.mini", "[8]\n+\"near.mini\"\"far.mini\"-"),
+            ("near.mini", "[99]\n>"),
+            ("lib/far.mini", "<"),
+        ]);
+
+        // near.mini's header is dropped, and far.mini is found through the search path
+        let kinds = scratch.compile("main.mini", &["lib"]).unwrap();
+        assert_eq!(kinds, vec![TokenKind::Table(Header::new(8)), TokenKind::Inc, TokenKind::MovR, TokenKind::MovL, TokenKind::Dec]);
+
+        let err = scratch.compile("main.mini", &[]).err().unwrap();
+        assert!(matches!(err, IncludeError::NotFound(_, ref searched) if searched.len() == 1));
+    }
+
+    #[test]
+    fn test_include_once() {
+        let scratch = Scratch::new("once", &[
+            ("main.mini", "[8]\n\"a.mini\"\"b.mini\""),
+            ("a.mini", "\"common.mini\"+"),
+            ("b.mini", "\"common.mini\"-"),
+            ("common.mini", "."),
+        ]);
+
+        let kinds = scratch.compile("main.mini", &[]).unwrap();
+        assert_eq!(kinds, vec![TokenKind::Table(Header::new(8)), TokenKind::Print, TokenKind::Inc, TokenKind::Dec]);
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let scratch = Scratch::new("cycle", &[
+            ("main.mini", "[8]\n\"a.mini\""),
+            ("a.mini", "\"b.mini\""),
+            ("b.mini", "\n\"main.mini\""),
+        ]);
+
+        let err = scratch.compile("main.mini", &[]).err().unwrap();
+        assert!(matches!(err, IncludeError::Cycle(_, ref chain) if chain.len() == 4));
+        let message = err.to_string();
+        assert!(message.contains("\"main.mini\" at line 2 col 1 of "), "{}", message);
+        assert!(message.ends_with("a.mini, included at line 2 col 1."), "{}", message);
+    }
+
+    #[test]
+    fn test_errors_name_the_file() {
+        let scratch = Scratch::new("errors", &[
+            ("main.mini", "[8]\n+\n\"lib.mini\""),
+            ("lib.mini", "+\n ]"),
+        ]);
+
+        let path = scratch.0.join("main.mini");
+        let tokens = Lexer::new(fs::read_to_string(&path).unwrap()).lex().unwrap().tokens;
+        let tokens = include(tokens, Some(&path), &[]).unwrap();
+
+        let err = Parser::new(tokens).parse_program().err().unwrap();
+        assert!(matches!(err, ParseError::UnexpectedToken(_)));
+        let message = err.to_string();
+        assert!(message.starts_with("Unexpected token ']' at line 2 col 2. In "), "{}", message);
+        assert!(message.ends_with(&format!("{}, included at line 3 col 1.", scratch.0.join("lib.mini").display())), "{}", message);
+    }
+
+    #[test]
+    fn test_numbered_functions_conflict_across_files() {
+        let scratch = Scratch::new("conflict", &[
+            ("main.mini", "[8]\n\"lib.mini\":(0)+;"),
+            ("lib.mini", ":(0)-;:(helper).;"),
+            ("named.mini", "[8]\n\"lib.mini\":(main)+;^(main)^(helper)"),
+        ]);
+
+        let parse = |main: &str| {
+            let path = scratch.0.join(main);
+            let tokens = Lexer::new(fs::read_to_string(&path).unwrap()).lex().unwrap().tokens;
+            Parser::new(include(tokens, Some(&path), &[]).unwrap()).parse_program()
+        };
+
+        assert!(matches!(parse("main.mini"), Err(ParseError::ConflictingFunction(_, 0, _))));
+        // Names are numbered across every file at once, so they can't collide
+        assert!(parse("named.mini").is_ok());
+    }
+}
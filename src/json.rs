@@ -0,0 +1,497 @@
+//! A stable JSON form of parsed programs, for tools written in other languages.
+//!
+//! `to_json` writes a `Program` out and `from_json` reads one back, ready to check and run
+//! without the source it came from. The top-level `"schema"` field is the version of the
+//! layout below; fields are only ever added to a version, never changed or removed.
+//!
+//! ```text
+//! Program    = { "schema": 1, "header": Header, "symbols": Symbols, "statements": [Statement] }
+//! Header     = { "size": n, "language": 1 | 2, "cells": "native" | "byte", "tape": "clamp" | "wrap" }
+//! Symbols    = { "functions": { name: id }, "cells": { name: index } }
+//! Statement  = { "kind": Kind, "loc": Location, "name"?: Name, ...the fields for its kind }
+//! Name       = { "name": string, "binds": bool }
+//! Location   = { "line": n, "col": n, "start": n, "end": n,
+//!                "file"?: { "path": string, "included_at": Location }, "expansion"?: Location }
+//! Param      = { "value": integer } | { "saved": register }
+//! Condition  = { "compare": "equal" | "not_equal" | "less" | "less_equal" | "greater" | "greater_equal",
+//!                "against": Param }
+//! ```
+//!
+//! | `kind`          | fields                                                   |
+//! |-----------------|----------------------------------------------------------|
+//! | `inc`, `dec`, `mul`, `div`, `mov_right`, `mov_left`, `print`, `print_ascii`, `modulo`, `print_newline`, `flip_sign`, `input`, `break`, `continue`, `return`, `end` | none |
+//! | `loop`          | `body`: [Statement], `until`: Condition or null          |
+//! | `define`        | `value`: integer                                         |
+//! | `if`            | `condition`: Condition, `then`: [Statement], `else`: [Statement] or null |
+//! | `goto`, `copy`  | `cell`: Param                                            |
+//! | `save`          | `register`: n                                            |
+//! | `define_fn`     | `id`: n, `locals`: n or null, `body`: [Statement]        |
+//! | `call_fn`       | `id`: n                                                  |
+//! | `comment`       | `text`: string                                           |
+//! | `table`         | `header`: Header                                         |
+//!
+//! `n` is a non-negative integer and `integer` may be negative. Locations are byte offsets
+//! and 1-based lines and columns, as in `TokenLocation`. `Program::functions` isn't written
+//! out, since it's just the top-level `define_fn` statements. Reading rejects anything the
+//! parser would have, like a negative cell or blocks nested more than `MAX_NESTING` deep.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::ast::{Comparison, Condition, Name, ParameterKind, Statement, StatementKind};
+use crate::lexer::{SourceFile, Token, TokenLocation};
+use crate::parser::MAX_NESTING;
+use crate::program::{CellWidth, Header, Program, Table, TapePolicy, Version, MAX_LOCALS, SAVE_REGISTERS};
+use crate::symbols::SymbolTable;
+
+/// The `schema` field of every document. Tools reading the JSON should check it before anything
+/// else, since a new schema may rename or drop fields.
+pub(crate) const SCHEMA_VERSION: u64 = 1;
+
+#[derive(Debug)]
+pub enum JsonError {
+    /// The text isn't JSON at all.
+    Syntax(serde_json::Error),
+    /// Where in the document something is wrong, and what was expected there instead.
+    Schema(String, String),
+}
+
+impl Display for JsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JsonError::Syntax(err) => f.write_fmt(format_args!("Not valid JSON: {}.", err)),
+            JsonError::Schema(at, expected) => f.write_fmt(format_args!("Expected {} at {}.", expected, at)),
+        }
+    }
+}
+
+type JsonResult<T> = Result<T, JsonError>;
+
+fn expected(at: &str, what: impl Into<String>) -> JsonError {
+    JsonError::Schema(at.to_string(), what.into())
+}
+
+fn field<'a>(object: &'a Value, at: &str, key: &str) -> JsonResult<&'a Value> {
+    object.get(key).ok_or_else(|| expected(at, format!("a \"{}\" field", key)))
+}
+
+/// A field that may be missing or null.
+fn optional<'a>(object: &'a Value, key: &str) -> Option<&'a Value> {
+    object.get(key).filter(|value| !value.is_null())
+}
+
+fn number(value: &Value, at: &str) -> JsonResult<usize> {
+    value.as_u64().and_then(|num| usize::try_from(num).ok()).ok_or_else(|| expected(at, "a non-negative integer"))
+}
+
+fn signed(value: &Value, at: &str) -> JsonResult<isize> {
+    value.as_i64().and_then(|num| isize::try_from(num).ok()).ok_or_else(|| expected(at, "an integer"))
+}
+
+fn string<'a>(value: &'a Value, at: &str) -> JsonResult<&'a str> {
+    value.as_str().ok_or_else(|| expected(at, "a string"))
+}
+
+fn location_to(loc: &TokenLocation) -> Value {
+    let mut value = json!({ "line": loc.line, "col": loc.col, "start": loc.start, "end": loc.end });
+    if let Some(file) = &loc.file {
+        value["file"] = json!({ "path": file.path.to_string_lossy(), "included_at": location_to(&file.included_at) });
+    }
+    if let Some(call) = &loc.expansion {
+        value["expansion"] = location_to(call);
+    }
+
+    value
+}
+
+fn location_from(value: &Value, at: &str) -> JsonResult<TokenLocation> {
+    let get = |key: &str| number(field(value, at, key)?, &format!("{}.{}", at, key));
+    let mut loc = TokenLocation::new(get("line")?, get("col")?, get("start")?, get("end")?);
+
+    if let Some(file) = optional(value, "file") {
+        let at = format!("{}.file", at);
+        let path = PathBuf::from(string(field(file, &at, "path")?, &format!("{}.path", at))?);
+        let included_at = location_from(field(file, &at, "included_at")?, &format!("{}.included_at", at))?;
+        loc.file = Some(Rc::new(SourceFile { path, included_at }));
+    }
+    if let Some(call) = optional(value, "expansion") {
+        loc.expansion = Some(Rc::new(location_from(call, &format!("{}.expansion", at))?));
+    }
+
+    Ok(loc)
+}
+
+fn header_to(header: &Header) -> Value {
+    json!({
+        "size": header.size,
+        "language": match header.version { Version::V1 => 1, Version::V2 => 2 },
+        "cells": match header.width { CellWidth::Native => "native", CellWidth::Byte => "byte" },
+        "tape": match header.tape { TapePolicy::Clamp => "clamp", TapePolicy::Wrap => "wrap" },
+    })
+}
+
+fn header_from(value: &Value, at: &str) -> JsonResult<Header> {
+    let size = number(field(value, at, "size")?, &format!("{}.size", at))?;
+    let mut header = Header::new(size);
+    let options = [("language", "version", "1 or 2"), ("cells", "cells", "\"native\" or \"byte\""), ("tape", "tape", "\"clamp\" or \"wrap\"")];
+    for (key, option, allowed) in options {
+        let Some(setting) = optional(value, key) else { continue; };
+        // Options are checked the same way the header line's are
+        let setting = match setting {
+            Value::Number(num) => num.to_string(),
+            Value::String(text) => text.clone(),
+            _ => String::new(),
+        };
+        header.set(option, &setting).map_err(|_| expected(&format!("{}.{}", at, key), allowed))?;
+    }
+
+    Ok(header)
+}
+
+fn param_to(param: &ParameterKind) -> Value {
+    match param {
+        ParameterKind::Numeric(val) => json!({ "value": val }),
+        ParameterKind::Saved(register) => json!({ "saved": register }),
+    }
+}
+
+fn param_from(value: &Value, at: &str) -> JsonResult<ParameterKind> {
+    if let Some(val) = value.get("value") {
+        return Ok(ParameterKind::Numeric(signed(val, &format!("{}.value", at))?));
+    }
+    if let Some(register) = value.get("saved") {
+        return Ok(ParameterKind::Saved(register_from(register, &format!("{}.saved", at))?));
+    }
+
+    Err(expected(at, "a \"value\" or \"saved\" field"))
+}
+
+fn register_from(value: &Value, at: &str) -> JsonResult<usize> {
+    match number(value, at)? {
+        register if register < SAVE_REGISTERS => Ok(register),
+        _ => Err(expected(at, format!("a save register below {}", SAVE_REGISTERS))),
+    }
+}
+
+/// A parameter that names a cell, which the parser never lets be negative.
+fn cell_from(value: &Value, at: &str) -> JsonResult<ParameterKind> {
+    match param_from(value, at)? {
+        ParameterKind::Numeric(idx) if idx < 0 => Err(expected(&format!("{}.value", at), "a cell index, which can't be negative")),
+        param => Ok(param),
+    }
+}
+
+fn condition_to(condition: &Condition) -> Value {
+    let compare = match condition.comparison {
+        Comparison::Equal => "equal",
+        Comparison::NotEqual => "not_equal",
+        Comparison::Less => "less",
+        Comparison::LessEqual => "less_equal",
+        Comparison::Greater => "greater",
+        Comparison::GreaterEqual => "greater_equal",
+    };
+    json!({ "compare": compare, "against": param_to(&condition.param) })
+}
+
+fn condition_from(value: &Value, at: &str) -> JsonResult<Condition> {
+    let compare_at = format!("{}.compare", at);
+    let compare = string(field(value, at, "compare")?, &compare_at)?;
+    let comparison = match compare {
+        "equal" => Comparison::Equal,
+        "not_equal" => Comparison::NotEqual,
+        "less" => Comparison::Less,
+        "less_equal" => Comparison::LessEqual,
+        "greater" => Comparison::Greater,
+        "greater_equal" => Comparison::GreaterEqual,
+        _ => return Err(expected(&compare_at, "one of \"equal\", \"not_equal\", \"less\", \"less_equal\", \"greater\" or \"greater_equal\"")),
+    };
+    let param = param_from(field(value, at, "against")?, &format!("{}.against", at))?;
+
+    Ok(Condition { comparison, param })
+}
+
+fn block_to(stmts: &[Statement]) -> Value {
+    Value::Array(stmts.iter().map(statement_to).collect())
+}
+
+fn block_from(value: &Value, at: &str, depth: usize) -> JsonResult<Vec<Statement>> {
+    if depth > MAX_NESTING {
+        return Err(expected(at, format!("blocks nested at most {} deep", MAX_NESTING)));
+    }
+
+    // Reading a statement takes more stack than parsing one, so make room rather than overflow
+    // on blocks the parser was fine with
+    let stmts = value.as_array().ok_or_else(|| expected(at, "a list of statements"))?;
+    stacker::maybe_grow(64 * 1024, 1024 * 1024, || {
+        stmts.iter().enumerate().map(|(idx, stmt)| statement_from(stmt, &format!("{}[{}]", at, idx), depth)).collect()
+    })
+}
+
+fn statement_to(stmt: &Statement) -> Value {
+    let (kind, mut value) = match &stmt.kind {
+        StatementKind::Table(header) => ("table", json!({ "header": header_to(header) })),
+        StatementKind::Inc => ("inc", json!({})),
+        StatementKind::Dec => ("dec", json!({})),
+        StatementKind::Mul => ("mul", json!({})),
+        StatementKind::Div => ("div", json!({})),
+        StatementKind::MovR => ("mov_right", json!({})),
+        StatementKind::MovL => ("mov_left", json!({})),
+        StatementKind::Print => ("print", json!({})),
+        // Blocks are put in place rather than passed to `json!`, which would copy them again at
+        // every level they're nested in
+        StatementKind::Loop(body, until) => {
+            let mut value = json!({ "until": until.as_ref().map(condition_to) });
+            value["body"] = block_to(body);
+            ("loop", value)
+        },
+        StatementKind::Define(val) => ("define", json!({ "value": val })),
+        StatementKind::If(condition, then, otherwise) => {
+            let mut value = json!({ "condition": condition_to(condition) });
+            value["then"] = block_to(then);
+            value["else"] = otherwise.as_deref().map_or(Value::Null, block_to);
+            ("if", value)
+        },
+        StatementKind::Goto(param) => ("goto", json!({ "cell": param_to(param) })),
+        StatementKind::Save(register) => ("save", json!({ "register": register })),
+        StatementKind::PrintAscii => ("print_ascii", json!({})),
+        StatementKind::Copy(param) => ("copy", json!({ "cell": param_to(param) })),
+        StatementKind::Modulo => ("modulo", json!({})),
+        StatementKind::DefineFn(id, locals, body) => {
+            let mut value = json!({ "id": id, "locals": locals });
+            value["body"] = block_to(body);
+            ("define_fn", value)
+        },
+        StatementKind::CallFn(id) => ("call_fn", json!({ "id": id })),
+        StatementKind::PrintNewline => ("print_newline", json!({})),
+        StatementKind::FlipSign => ("flip_sign", json!({})),
+        StatementKind::Input => ("input", json!({})),
+        StatementKind::Break => ("break", json!({})),
+        StatementKind::Continue => ("continue", json!({})),
+        StatementKind::Return => ("return", json!({})),
+        StatementKind::Comment(text) => ("comment", json!({ "text": text })),
+        StatementKind::End => ("end", json!({})),
+    };
+
+    value["kind"] = json!(kind);
+    value["loc"] = location_to(&stmt.token.loc);
+    if let Some(Name { name, binds }) = &stmt.name {
+        value["name"] = json!({ "name": name, "binds": binds });
+    }
+
+    value
+}
+
+fn statement_from(value: &Value, at: &str, depth: usize) -> JsonResult<Statement> {
+    let kind_at = format!("{}.kind", at);
+    let kind = string(field(value, at, "kind")?, &kind_at)?;
+    let loc = location_from(field(value, at, "loc")?, &format!("{}.loc", at))?;
+
+    let get = |key: &str| field(value, at, key);
+    let path = |key: &str| format!("{}.{}", at, key);
+    let block = |key: &str| block_from(get(key)?, &path(key), depth + 1);
+
+    let kind = match kind {
+        "table" => StatementKind::Table(header_from(get("header")?, &path("header"))?),
+        "inc" => StatementKind::Inc,
+        "dec" => StatementKind::Dec,
+        "mul" => StatementKind::Mul,
+        "div" => StatementKind::Div,
+        "mov_right" => StatementKind::MovR,
+        "mov_left" => StatementKind::MovL,
+        "print" => StatementKind::Print,
+        "loop" => {
+            let until = optional(value, "until").map(|until| condition_from(until, &path("until"))).transpose()?;
+            StatementKind::Loop(block("body")?, until)
+        },
+        "define" => StatementKind::Define(signed(get("value")?, &path("value"))?),
+        "if" => {
+            let otherwise = match optional(value, "else") {
+                Some(otherwise) => Some(block_from(otherwise, &path("else"), depth + 1)?),
+                None => None,
+            };
+            StatementKind::If(condition_from(get("condition")?, &path("condition"))?, block("then")?, otherwise)
+        },
+        "goto" => StatementKind::Goto(cell_from(get("cell")?, &path("cell"))?),
+        "save" => StatementKind::Save(register_from(get("register")?, &path("register"))?),
+        "print_ascii" => StatementKind::PrintAscii,
+        "copy" => StatementKind::Copy(cell_from(get("cell")?, &path("cell"))?),
+        "modulo" => StatementKind::Modulo,
+        "define_fn" => {
+            let locals = optional(value, "locals").map(|locals| number(locals, &path("locals"))).transpose()?;
+            if locals.is_some_and(|locals| locals > MAX_LOCALS) {
+                return Err(expected(&path("locals"), format!("at most {} locals", MAX_LOCALS)));
+            }
+            StatementKind::DefineFn(number(get("id")?, &path("id"))?, locals, block("body")?)
+        },
+        "call_fn" => StatementKind::CallFn(number(get("id")?, &path("id"))?),
+        "print_newline" => StatementKind::PrintNewline,
+        "flip_sign" => StatementKind::FlipSign,
+        "input" => StatementKind::Input,
+        "break" => StatementKind::Break,
+        "continue" => StatementKind::Continue,
+        "return" => StatementKind::Return,
+        "comment" => StatementKind::Comment(string(get("text")?, &path("text"))?.to_string()),
+        "end" => StatementKind::End,
+        other => return Err(expected(&kind_at, format!("a statement kind, not \"{}\"", other))),
+    };
+
+    let name = match optional(value, "name") {
+        Some(name) => {
+            let at = path("name");
+            let binds = field(name, &at, "binds")?.as_bool().ok_or_else(|| expected(&format!("{}.binds", at), "true or false"))?;
+            Some(Name { name: string(field(name, &at, "name")?, &format!("{}.name", at))?.to_string(), binds })
+        },
+        None => None,
+    };
+
+    let token_kind = kind.token_kind();
+    Ok(Statement::named(kind, Token::new(token_kind, loc), name))
+}
+
+fn symbols_from(value: &Value, at: &str) -> JsonResult<BTreeMap<String, usize>> {
+    let entries = value.as_object().ok_or_else(|| expected(at, "an object of names to numbers"))?;
+    entries.iter().map(|(name, num)| Ok((name.clone(), number(num, &format!("{}.{}", at, name))?))).collect()
+}
+
+/// The JSON form of a program.
+pub fn to_json(program: &Program) -> Value {
+    let mut value = json!({
+        "schema": SCHEMA_VERSION,
+        "header": header_to(&program.header()),
+        "symbols": { "functions": program.symbols.functions, "cells": program.symbols.cells },
+    });
+    value["statements"] = block_to(&program.statements);
+
+    value
+}
+
+/// Read a program back from its JSON form.
+pub(crate) fn from_json(value: &Value) -> JsonResult<Program> {
+    if value.get("schema").and_then(Value::as_u64) != Some(SCHEMA_VERSION) {
+        return Err(expected("schema", format!("{}, the only schema this version of minlang2 reads", SCHEMA_VERSION)));
+    }
+
+    let header = header_from(field(value, "the top level", "header")?, "header")?;
+    let statements = block_from(field(value, "the top level", "statements")?, "statements", 0)?;
+
+    let symbols = field(value, "the top level", "symbols")?;
+    let symbols = SymbolTable {
+        functions: symbols_from(field(symbols, "symbols", "functions")?, "symbols.functions")?,
+        cells: symbols_from(field(symbols, "symbols", "cells")?, "symbols.cells")?,
+    };
+
+    // Only top-level definitions can be called, just as the parser collects them
+    let functions = statements.iter()
+        .filter_map(|stmt| match stmt.kind {
+            StatementKind::DefineFn(id, ..) => Some((id, stmt.clone())),
+            _ => None,
+        })
+        .collect();
+
+    let mut program = Program::new(statements, functions, Table::from_header(&header));
+    program.symbols = symbols;
+    program.version = header.version;
+
+    Ok(program)
+}
+
+/// Read a program from the text of its JSON form.
+pub fn load(text: &str) -> JsonResult<Program> {
+    // Every nested block is a few levels of JSON, more than serde_json allows by default, so
+    // read without its limit on a stack that grows as deep as the document goes
+    let mut deserializer = serde_json::Deserializer::from_str(text);
+    deserializer.disable_recursion_limit();
+    let value = Value::deserialize(serde_stacker::Deserializer::new(&mut deserializer)).map_err(JsonError::Syntax)?;
+    deserializer.end().map_err(JsonError::Syntax)?;
+
+    from_json(&value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use serde_json::json;
+
+    use crate::lexer::{SourceFile, TokenLocation};
+    use crate::parser::MAX_NESTING;
+    use crate::printer::Printer;
+    use crate::program::{assert_same_program, with_deep_stack, Program, MAX_LOCALS, SAMPLE_SOURCE};
+
+    use super::{from_json, load, location_from, location_to, to_json, JsonError};
+
+    #[test]
+    fn test_round_trip() {
+        let program = Program::parse_str(SAMPLE_SOURCE);
+        let loaded = load(&serde_json::to_string(&to_json(&program)).unwrap()).unwrap();
+
+        assert_same_program(&loaded, &program);
+        // Unlike bytecode, JSON keeps names, so check them along with locations
+        assert_eq!(loaded.symbols, program.symbols);
+        let locs = |program: &Program| program.statements.iter().map(|stmt| (stmt.token.loc.clone(), stmt.name.clone())).collect::<Vec<_>>();
+        assert_eq!(locs(&loaded), locs(&program));
+        assert_eq!(Printer::new().print_program(&loaded), Printer::new().print_program(&program));
+    }
+
+    #[test]
+    fn test_deep_round_trip() {
+        with_deep_stack(|| {
+            let deep = |depth: usize| format!("[4]\n+ {}-{} .", "{".repeat(depth), "}".repeat(depth));
+            let program = Program::parse_str(&deep(MAX_NESTING));
+            let text = serde_json::to_string(&to_json(&program)).unwrap();
+            assert_eq!(load(&text).unwrap().statements, program.statements);
+
+            // One more level than the parser takes is refused, as it would be in source
+            let mut value = to_json(&program);
+            let outer = value["statements"].as_array_mut().unwrap().iter_mut().find(|stmt| stmt["kind"] == "loop").unwrap();
+            *outer = json!({ "kind": "loop", "loc": outer["loc"].clone(), "body": [outer.take()], "until": null });
+            let err = load(&value.to_string()).err().unwrap();
+            assert!(err.to_string().contains(&format!("blocks nested at most {} deep", MAX_NESTING)), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_locations() {
+        let mut loc = TokenLocation::new(2, 3, 10, 11);
+        loc.file = Some(Rc::new(SourceFile { path: "lib/io.mini".into(), included_at: TokenLocation::new(1, 1, 0, 8) }));
+        loc.expansion = Some(Rc::new(TokenLocation::new(4, 2, 30, 35)));
+
+        let value = location_to(&loc);
+        assert_eq!(value["file"]["path"], "lib/io.mini");
+        assert_eq!(location_from(&value, "loc").unwrap(), loc);
+    }
+
+    #[test]
+    fn test_schema() {
+        let value = to_json(&Program::parse_str("[4]\n?(>2) + ;"));
+        assert_eq!(value["header"], json!({ "size": 4, "language": 2, "cells": "native", "tape": "clamp" }));
+        assert_eq!(value["statements"][0]["kind"], "if");
+        assert_eq!(value["statements"][0]["condition"], json!({ "compare": "greater", "against": { "value": 2 } }));
+        assert_eq!(value["statements"][0]["then"][0], json!({ "kind": "inc", "loc": { "line": 2, "col": 7, "start": 10, "end": 11 } }));
+        assert_eq!(value["statements"][0]["else"], json!(null));
+    }
+
+    #[test]
+    fn test_errors_say_where() {
+        let mut value = to_json(&Program::parse_str("[4]\n{ @(1) }"));
+        value["statements"][0]["body"][0]["cell"] = json!({ "value": -1 });
+        let err = from_json(&value).err().unwrap();
+        assert_eq!(err.to_string(), "Expected a cell index, which can't be negative at statements[0].body[0].cell.value.");
+
+        value["statements"][0]["body"][0]["kind"] = json!("jump");
+        assert!(matches!(from_json(&value), Err(JsonError::Schema(at, _)) if at == "statements[0].body[0].kind"));
+
+        let mut frame = to_json(&Program::parse_str("[4]\n:(0)(1) + ;"));
+        frame["statements"][0]["locals"] = json!(MAX_LOCALS + 1);
+        assert!(matches!(from_json(&frame), Err(JsonError::Schema(at, _)) if at == "statements[0].locals"));
+
+        value["schema"] = json!(2);
+        assert!(matches!(from_json(&value), Err(JsonError::Schema(at, _)) if at == "schema"));
+        assert!(matches!(load("{"), Err(JsonError::Syntax(_))));
+    }
+}
@@ -1,22 +1,101 @@
-use std::{iter::Peekable, str::Chars, fmt::Display};
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::program::{Header, Version};
+
+/// A file other than the one being compiled, pulled in by an include.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SourceFile {
+    /// The path as it was found, for messages.
+    pub(crate) path: PathBuf,
+    /// The `"path"` that included it.
+    pub(crate) included_at: TokenLocation,
+}
 
-#[derive(Clone, Debug)]
-pub(crate) struct TokenLocation {
-    pub(crate) line: usize,
-    pub(crate) col: usize,
-    pub(crate) len: usize,
-    pub(crate) span: String,
+/// Where a token lives in the source text.
+///
+/// `start` and `end` are byte offsets into the source (`end` is exclusive), so the token's
+/// text can be recovered with [`TokenLocation::span`] without every token owning a copy.
+/// `line` and `col` are 1-based and count characters, for humans, and `chars` is how many
+/// characters the token spans.
+///
+/// Tokens from an included file carry that file in `file`; it's `None` for the file being
+/// compiled. Tokens copied out of a macro body by expansion keep their place in the body, and
+/// `expansion` records the call that put them there.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenLocation {
+    pub line: usize,
+    pub col: usize,
+    pub start: usize,
+    pub end: usize,
+    pub(crate) chars: usize,
+    pub(crate) file: Option<Rc<SourceFile>>,
+    pub(crate) expansion: Option<Rc<TokenLocation>>,
 }
 
-impl<'source> TokenLocation {
-    pub(crate) fn new(text: &String, line: usize, col: usize, start: usize, end: usize) -> TokenLocation {
-        TokenLocation { line: line, col: col, len: end-start+1, span: text[start..=end].into() }
+impl TokenLocation {
+    /// A location whose text is taken to be ASCII, so it's as many characters long as bytes.
+    pub(crate) fn new(line: usize, col: usize, start: usize, end: usize) -> TokenLocation {
+        TokenLocation { line, col, start, end, chars: end - start, file: None, expansion: None }
+    }
+
+    /// The column of the token's last character.
+    pub(crate) fn last_col(&self) -> usize {
+        self.col + self.chars - 1
+    }
+
+    /// `line L col C`, naming the file if it isn't the one being compiled.
+    pub(crate) fn describe(&self) -> String {
+        match &self.file {
+            Some(file) => format!("line {} col {} of {}", self.line, self.col, file.path.display()),
+            None => format!("line {} col {}", self.line, self.col),
+        }
+    }
+
+    /// This location, as seen through a macro call at `call`.
+    pub(crate) fn expanded_at(&self, call: &TokenLocation) -> TokenLocation {
+        TokenLocation { expansion: Some(Rc::new(call.clone())), ..self.clone() }
+    }
+
+    /// Which file this location is in and the macro calls it was expanded through, to append
+    /// to error messages that give its line and column. Empty for a token written right where
+    /// it is in the file being compiled.
+    pub(crate) fn origin_note(&self) -> String {
+        let mut note = String::new();
+
+        if let Some(file) = &self.file {
+            let mut includes = vec![file.included_at.describe()];
+            let mut at = &file.included_at.file;
+            while let Some(outer) = at {
+                includes.push(outer.included_at.describe());
+                at = &outer.included_at.file;
+            }
+            note.push_str(&format!(" In {}, included at {}.", file.path.display(), includes.join(", included at ")));
+        }
+
+        let mut calls = Vec::new();
+        let mut at = &self.expansion;
+        while let Some(call) = at {
+            calls.push(call.describe());
+            at = &call.expansion;
+        }
+        if !calls.is_empty() {
+            note.push_str(&format!(" Expanded from the macro call at {}.", calls.join(", called from ")));
+        }
+
+        note
+    }
+
+    /// The slice of `text` this location covers, or an empty string if it doesn't belong to `text`.
+    pub(crate) fn span<'source>(&self, text: &'source str) -> &'source str {
+        text.get(self.start..self.end).unwrap_or("")
     }
 }
 
 #[derive(Clone, PartialEq, Eq, Debug)]
-pub(crate) enum TokenKind {
-    Table(usize),               // [size]
+pub enum TokenKind {
+    Table(Header),              // [size, options]
     Inc,                        // +
     Dec,                        // -
     Mul,                        // *
@@ -32,7 +111,7 @@ pub(crate) enum TokenKind {
     RParen,                     // )
     Question,                   // ?
     Goto,                       // @
-    Save,                       // $
+    Save(usize),                // $ or $n, naming save register n
     PrintAscii,                 // &
     CopyTo,                     // =
     Modulo,                     // %
@@ -41,46 +120,74 @@ pub(crate) enum TokenKind {
     Newline,                    // _
     Eos,                        // ;
     Tilde,                      // ~
+    Bang,                       // !
+    Pipe,                       // |
+    Backtick,                   // `
     Number(usize),              // numeric
+    Negative(usize),            // -numeric, holding the magnitude
+    Char(char),                 // 'c'
+    Ident(String),              // name, only right after (
+    Macro(String),              // \name
+    Comma,                      // , only inside a macro's parentheses
+    Str(String),                // "path"
+    Comment(String),            // # line or #| block |#
 }
 
 impl Display for TokenKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.clone() {
-            Self::Table(num) => return f.write_fmt(format_args!("{}", num)),
-            Self::Inc => return f.write_fmt(format_args!("+")),
-            Self::Dec => return f.write_fmt(format_args!("-")),
-            Self::Mul => return f.write_fmt(format_args!("*")),
-            Self::Div => return f.write_fmt(format_args!("/")),
-            Self::MovR => return f.write_fmt(format_args!(">")),
-            Self::MovL => return f.write_fmt(format_args!("<")),
-            Self::Print => return f.write_fmt(format_args!(".")),
-            Self::StartLoop => return f.write_fmt(format_args!("{{")),
-            Self::EndLoop => return f.write_fmt(format_args!("}}")),
-            Self::StartDefine => return f.write_fmt(format_args!("[")),
-            Self::EndDefine => return f.write_fmt(format_args!("]")),
-            Self::LParen => return f.write_fmt(format_args!("(")),
-            Self::RParen => return f.write_fmt(format_args!(")")),
-            Self::Question => return f.write_fmt(format_args!("?")),
-            Self::Goto => return f.write_fmt(format_args!("@")),
-            Self::Save => return f.write_fmt(format_args!("$")),
-            Self::PrintAscii => return f.write_fmt(format_args!("&")),
-            Self::CopyTo => return f.write_fmt(format_args!("=")),
-            Self::Modulo => return f.write_fmt(format_args!("%")),
-            Self::FuncDef => return f.write_fmt(format_args!(":")),
-            Self::FuncCall => return f.write_fmt(format_args!("^")),
-            Self::Newline => return f.write_fmt(format_args!("_")),
-            Self::Eos => return f.write_fmt(format_args!(";")),
-            Self::Tilde => return f.write_fmt(format_args!("~")),
-            Self::Number(num) => return f.write_fmt(format_args!("{}", num)),
+            Self::Table(header) => f.write_fmt(format_args!("{}", header)),
+            Self::Inc => f.write_fmt(format_args!("+")),
+            Self::Dec => f.write_fmt(format_args!("-")),
+            Self::Mul => f.write_fmt(format_args!("*")),
+            Self::Div => f.write_fmt(format_args!("/")),
+            Self::MovR => f.write_fmt(format_args!(">")),
+            Self::MovL => f.write_fmt(format_args!("<")),
+            Self::Print => f.write_fmt(format_args!(".")),
+            Self::StartLoop => f.write_fmt(format_args!("{{")),
+            Self::EndLoop => f.write_fmt(format_args!("}}")),
+            Self::StartDefine => f.write_fmt(format_args!("[")),
+            Self::EndDefine => f.write_fmt(format_args!("]")),
+            Self::LParen => f.write_fmt(format_args!("(")),
+            Self::RParen => f.write_fmt(format_args!(")")),
+            Self::Question => f.write_fmt(format_args!("?")),
+            Self::Goto => f.write_fmt(format_args!("@")),
+            Self::Save(0) => f.write_fmt(format_args!("$")),
+            Self::Save(register) => f.write_fmt(format_args!("${}", register)),
+            Self::PrintAscii => f.write_fmt(format_args!("&")),
+            Self::CopyTo => f.write_fmt(format_args!("=")),
+            Self::Modulo => f.write_fmt(format_args!("%")),
+            Self::FuncDef => f.write_fmt(format_args!(":")),
+            Self::FuncCall => f.write_fmt(format_args!("^")),
+            Self::Newline => f.write_fmt(format_args!("_")),
+            Self::Eos => f.write_fmt(format_args!(";")),
+            Self::Tilde => f.write_fmt(format_args!("~")),
+            Self::Bang => f.write_fmt(format_args!("!")),
+            Self::Pipe => f.write_fmt(format_args!("|")),
+            Self::Backtick => f.write_fmt(format_args!("`")),
+            Self::Number(num) => f.write_fmt(format_args!("{}", num)),
+            Self::Negative(num) => f.write_fmt(format_args!("-{}", num)),
+            Self::Char(chr) => match chr {
+                '\n' => f.write_str("'\\n'"),
+                '\t' => f.write_str("'\\t'"),
+                '\r' => f.write_str("'\\r'"),
+                '\0' => f.write_str("'\\0'"),
+                '\\' | '\'' => f.write_fmt(format_args!("'\\{}'", chr)),
+                _ => f.write_fmt(format_args!("'{}'", chr)),
+            },
+            Self::Ident(name) => f.write_fmt(format_args!("{}", name)),
+            Self::Macro(name) => f.write_fmt(format_args!("\\{}", name)),
+            Self::Comma => f.write_fmt(format_args!(",")),
+            Self::Str(text) => f.write_fmt(format_args!("\"{}\"", text)),
+            Self::Comment(text) => f.write_fmt(format_args!("{}", text)),
         }
     }
 }
 
 #[derive(Clone, Debug)]
-pub(crate) struct Token {
-    pub(crate) kind: TokenKind,
-    pub(crate) loc: TokenLocation,
+pub struct Token {
+    pub kind: TokenKind,
+    pub loc: TokenLocation,
 }
 
 impl Token {
@@ -89,8 +196,8 @@ impl Token {
     }
 }
 
-pub(crate) struct TokenStream {
-    pub(crate) tokens: Vec<Token>,
+pub struct TokenStream {
+    pub tokens: Vec<Token>,
     curr: usize,
 }
 
@@ -114,214 +221,487 @@ impl Iterator for TokenStream {
     }
 }
 
-pub(crate) struct Lexer {
+/// Knobs controlling how the lexer treats text that is not an instruction.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LexerOptions {
+    /// Emit `TokenKind::Comment` tokens instead of dropping comments.
+    pub keep_comments: bool,
+    /// Reject any non-whitespace character outside of a comment that is not an instruction.
+    pub strict: bool,
+}
+
+/// A single forward pass over the source text.
+///
+/// `pos` is a byte offset into `text` and only ever moves forward, so lexing is linear in the
+/// size of the input. `line`/`col` track the character at `pos`.
+pub struct Lexer {
     text: String,
+    pos: usize,
     line: usize,
     col: usize,
-    curr_char: usize,
+    // Where the token currently being lexed started
+    start: usize,
+    start_line: usize,
+    start_col: usize,
+    options: LexerOptions,
+    // Set on every location, when lexing an included file
+    file: Option<Rc<SourceFile>>,
 }
 
 #[derive(Debug)]
-pub(crate) enum LexError {
-    UnexpectedToken(TokenKind, TokenLocation),
-    ExpectedToken(TokenKind, TokenLocation),
+pub enum LexError {
+    ExpectedToken(TokenKind, Option<char>, TokenLocation),
+    ExpectedNumber(Option<char>, TokenLocation),
+    UnexpectedCharacter(char, TokenLocation),
+    UnterminatedComment(TokenLocation),
+    UnterminatedString(TokenLocation),
+    BadCharacter(TokenLocation),
+    /// A `key=value` in the table header, and why it's wrong.
+    BadTableOption(String, String, TokenLocation),
 }
 
-type LexResult<'source> = Result<TokenStream, LexError>;
+type LexResult = Result<TokenStream, LexError>;
+
+fn describe(found: &Option<char>) -> String {
+    match found {
+        Some(chr) => format!("'{}'", chr),
+        None => "end of file".into(),
+    }
+}
+
+impl LexError {
+    /// Where in the source the error was found.
+    pub fn location(&self) -> &TokenLocation {
+        match self {
+            LexError::ExpectedToken(_, _, loc) => loc,
+            LexError::ExpectedNumber(_, loc) => loc,
+            LexError::UnexpectedCharacter(_, loc) => loc,
+            LexError::UnterminatedComment(loc) => loc,
+            LexError::UnterminatedString(loc) => loc,
+            LexError::BadCharacter(loc) => loc,
+            LexError::BadTableOption(_, _, loc) => loc,
+        }
+    }
+}
 
 impl Display for LexError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.clone() {
-            LexError::UnexpectedToken(_, loc) => {
-                return f.write_fmt(format_args!(
-                    "Unexpected token '{}' at line {} col {}.",
-                    loc.span, loc.line, loc.col
-                ));
+        match self {
+            LexError::ExpectedToken(kind, found, loc) => {
+                f.write_fmt(format_args!(
+                    "Expected token '{}' at line {} col {}, got {}.",
+                    kind, loc.line, loc.col, describe(found)
+                ))
             }
-            LexError::ExpectedToken(kind, loc) => {
-                return f.write_fmt(format_args!(
-                    "Expected token '{}' at line {} col {}, got '{}'.",
-                    kind, loc.line, loc.col, loc.span
-                ));
+            LexError::ExpectedNumber(found, loc) => {
+                f.write_fmt(format_args!(
+                    "Expected a number at line {} col {}, got {}.",
+                    loc.line, loc.col, describe(found)
+                ))
             }
-        }
+            LexError::UnexpectedCharacter(chr, loc) => {
+                f.write_fmt(format_args!(
+                    "Unexpected character '{}' at line {} col {}; use '#' to start a comment.",
+                    chr, loc.line, loc.col
+                ))
+            }
+            LexError::UnterminatedComment(loc) => {
+                f.write_fmt(format_args!(
+                    "Unterminated block comment starting at line {} col {}.",
+                    loc.line, loc.col
+                ))
+            }
+            LexError::UnterminatedString(loc) => {
+                f.write_fmt(format_args!(
+                    "Unterminated string starting at line {} col {}.",
+                    loc.line, loc.col
+                ))
+            }
+            LexError::BadTableOption(option, reason, loc) => {
+                f.write_fmt(format_args!(
+                    "Bad table option '{}' at line {} col {}: {}. The options are version=1 or 2, cells=native or byte, and tape=clamp or wrap.",
+                    option, loc.line, loc.col, reason
+                ))
+            }
+            LexError::BadCharacter(loc) => {
+                f.write_fmt(format_args!(
+                    "Malformed character at line {} col {}; write one character or escape in single quotes, like 'A' or '\\n'.",
+                    loc.line, loc.col
+                ))
+            }
+        }?;
+
+        f.write_str(&self.location().origin_note())
     }
 }
 
 impl Lexer {
-    pub(crate) fn new(text: String) -> Lexer {
+    pub fn new(text: String) -> Lexer {
+        Lexer::with_options(text, LexerOptions::default())
+    }
+
+    pub fn with_options(text: String, options: LexerOptions) -> Lexer {
         Lexer {
-            text: text,
+            text,
+            pos: 0,
             line: 1,
             col: 1,
-            curr_char: 0,
+            start: 0,
+            start_line: 1,
+            start_col: 1,
+            options,
+            file: None,
         }
     }
 
-    fn peek(&mut self) -> Option<char> {
-        self.text.chars().skip(self.curr_char).peekable().peek().copied()
+    /// Mark every token as coming from an included file.
+    pub(crate) fn in_file(mut self, file: Rc<SourceFile>) -> Lexer {
+        self.file = Some(file);
+        self
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
     }
 
     fn next(&mut self) -> Option<char> {
-        if let Some(chr) = self.text.chars().skip(self.curr_char).next() {
-            self.curr_char += 1;
-            match chr {
-                '\n' => { self.line += 1; self.col = 0;}
-                _ => { self.col += 1; }
-            }
-            return Some(chr);
+        let chr = self.peek()?;
+        self.pos += chr.len_utf8();
+        match chr {
+            '\n' => { self.line += 1; self.col = 1; }
+            _ => { self.col += 1; }
         }
 
-        None
+        Some(chr)
+    }
+
+    /// Mark the current position as the start of the next token.
+    fn begin(&mut self) {
+        self.start = self.pos;
+        self.start_line = self.line;
+        self.start_col = self.col;
+    }
+
+    /// Location of everything consumed since the last `begin`.
+    fn loc(&self) -> TokenLocation {
+        let chars = self.text[self.start..self.pos].chars().count();
+        TokenLocation { file: self.file.clone(), chars, ..TokenLocation::new(self.start_line, self.start_col, self.start, self.pos) }
+    }
+
+    /// Location of the character at the cursor, for errors about what comes next.
+    fn here(&self) -> TokenLocation {
+        let end = self.pos + self.peek().map(char::len_utf8).unwrap_or(0);
+        let chars = usize::from(self.peek().is_some());
+        TokenLocation { file: self.file.clone(), chars, ..TokenLocation::new(self.line, self.col, self.pos, end) }
     }
 
     fn emit(&self, kind: TokenKind) -> Token {
-        Token { kind, loc: TokenLocation::new(&self.text, self.line, self.col, self.curr_char-1, self.curr_char-1) }
+        Token::new(kind, self.loc())
     }
 
-    fn emit_large(&self, kind: TokenKind, col: usize, from: usize, to: usize) -> Token {
-        Token { kind, loc: TokenLocation::new(&self.text, self.line, col, from, to) }
+    /// Consume a run of ASCII digits, returning them as a slice of the source.
+    fn lex_digits(&mut self) -> &str {
+        let from = self.pos;
+        while matches!(self.peek(), Some(chr) if chr.is_ascii_digit()) {
+            self.next();
+        }
+
+        &self.text[from..self.pos]
     }
 
-    pub(crate) fn lex(&mut self) -> LexResult {
-        let mut tokens: Vec<Token> = Vec::new();
+    /// Consume the rest of a name whose first letter has already been eaten.
+    fn lex_name(&mut self) {
+        while matches!(self.peek(), Some(chr) if chr.is_ascii_alphanumeric() || chr == '_') {
+            self.next();
+        }
+    }
+
+    /// Lex a string whose opening quote has already been consumed. There are no escapes; a
+    /// string runs to the next `"`.
+    fn lex_string(&mut self) -> Result<TokenKind, LexError> {
+        let from = self.pos;
+        loop {
+            match self.next() {
+                Some('"') => return Ok(TokenKind::Str(self.text[from..self.pos - 1].to_string())),
+                Some(_) => {},
+                None => return Err(LexError::UnterminatedString(TokenLocation { end: self.start + 1, chars: 1, ..self.loc() })),
+            }
+        }
+    }
+
+    /// Lex a character like `'A'` or `'\n'` whose opening quote has already been eaten, or
+    /// `None` if the quote doesn't start one and is just an apostrophe in prose, like "it's".
+    fn lex_char(&mut self) -> Result<Option<TokenKind>, LexError> {
+        // Look before eating anything, so a quote that's prose leaves what follows it to be lexed
+        let mut chars = self.text[self.pos..].chars();
+        let literal = match chars.next() {
+            Some('\\') => match chars.next() {
+                Some('n') => Some(('\n', 2)),
+                Some('t') => Some(('\t', 2)),
+                Some('r') => Some(('\r', 2)),
+                Some('0') => Some(('\0', 2)),
+                Some(chr @ ('\\' | '\'')) => Some((chr, 2)),
+                _ => None,
+            },
+            Some(chr) if chr != '\'' && chr != '\n' => Some((chr, 1)),
+            _ => None,
+        };
+
+        match literal.filter(|_| chars.next() == Some('\'')) {
+            Some((chr, len)) => {
+                for _ in 0..=len {
+                    self.next();
+                }
+                Ok(Some(TokenKind::Char(chr)))
+            },
+            None if self.options.strict => Err(LexError::BadCharacter(self.loc())),
+            None => Ok(None),
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.next();
+        }
+    }
 
-        let table_size: usize = if let Some(char) = self.peek() {
-            if char == '[' {
+    /// Lex the optional `[size, key=value, ...]` header at the very start of the file.
+    fn lex_table_header(&mut self) -> Result<Header, LexError> {
+        if self.peek() != Some('[') {
+            return Ok(Header::new(crate::program::DEFAULT_TABLE_SIZE));
+        }
+        self.next();
+
+        let digits = self.lex_digits();
+        if digits.is_empty() {
+            return Err(LexError::ExpectedNumber(self.peek(), self.here()));
+        }
+        let mut header = Header::new(digits.parse::<usize>().unwrap_or(crate::program::DEFAULT_TABLE_SIZE));
+
+        loop {
+            self.skip_spaces();
+            match self.peek() {
+                Some(']') => break,
+                Some(',') => { self.next(); },
+                _ => return Err(LexError::ExpectedToken(TokenKind::EndDefine, self.peek(), self.here())),
+            }
+            self.skip_spaces();
+
+            let (from, line, col) = (self.pos, self.line, self.col);
+            while matches!(self.peek(), Some(chr) if chr.is_ascii_alphanumeric() || chr == '=') {
                 self.next();
-                if let Some(char2) = self.next() {
-                    if char2.is_numeric() {
-                        // Got a table size                        
-                        // Build a string of the size
-                        let mut num_string = String::new();
-                        num_string.push(char2);
-
-                        while let Some(nchar) = self.next() {
-                            if nchar.is_numeric() {
-                                num_string.push(nchar);
-                            } else if nchar == ']' {
-                                break;
-                            } else if nchar != ']' {
-                                return Err(LexError::ExpectedToken(TokenKind::EndDefine, TokenLocation::new(&self.text, self.line, self.col, self.curr_char, self.curr_char)));
-                            }
-                        }
+            }
+            let option = &self.text[from..self.pos];
+            let loc = TokenLocation { file: self.file.clone(), ..TokenLocation::new(line, col, from, self.pos) };
+
+            let applied = match option.split_once('=') {
+                Some((key, value)) => header.set(key, value),
+                None => Err("options are written as key=value".to_string()),
+            };
+            if let Err(reason) = applied {
+                return Err(LexError::BadTableOption(option.to_string(), reason, loc));
+            }
+        }
+        self.next();
 
-                        str::parse::<usize>(&num_string).unwrap_or(256)
-                    } else { return Err(LexError::UnexpectedToken(TokenKind::Eos, TokenLocation::new(&self.text, self.line, self.col, self.curr_char, self.curr_char))); }
-                } else { 256 }
-            } else { 256 }
-        } else { 256 };
+        Ok(header)
+    }
 
-        // Add table size token.
-        tokens.push(Token { kind: TokenKind::Table(table_size), loc: TokenLocation::new(&self.text, 1, 1, 0, self.curr_char - 1) });
+    /// Lex a comment whose leading `#` has already been consumed.
+    ///
+    /// `# ...` runs to the end of the line, `#| ... |#` runs to the matching `|#` and may nest.
+    fn lex_comment(&mut self) -> Result<Option<Token>, LexError> {
+        if self.peek() == Some('|') {
+            self.next();
+            let mut depth = 1usize;
+
+            while depth > 0 {
+                match self.next() {
+                    Some('|') if self.peek() == Some('#') => {
+                        self.next();
+                        depth -= 1;
+                    },
+                    Some('#') if self.peek() == Some('|') => {
+                        self.next();
+                        depth += 1;
+                    },
+                    Some(_) => {},
+                    None => {
+                        return Err(LexError::UnterminatedComment(TokenLocation { end: self.start + 1, chars: 1, ..self.loc() }));
+                    },
+                }
+            }
+        } else {
+            while let Some(chr) = self.peek() {
+                if chr == '\n' || chr == '\r' {
+                    break;
+                }
+                self.next();
+            }
+        }
 
-        while let Some(char) = self.next() {
-            match char {
-                '+' => {
-                    tokens.push(self.emit(TokenKind::Inc));
-                },
-                '-' => {
-                    tokens.push(self.emit(TokenKind::Dec));
-                },
-                '*' => {
-                    tokens.push(self.emit(TokenKind::Mul));
-                },
-                '/' => {
-                    tokens.push(self.emit(TokenKind::Div));
-                },
-                '>' => {
-                    tokens.push(self.emit(TokenKind::MovR));
-                },
-                '<' => {
-                    tokens.push(self.emit(TokenKind::MovL));
-                },
-                '.' => {
-                    tokens.push(self.emit(TokenKind::Print));
-                },
-                '{' => {
-                    tokens.push(self.emit(TokenKind::StartLoop));
-                },
-                '}' => {
-                    tokens.push(self.emit(TokenKind::EndLoop));
-                },
-                '[' => {
-                    tokens.push(self.emit(TokenKind::StartDefine));
+        if self.options.keep_comments {
+            let text = self.loc().span(&self.text).to_string();
+            Ok(Some(self.emit(TokenKind::Comment(text))))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn lex(&mut self) -> LexResult {
+        let mut tokens: Vec<Token> = Vec::new();
+
+        // Add table size token.
+        self.begin();
+        let header = self.lex_table_header()?;
+        tokens.push(self.emit(TokenKind::Table(header)));
+        // The original language only had the instructions it had, and ignored everything else
+        let legacy = header.version == Version::V1;
+        // The last token, to tell the parentheses statements take from ones in prose
+        let mut prev: Option<TokenKind> = None;
+        // The token before each `(` we're inside, or `None` for one that's only prose
+        let mut parens: Vec<Option<TokenKind>> = Vec::new();
+        // Whether the last token closed a function id, which can be followed by its locals
+        let mut after_function_id = false;
+        // Whether a name can go here
+        let mut name_allowed = false;
+        // Paren depth within each macro argument list we're inside, innermost last
+        let mut macro_parens: Vec<usize> = Vec::new();
+        let mut after_macro = false;
+
+        loop {
+            self.begin();
+            let Some(char) = self.next() else { break; };
+            let mut closes_function_id = false;
+
+            let kind = match char {
+                '+' => TokenKind::Inc,
+                '-' if !legacy && self.peek().is_some_and(|chr| chr.is_ascii_digit()) => {
+                    // A minus sign right before a number makes it negative
+                    TokenKind::Negative(self.lex_digits().parse::<usize>().unwrap_or(0))
                 },
-                ']' => {
-                    tokens.push(self.emit(TokenKind::EndDefine));
+                '-' => TokenKind::Dec,
+                '\'' if !legacy => match self.lex_char()? {
+                    Some(kind) => kind,
+                    None => continue,
                 },
-                '(' =>{
-                    tokens.push(self.emit(TokenKind::LParen));
+                '*' => TokenKind::Mul,
+                '/' => TokenKind::Div,
+                '>' => TokenKind::MovR,
+                '<' => TokenKind::MovL,
+                '.' => TokenKind::Print,
+                '{' => TokenKind::StartLoop,
+                '}' => TokenKind::EndLoop,
+                '[' => TokenKind::StartDefine,
+                ']' => TokenKind::EndDefine,
+                '(' => {
+                    let opens = after_function_id || !macro_parens.is_empty() || prev.as_ref().is_some_and(takes_parens);
+                    if !opens {
+                        // No statement takes it, so it's prose, along with its match
+                        if self.options.strict {
+                            return Err(LexError::UnexpectedCharacter(char, self.loc()));
+                        }
+                        parens.push(None);
+                        continue;
+                    }
+                    parens.push(prev.clone());
+
+                    if after_macro {
+                        macro_parens.push(1);
+                    } else if let Some(depth) = macro_parens.last_mut() {
+                        *depth += 1;
+                    }
+                    TokenKind::LParen
                 },
                 ')' => {
-                    tokens.push(self.emit(TokenKind::RParen));
-                },
-                '?' => {
-                    tokens.push(self.emit(TokenKind::Question));
+                    let opener = parens.pop();
+                    if opener == Some(None) {
+                        continue;
+                    }
+                    closes_function_id = opener == Some(Some(TokenKind::FuncDef));
+
+                    if let Some(depth) = macro_parens.last_mut() {
+                        *depth -= 1;
+                        if *depth == 0 {
+                            macro_parens.pop();
+                        }
+                    }
+                    TokenKind::RParen
                 },
-                '@' => {
-                    tokens.push(self.emit(TokenKind::Goto));
+                ',' if macro_parens.last() == Some(&1) => TokenKind::Comma,
+                '"' if !legacy => self.lex_string()?,
+                '\\' if !legacy && matches!(self.peek(), Some(chr) if chr.is_ascii_alphabetic()) => {
+                    self.lex_name();
+                    TokenKind::Macro(self.loc().span(&self.text)[1..].to_string())
                 },
+                '?' => TokenKind::Question,
+                '@' => TokenKind::Goto,
+                '$' if legacy => TokenKind::Save(0),
                 '$' => {
-                    tokens.push(self.emit(TokenKind::Save));
-                },
-                '&' => {
-                    tokens.push(self.emit(TokenKind::PrintAscii));
-                },
-                '=' => {
-                    tokens.push(self.emit(TokenKind::CopyTo));
-                },
-                '%' => {
-                    tokens.push(self.emit(TokenKind::Modulo));
-                },
-                ':' => {
-                    tokens.push(self.emit(TokenKind::FuncDef));
+                    // Digits right after it pick a register, and $ alone is $0. A register too
+                    // big to parse is still too big, so the parser can say so.
+                    let digits = self.lex_digits();
+                    TokenKind::Save(if digits.is_empty() { 0 } else { digits.parse::<usize>().unwrap_or(usize::MAX) })
                 },
-                '^' => {
-                    tokens.push(self.emit(TokenKind::FuncCall));
-                },
-                '_' => {
-                    tokens.push(self.emit(TokenKind::Newline));
+                '&' => TokenKind::PrintAscii,
+                '=' => TokenKind::CopyTo,
+                '%' => TokenKind::Modulo,
+                ':' => TokenKind::FuncDef,
+                '^' => TokenKind::FuncCall,
+                '_' => TokenKind::Newline,
+                ';' => TokenKind::Eos,
+                '~' => TokenKind::Tilde,
+                '!' if !legacy => TokenKind::Bang,
+                '|' if !legacy => TokenKind::Pipe,
+                '`' if !legacy => TokenKind::Backtick,
+                '#' if !legacy => {
+                    if let Some(comment) = self.lex_comment()? {
+                        tokens.push(comment);
+                    }
+                    continue;
                 },
-                ';' => {
-                    tokens.push(self.emit(TokenKind::Eos));
+                '0'..='9' => {
+                    // Need to lex a number; the first digit is already eaten
+                    self.lex_digits();
+                    TokenKind::Number(self.loc().span(&self.text).parse::<usize>().unwrap_or(0))
                 },
-                '~' => {
-                    tokens.push(self.emit(TokenKind::Tilde));
+                'a'..='z' | 'A'..='Z' if name_allowed && !legacy => {
+                    // Names can only be function ids, cells and macro arguments, so letters
+                    // anywhere else are still ignored
+                    self.lex_name();
+                    TokenKind::Ident(self.loc().span(&self.text).to_string())
                 },
                 _ => {
-                    if char.is_numeric() {
-                        // Need to lex a number
-                        let start_char = self.curr_char.checked_sub(1).unwrap_or(0);
-                        let start_col = self.col;
-
-                        let mut num_string = String::new();
-                        num_string.push(char);
-                        while let Some(nchar) = self.peek() {
-                            if nchar.is_numeric() {
-                                self.next();
-                                num_string.push(nchar);
-                            } else {
-                                break;
-                            }
-                        }
-
-                        tokens.push(Token { 
-                            kind: TokenKind::Number(str::parse::<usize>(&num_string).unwrap_or(0)),
-                            loc: TokenLocation::new(&self.text, self.line, start_col, start_char, self.curr_char-1)
-                        });
+                    if self.options.strict && !char.is_whitespace() {
+                        return Err(LexError::UnexpectedCharacter(char, self.loc()));
                     }
+                    continue;
                 }
-            }
+            };
+
+            name_allowed = kind == TokenKind::LParen && (after_macro || matches!(prev, Some(TokenKind::FuncDef | TokenKind::FuncCall | TokenKind::Goto | TokenKind::CopyTo)));
+            after_function_id = closes_function_id;
+            prev = Some(kind.clone());
+            after_macro = matches!(kind, TokenKind::Macro(_));
+            tokens.push(self.emit(kind));
         }
 
-        return Ok(TokenStream { tokens, curr: 0 });
+        Ok(TokenStream::new(tokens))
     }
 }
 
+/// Whether a `(` right after a token of this kind belongs to its statement.
+fn takes_parens(kind: &TokenKind) -> bool {
+    matches!(kind, TokenKind::FuncDef | TokenKind::FuncCall | TokenKind::Goto | TokenKind::CopyTo
+        | TokenKind::Question | TokenKind::EndLoop | TokenKind::Macro(_))
+}
+
+#[cfg(test)]
 #[allow(unused_imports)]
 mod tests {
-    use super::{Lexer, LexResult, Token, TokenKind, TokenLocation, TokenStream};
+    use proptest::prelude::*;
+
+    use super::{Header, Lexer, LexerOptions, LexError, Token, TokenKind, TokenLocation, TokenStream};
 
     #[test]
     fn test_it_works() {
@@ -331,4 +711,185 @@ mod tests {
         assert!(lex_result.is_ok());
         println!("{:?}", lex_result.unwrap().tokens);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_comments_swallow_digits() {
+        let mut lexer = Lexer::new("[32]\n# Allocates thirty 2 registers\n++ #| block 4 #| nested 5 |# still 6 |# .".into());
+        let tokens = lexer.lex().unwrap().tokens;
+
+        let kinds: Vec<TokenKind> = tokens.into_iter().map(|tok| tok.kind).collect();
+        assert_eq!(kinds, vec![TokenKind::Table(Header::new(32)), TokenKind::Inc, TokenKind::Inc, TokenKind::Print]);
+    }
+
+    #[test]
+    fn test_keep_comments() {
+        let options = LexerOptions { keep_comments: true, strict: false };
+        let mut lexer = Lexer::with_options("[4]\n+ # one\n#|two|#-".into(), options);
+        let tokens = lexer.lex().unwrap().tokens;
+
+        let kinds: Vec<TokenKind> = tokens.into_iter().map(|tok| tok.kind).collect();
+        assert_eq!(kinds, vec![
+            TokenKind::Table(Header::new(4)),
+            TokenKind::Inc,
+            TokenKind::Comment("# one".into()),
+            TokenKind::Comment("#|two|#".into()),
+            TokenKind::Dec,
+        ]);
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        let options = LexerOptions { keep_comments: false, strict: true };
+        assert!(Lexer::with_options("[4]\n+ # prose is fine here\n-".into(), options).lex().is_ok());
+
+        let err = Lexer::with_options("[4]\n+ prose is not\n-".into(), options).lex().err().unwrap();
+        assert!(matches!(err, LexError::UnexpectedCharacter('p', _)));
+    }
+
+    #[test]
+    fn test_unterminated_block_comment() {
+        let err = Lexer::new("[4]\n+ #| never closed".into()).lex().err().unwrap();
+        assert!(matches!(err, LexError::UnterminatedComment(_)));
+    }
+
+    #[test]
+    fn test_locations_are_byte_ranges() {
+        let source = "[8]\n+ #| héllo |#\n  >";
+        let tokens = Lexer::new(source.into()).lex().unwrap().tokens;
+
+        let inc = &tokens[1];
+        assert_eq!((inc.loc.line, inc.loc.col), (2, 1));
+        assert_eq!(inc.loc.span(source), "+");
+
+        // Columns restart at 1 after a newline and count characters, not bytes
+        let movr = &tokens[2];
+        assert_eq!((movr.loc.line, movr.loc.col), (3, 3));
+        assert_eq!(movr.loc.span(source), ">");
+        assert_eq!(movr.loc.start, source.len() - 1);
+    }
+
+    #[test]
+    fn test_no_table_header() {
+        let tokens = Lexer::new("++@(12)".into()).lex().unwrap().tokens;
+
+        assert_eq!(tokens[0].kind, TokenKind::Table(Header::new(crate::program::DEFAULT_TABLE_SIZE)));
+        assert_eq!(tokens[0].loc.start, tokens[0].loc.end);
+        assert_eq!(tokens[5].kind, TokenKind::Number(12));
+        assert_eq!(tokens[5].loc.end - tokens[5].loc.start, 2);
+    }
+
+    #[test]
+    fn test_names_only_after_paren() {
+        let tokens = Lexer::new("[4]\nloop :( print_2 )^(x1=3)".into()).lex().unwrap().tokens;
+
+        let kinds: Vec<TokenKind> = tokens.into_iter().map(|tok| tok.kind).collect();
+        assert_eq!(kinds, vec![
+            TokenKind::Table(Header::new(4)),
+            TokenKind::FuncDef, TokenKind::LParen, TokenKind::Ident("print_2".into()), TokenKind::RParen,
+            TokenKind::FuncCall, TokenKind::LParen, TokenKind::Ident("x1".into()), TokenKind::CopyTo, TokenKind::Number(3), TokenKind::RParen,
+        ]);
+    }
+
+    #[test]
+    fn test_prose_in_parens() {
+        let tokens = Lexer::new("[4]\n+ (see this, or (that)) . ?(x) ; {}(y)".into()).lex().unwrap().tokens;
+
+        // Parentheses nothing takes are prose, and names only go where a statement takes one
+        let kinds: Vec<TokenKind> = tokens.into_iter().skip(1).map(|tok| tok.kind).collect();
+        assert_eq!(kinds, vec![
+            TokenKind::Inc, TokenKind::Print,
+            TokenKind::Question, TokenKind::LParen, TokenKind::RParen, TokenKind::Eos,
+            TokenKind::StartLoop, TokenKind::EndLoop, TokenKind::LParen, TokenKind::RParen,
+        ]);
+
+        let strict = LexerOptions { strict: true, ..LexerOptions::default() };
+        assert!(matches!(Lexer::with_options("[4]\n+ (see this)".into(), strict).lex(), Err(LexError::UnexpectedCharacter('(', _))));
+    }
+
+    #[test]
+    fn test_macro_commas() {
+        let tokens = Lexer::new("[4]\n, \\m(\\a, @(1), \\n(2, 3)) , \\ +".into()).lex().unwrap().tokens;
+
+        // Commas only count directly inside a macro's parentheses, and a lone backslash is ignored
+        let kinds: Vec<TokenKind> = tokens.into_iter().map(|tok| tok.kind).collect();
+        assert_eq!(kinds, vec![
+            TokenKind::Table(Header::new(4)),
+            TokenKind::Macro("m".into()), TokenKind::LParen, TokenKind::Macro("a".into()), TokenKind::Comma,
+            TokenKind::Goto, TokenKind::LParen, TokenKind::Number(1), TokenKind::RParen, TokenKind::Comma,
+            TokenKind::Macro("n".into()), TokenKind::LParen, TokenKind::Number(2), TokenKind::Comma, TokenKind::Number(3), TokenKind::RParen,
+            TokenKind::RParen,
+            TokenKind::Inc,
+        ]);
+    }
+
+    #[test]
+    fn test_literals() {
+        let tokens = Lexer::new("[4]\n[-12]- 3?('A')[' ']['\\'']['\\n']".into()).lex().unwrap().tokens;
+
+        // Only a minus sign touching a number makes it negative
+        let kinds: Vec<TokenKind> = tokens.into_iter().map(|tok| tok.kind).collect();
+        assert_eq!(kinds, vec![
+            TokenKind::Table(Header::new(4)),
+            TokenKind::StartDefine, TokenKind::Negative(12), TokenKind::EndDefine,
+            TokenKind::Dec, TokenKind::Number(3),
+            TokenKind::Question, TokenKind::LParen, TokenKind::Char('A'), TokenKind::RParen,
+            TokenKind::StartDefine, TokenKind::Char(' '), TokenKind::EndDefine,
+            TokenKind::StartDefine, TokenKind::Char('\''), TokenKind::EndDefine,
+            TokenKind::StartDefine, TokenKind::Char('\n'), TokenKind::EndDefine,
+        ]);
+
+        assert_eq!(TokenKind::Char('\n').to_string(), "'\\n'");
+        // A quote that doesn't start a character is prose, unless that's being refused
+        let strict = LexerOptions { keep_comments: false, strict: true };
+        assert!(matches!(Lexer::with_options("[4]\n['AB']".into(), strict).lex(), Err(LexError::BadCharacter(_))));
+        assert!(matches!(Lexer::with_options("[4]\n['']".into(), strict).lex(), Err(LexError::BadCharacter(_))));
+        let tokens = Lexer::new("[4]\n+ it's fine, isn't it .".into()).lex().unwrap().tokens;
+        let kinds: Vec<TokenKind> = tokens.into_iter().map(|tok| tok.kind).collect();
+        assert_eq!(kinds, vec![TokenKind::Table(Header::new(4)), TokenKind::Inc, TokenKind::Print]);
+    }
+
+    #[test]
+    fn test_table_options() {
+        use crate::program::{CellWidth, TapePolicy, Version};
+
+        let tokens = Lexer::new("[16, version=1 ,cells=byte,tape=wrap]\n+".into()).lex().unwrap().tokens;
+        let header = Header { size: 16, version: Version::V1, width: CellWidth::Byte, tape: TapePolicy::Wrap };
+        assert_eq!(tokens[0].kind, TokenKind::Table(header));
+        assert_eq!(header.to_string(), "16, version=1, cells=byte, tape=wrap");
+
+        let err = Lexer::new("[16, tape=loop]".into()).lex().err().unwrap();
+        assert!(matches!(err, LexError::BadTableOption(ref option, _, _) if option == "tape=loop"));
+        assert!(matches!(Lexer::new("[16, speed=11]".into()).lex(), Err(LexError::BadTableOption(..))));
+        assert!(matches!(Lexer::new("[16 version=1]".into()).lex(), Err(LexError::ExpectedToken(..))));
+    }
+
+    #[test]
+    fn test_version_one_ignores_newer_characters() {
+        let tokens = Lexer::new("[4, version=1]\n# 'x' ! | ` \"lib.mini\" \\m -1 $2 ^(name)".into()).lex().unwrap().tokens;
+
+        // Only the "." of the include is left, since it was always an instruction
+        let kinds: Vec<TokenKind> = tokens.into_iter().skip(1).map(|tok| tok.kind).collect();
+        assert_eq!(kinds, vec![
+            TokenKind::Print,
+            TokenKind::Dec, TokenKind::Number(1), TokenKind::Save(0), TokenKind::Number(2),
+            TokenKind::FuncCall, TokenKind::LParen, TokenKind::RParen,
+        ]);
+    }
+
+    proptest! {
+        #[test]
+        fn test_lex_never_panics(source in "\\PC*", keep_comments: bool, strict: bool) {
+            let _ = Lexer::with_options(source, LexerOptions { keep_comments, strict }).lex();
+        }
+
+        #[test]
+        fn test_spans_lie_in_source(source in "(\\[[0-9]{0,3}\\])?[-+*/<>.{}()\\[\\]?@$&=%:^_;~#|'0-9a-zé \n]*") {
+            let options = LexerOptions { keep_comments: true, strict: false };
+            if let Ok(stream) = Lexer::with_options(source.clone(), options).lex() {
+                for tok in stream.tokens {
+                    prop_assert!(source.get(tok.loc.start..tok.loc.end).is_some());
+                }
+            }
+        }
+    }
+}
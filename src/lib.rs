@@ -0,0 +1,23 @@
+pub mod ast;
+pub mod brainfuck;
+pub mod bytecode;
+pub mod debugger;
+pub mod disasm;
+pub mod dump;
+pub mod generator;
+pub mod include;
+pub mod json;
+pub mod lexer;
+pub mod lsp;
+pub mod macros;
+pub mod parser;
+pub mod preload;
+pub mod printer;
+pub mod program;
+pub mod semantics;
+pub mod snapshot;
+mod symbols;
+pub mod tui;
+pub mod vm;
+
+pub use symbols::SymbolTable;
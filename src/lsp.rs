@@ -0,0 +1,562 @@
+//! A Language Server Protocol server for minlang.
+//!
+//! The server speaks JSON-RPC over any reader/writer pair, so the `minlang2-lsp` binary hands it
+//! stdin and stdout while tests hand it scripted sessions. Documents are fully re-analyzed on
+//! every change; minlang files are small and the lexer is linear, so there is nothing to gain
+//! from incremental updates.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::ast::{Statement, StatementKind};
+use crate::include;
+use crate::lexer::{Lexer, Token, TokenKind, TokenLocation};
+use crate::macros;
+use crate::parser::Parser;
+use crate::printer::{self, Printer};
+use crate::program::Program;
+use crate::semantics::{self, SemanticError};
+use crate::symbols::SymbolTable;
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_REQUEST: i64 = -32600;
+
+const SEVERITY_ERROR: u64 = 1;
+const SYMBOL_FUNCTION: u64 = 12;
+
+/// Read one `Content-Length` framed message, or `None` once the input is exhausted.
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length: Option<usize> = None;
+
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+
+    let Some(length) = length else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Message is missing a Content-Length header"));
+    };
+
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body)?;
+
+    serde_json::from_slice(&body).map(Some).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Write one message with its `Content-Length` header.
+pub fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+/// Converts between byte offsets and LSP positions, which count UTF-16 code units.
+struct LineIndex<'source> {
+    text: &'source str,
+    starts: Vec<usize>,
+}
+
+impl<'source> LineIndex<'source> {
+    fn new(text: &'source str) -> LineIndex<'source> {
+        let mut starts = vec![0];
+        starts.extend(text.match_indices('\n').map(|(idx, _)| idx + 1));
+        LineIndex { text, starts }
+    }
+
+    fn position(&self, offset: usize) -> Value {
+        let offset = offset.min(self.text.len());
+        let line = self.starts.partition_point(|start| *start <= offset) - 1;
+        let character: usize = self.text[self.starts[line]..offset].chars().map(char::len_utf16).sum();
+
+        json!({ "line": line, "character": character })
+    }
+
+    fn range(&self, start: usize, end: usize) -> Value {
+        json!({ "start": self.position(start), "end": self.position(end) })
+    }
+
+    fn offset(&self, position: &Value) -> usize {
+        let line = position["line"].as_u64().unwrap_or(0) as usize;
+        let character = position["character"].as_u64().unwrap_or(0) as usize;
+
+        let Some(start) = self.starts.get(line).copied() else {
+            return self.text.len();
+        };
+
+        let mut units = 0;
+        for (idx, chr) in self.text[start..].char_indices() {
+            if units >= character || chr == '\n' {
+                return start + idx;
+            }
+            units += chr.len_utf16();
+        }
+
+        self.text.len()
+    }
+
+    /// Byte offset of a 1-based line and character column, as the parser reports them.
+    fn offset_of_line_col(&self, line: usize, col: usize) -> usize {
+        let Some(start) = self.starts.get(line.saturating_sub(1)).copied() else {
+            return self.text.len();
+        };
+
+        self.text[start..].char_indices().nth(col.saturating_sub(1)).map(|(idx, _)| start + idx).unwrap_or(self.text.len())
+    }
+}
+
+/// A `:(n)` or `^(n)` found in the token stream, where `n` may be a name.
+struct FunctionRef {
+    id: usize,
+    // The id as written
+    label: String,
+    definition: bool,
+    // Byte range of the whole `^(n)`
+    start: usize,
+    end: usize,
+    // For definitions, the end of the closing `;` if there is one
+    block_end: Option<usize>,
+}
+
+/// The `^(n)`s and `:(n)`s in `tokens`, with names resolved through `symbols`.
+fn function_refs(tokens: &[Token], symbols: &SymbolTable) -> Vec<FunctionRef> {
+    let mut refs = Vec::new();
+
+    for (idx, window) in tokens.windows(4).enumerate() {
+        let definition = match window[0].kind {
+            TokenKind::FuncDef => true,
+            TokenKind::FuncCall => false,
+            _ => continue,
+        };
+        let (TokenKind::LParen, TokenKind::RParen) = (&window[1].kind, &window[3].kind) else {
+            continue;
+        };
+        let id = match &window[2].kind {
+            TokenKind::Number(id) => *id,
+            TokenKind::Ident(name) => match symbols.function(name) {
+                Some(id) => id,
+                None => continue,
+            },
+            _ => continue,
+        };
+
+        let block_end = if definition { block_end(&tokens[idx + 4..]) } else { None };
+        let label = window[2].kind.to_string();
+        refs.push(FunctionRef { id, label, definition, start: window[0].loc.start, end: window[3].loc.end, block_end });
+    }
+
+    refs
+}
+
+/// Where the `;` closing a function body starts at `tokens` ends.
+fn block_end(tokens: &[Token]) -> Option<usize> {
+    macros::block_end(tokens).map(|end| tokens[end - 1].loc.end)
+}
+
+fn find_function(stmts: &[Statement], id: usize) -> Option<&Statement> {
+    for stmt in stmts {
+        let found = match &stmt.kind {
+            StatementKind::DefineFn(def_id, ..) if *def_id == id => Some(stmt),
+            StatementKind::DefineFn(_, _, body) | StatementKind::Loop(body, _) | StatementKind::If(_, body, None) => find_function(body, id),
+            StatementKind::If(_, body, Some(else_body)) => find_function(body, id).or_else(|| find_function(else_body, id)),
+            _ => None,
+        };
+        if found.is_some() {
+            return found;
+        }
+    }
+
+    None
+}
+
+/// What an operator does, for hovers.
+fn describe(kind: &TokenKind) -> Option<&'static str> {
+    let text = match kind {
+        TokenKind::Table(_) => "`[n]` on the first line allocates a table of `n` cells. Options can follow the size: `version=1` for the original language, `cells=byte` for cells that wrap around at 256, and `tape=wrap` for a cursor that wraps around the ends of the table.",
+        TokenKind::Inc => "`+` adds one to the current cell.",
+        TokenKind::Dec => "`-` subtracts one from the current cell.",
+        TokenKind::Mul => "`*` doubles the current cell.",
+        TokenKind::Div => "`/` halves the current cell, rounding towards zero.",
+        TokenKind::MovR => "`>` moves the cursor one cell to the right.",
+        TokenKind::MovL => "`<` moves the cursor one cell to the left.",
+        TokenKind::Print => "`.` prints the current cell as a number.",
+        TokenKind::StartLoop | TokenKind::EndLoop => "`{...}(p)` runs its body until the current cell equals `p`, or zero if no `(p)` is given. Put `!`, `<`, `<=`, `>` or `>=` before `p` to compare differently.",
+        TokenKind::StartDefine | TokenKind::EndDefine => "`[n]` sets the current cell to `n`, which can be negative or a character like `'A'`.",
+        TokenKind::Question => "`?(p)...:...;` runs its body if the current cell equals `p`, and the part after the optional `:` if it doesn't. Put `!`, `<`, `<=`, `>` or `>=` before `p` to compare differently.",
+        TokenKind::Goto => "`@(p)` moves the cursor to cell `p`.",
+        TokenKind::Save(_) => "`$` saves the cursor's position, and `$1` to `$15` save it in other registers. As a parameter, `$n` means the cell register `n` points at, cell 0 until something is saved there.",
+        TokenKind::PrintAscii => "`&` prints the current cell as an ASCII character.",
+        TokenKind::CopyTo => "`=(p)` copies cell `p` into the current cell.",
+        TokenKind::Modulo => "`%` replaces the current cell with its remainder modulo two.",
+        TokenKind::FuncDef => "`:(n)...;` defines function `n`. `:(n)(k)...;` gives it a frame: a copy of the caller's cell and `k` locals.",
+        TokenKind::FuncCall => "`^(n)` calls function `n`. A `^` on its own returns from the function it's in.",
+        TokenKind::Newline => "`_` prints a newline.",
+        TokenKind::Eos => "`;` ends a function definition or an if.",
+        TokenKind::Tilde => "`~` flips the sign of the current cell.",
+        TokenKind::Pipe => "`|` leaves the loop it's in.",
+        TokenKind::Backtick => "`` ` `` skips to the next check of the loop it's in.",
+        TokenKind::Bang => "`!` reads a byte of input into the current cell, or zero at the end of input.",
+        TokenKind::Str(_) => "`\"path\"` pastes in the file at `path`, looked for next to this file and then in the include paths.",
+        TokenKind::Macro(_) => "`\\name(...)` pastes in the body of the macro `name`, defined with `:\\name(\\a, \\b) ... ;`.",
+        _ => return None,
+    };
+
+    Some(text)
+}
+
+/// The byte range in the open document that a location stands for: the location itself, or
+/// whichever macro call or include brought it in from another file.
+fn local_span(mut loc: &TokenLocation) -> (usize, usize) {
+    while let Some(file) = &loc.file {
+        loc = match &loc.expansion {
+            Some(call) => call,
+            None => &file.included_at,
+        };
+    }
+
+    (loc.start, loc.end.max(loc.start + 1))
+}
+
+/// Everything we know about one open document.
+struct Document {
+    text: String,
+    tokens: Vec<Token>,
+    // Function and cell names, including those from included files
+    symbols: SymbolTable,
+    program: Option<Program>,
+    diagnostics: Vec<Value>,
+}
+
+impl Document {
+    /// Analyze `text`, looking for included files next to `path` and then in `search_paths`.
+    fn analyze(text: String, path: Option<PathBuf>, search_paths: &[PathBuf]) -> Document {
+        let index = LineIndex::new(&text);
+        let mut diagnostics = Vec::new();
+        let diagnostic = |start: usize, end: usize, message: String| {
+            json!({ "range": index.range(start, end), "severity": SEVERITY_ERROR, "source": "minlang2", "message": message })
+        };
+
+        let tokens = match Lexer::new(text.clone()).lex() {
+            Ok(stream) => stream.tokens,
+            Err(err) => {
+                let loc = err.location();
+                diagnostics.push(diagnostic(loc.start, loc.end.max(loc.start + 1), err.to_string()));
+                Vec::new()
+            },
+        };
+
+        let mut symbols = SymbolTable::collect(&tokens);
+        let program = if tokens.is_empty() {
+            None
+        } else {
+            let parsed = include::include(tokens.clone(), path.as_deref(), search_paths)
+                .map_err(|err| (local_span(err.location()), err.to_string()))
+                .and_then(|included| {
+                    symbols = SymbolTable::collect(&included);
+                    macros::expand(included).map_err(|err| (local_span(&err.token().loc), err.to_string()))
+                })
+                .and_then(|expanded| Parser::new(expanded).parse_program().map_err(|err| {
+                    let span = match err.token() {
+                        Some(token) => local_span(&token.loc),
+                        None => {
+                            let (line, col) = err.location();
+                            let start = index.offset_of_line_col(line, col);
+                            (start, start + 1)
+                        },
+                    };
+                    (span, err.to_string())
+                }));
+
+            match parsed {
+                Ok(program) => Some(program),
+                Err(((start, end), message)) => {
+                    diagnostics.push(diagnostic(start, end, message));
+                    None
+                },
+            }
+        };
+
+        if let Some(program) = &program {
+            // Undefined calls are reported below, every one of them and over all of `^(n)`
+            if let Err(err) = semantics::check(program).or_else(|err| match err {
+                SemanticError::UndefinedFunction(..) => Ok(()),
+                err => Err(err),
+            }) {
+                let (start, end) = local_span(&err.token().loc);
+                diagnostics.push(diagnostic(start, end, err.to_string()));
+            }
+
+            let refs = function_refs(&tokens, &symbols);
+            for call in refs.iter().filter(|call| !call.definition) {
+                // Functions from included files are only in the program
+                let defined = refs.iter().any(|def| def.definition && def.id == call.id) || program.functions.contains_key(&call.id);
+                if !defined {
+                    diagnostics.push(diagnostic(call.start, call.end, format!("Function {} is never defined.", call.id)));
+                }
+            }
+        }
+
+        Document { text, tokens, symbols, program, diagnostics }
+    }
+
+    fn function_refs(&self) -> Vec<FunctionRef> {
+        function_refs(&self.tokens, &self.symbols)
+    }
+
+    /// Index of the token under `offset`, if any.
+    fn token_at(&self, offset: usize) -> Option<usize> {
+        self.tokens.iter().position(|tok| tok.loc.start <= offset && offset < tok.loc.end)
+    }
+
+    /// The operator a token belongs to, so hovering a parameter describes the statement it's in.
+    fn head_of(&self, idx: usize) -> usize {
+        let kind_at = |idx: usize| self.tokens.get(idx).map(|tok| &tok.kind);
+
+        match kind_at(idx) {
+            Some(TokenKind::LParen) => idx.saturating_sub(1),
+            Some(TokenKind::Number(_) | TokenKind::Negative(_) | TokenKind::Char(_) | TokenKind::Ident(_) | TokenKind::Save(_)) if idx >= 2 && kind_at(idx - 1) == Some(&TokenKind::LParen) => idx - 2,
+            Some(TokenKind::RParen) => idx.saturating_sub(3),
+            _ => idx,
+        }
+    }
+
+    fn function_ref_at(&self, offset: usize) -> Option<FunctionRef> {
+        self.function_refs().into_iter().find(|call| call.start <= offset && offset < call.end)
+    }
+
+    fn hover(&self, offset: usize) -> Option<String> {
+        if let Some(func) = self.function_ref_at(offset) {
+            let refs = self.function_refs();
+            if func.definition {
+                let calls = refs.iter().filter(|call| !call.definition && call.id == func.id).count();
+                return Some(format!("`:({})` defines function {}, called {} time(s).", func.label, func.id, calls));
+            }
+
+            let mut text = format!("`^({})` calls function {}.", func.label, func.id);
+            let body = self.program.as_ref().and_then(|program| find_function(&program.statements, func.id));
+            match body {
+                Some(def) => {
+                    let printed = Printer::new().print_statements(std::slice::from_ref(def));
+                    let file = def.token.loc.file.as_ref().map_or(String::new(), |file| format!(" of {}", file.path.display()));
+                    text.push_str(&format!("\n\nDefined on line {}{}:\n```minlang\n{}```", def.token.loc.line, file, printed));
+                },
+                None => text.push_str(&format!("\n\nFunction {} is never defined.", func.id)),
+            }
+            return Some(text);
+        }
+
+        let head = self.head_of(self.token_at(offset)?);
+        describe(&self.tokens.get(head)?.kind).map(String::from)
+    }
+}
+
+/// The server state: open documents, where to look for included files, and whether the client
+/// has asked us to shut down.
+pub struct Server {
+    documents: HashMap<String, Document>,
+    include_paths: Vec<PathBuf>,
+    shutting_down: bool,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server { documents: HashMap::new(), include_paths: Vec::new(), shutting_down: false }
+    }
+
+    /// Handle messages until the client sends `exit` or closes the input.
+    pub fn serve(&mut self, mut reader: impl BufRead, mut writer: impl Write) -> io::Result<()> {
+        while let Some(message) = read_message(&mut reader)? {
+            if message["method"] == "exit" {
+                break;
+            }
+
+            for reply in self.handle(&message) {
+                write_message(&mut writer, &reply)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle one message, returning the responses and notifications to send back.
+    pub(crate) fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let id = message.get("id").cloned();
+
+        let result = match method {
+            "initialize" => {
+                // The editor can pass what `-I` would be on the command line
+                if let Some(paths) = params["initializationOptions"]["includePaths"].as_array() {
+                    self.include_paths = paths.iter().filter_map(Value::as_str).map(PathBuf::from).collect();
+                }
+                Ok(json!({
+                    "capabilities": {
+                        "textDocumentSync": 1,
+                        "hoverProvider": true,
+                        "definitionProvider": true,
+                        "referencesProvider": true,
+                        "documentSymbolProvider": true,
+                        "documentFormattingProvider": true,
+                    },
+                    "serverInfo": { "name": "minlang2-lsp", "version": env!("CARGO_PKG_VERSION") },
+                }))
+            },
+            "shutdown" => {
+                self.shutting_down = true;
+                Ok(Value::Null)
+            },
+            _ if self.shutting_down && id.is_some() => Err((INVALID_REQUEST, "The server is shutting down".to_string())),
+            "textDocument/didOpen" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+                let text = params["textDocument"]["text"].as_str().unwrap_or("").to_string();
+                return self.update(uri, text);
+            },
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+                // We only ask for full syncs, so the last change holds the whole text
+                let Some(text) = params["contentChanges"].as_array().and_then(|changes| changes.last()).and_then(|change| change["text"].as_str()) else {
+                    return Vec::new();
+                };
+                return self.update(uri, text.to_string());
+            },
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+                self.documents.remove(uri);
+                return vec![publish_diagnostics(uri, Vec::new())];
+            },
+            "textDocument/hover" => Ok(self.hover(params)),
+            "textDocument/definition" => Ok(self.definition(params)),
+            "textDocument/references" => Ok(self.references(params)),
+            "textDocument/documentSymbol" => Ok(self.symbols(params)),
+            "textDocument/formatting" => Ok(self.format(params)),
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method '{}'", method))),
+        };
+
+        // Notifications never get a response, even an error
+        let Some(id) = id else {
+            return Vec::new();
+        };
+
+        match result {
+            Ok(result) => vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })],
+            Err((code, message)) => vec![json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })],
+        }
+    }
+
+    fn update(&mut self, uri: String, text: String) -> Vec<Value> {
+        // Includes can only be found for documents that are files
+        let path = Url::parse(&uri).ok().and_then(|url| url.to_file_path().ok());
+        let document = Document::analyze(text, path, &self.include_paths);
+        let notification = publish_diagnostics(&uri, document.diagnostics.clone());
+        self.documents.insert(uri, document);
+
+        vec![notification]
+    }
+
+    /// The document and byte offset a `TextDocumentPositionParams` points at.
+    fn locate<'a>(&self, params: &'a Value) -> Option<(&'a str, &Document, usize)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let document = self.documents.get(uri)?;
+        let offset = LineIndex::new(&document.text).offset(&params["position"]);
+
+        Some((uri, document, offset))
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let Some((_, document, offset)) = self.locate(params) else { return Value::Null; };
+
+        match document.hover(offset) {
+            Some(text) => json!({ "contents": { "kind": "markdown", "value": text } }),
+            None => Value::Null,
+        }
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let Some((uri, document, offset)) = self.locate(params) else { return Value::Null; };
+        let Some(func) = document.function_ref_at(offset) else { return Value::Null; };
+
+        let index = LineIndex::new(&document.text);
+        document.function_refs()
+            .into_iter()
+            .find(|def| def.definition && def.id == func.id)
+            .map(|def| json!({ "uri": uri, "range": index.range(def.start, def.end) }))
+            .unwrap_or(Value::Null)
+    }
+
+    fn references(&self, params: &Value) -> Value {
+        let Some((uri, document, offset)) = self.locate(params) else { return Value::Null; };
+        let Some(func) = document.function_ref_at(offset) else { return Value::Null; };
+        let include_declaration = params["context"]["includeDeclaration"].as_bool().unwrap_or(true);
+
+        let index = LineIndex::new(&document.text);
+        let locations: Vec<Value> = document.function_refs()
+            .into_iter()
+            .filter(|other| other.id == func.id && (include_declaration || !other.definition))
+            .map(|other| json!({ "uri": uri, "range": index.range(other.start, other.end) }))
+            .collect();
+
+        Value::Array(locations)
+    }
+
+    fn symbols(&self, params: &Value) -> Value {
+        let Some(document) = params["textDocument"]["uri"].as_str().and_then(|uri| self.documents.get(uri)) else {
+            return Value::Null;
+        };
+
+        let index = LineIndex::new(&document.text);
+        let symbols: Vec<Value> = document.function_refs()
+            .into_iter()
+            .filter(|def| def.definition)
+            .map(|def| json!({
+                "name": format!("function {}", def.label),
+                "kind": SYMBOL_FUNCTION,
+                "range": index.range(def.start, def.block_end.unwrap_or(def.end)),
+                "selectionRange": index.range(def.start, def.end),
+            }))
+            .collect();
+
+        Value::Array(symbols)
+    }
+
+    fn format(&self, params: &Value) -> Value {
+        let Some(document) = params["textDocument"]["uri"].as_str().and_then(|uri| self.documents.get(uri)) else {
+            return Value::Null;
+        };
+
+        let Ok(formatted) = printer::format_source(&document.text) else { return Value::Null; };
+        if formatted == document.text {
+            return json!([]);
+        }
+
+        let index = LineIndex::new(&document.text);
+        json!([{ "range": index.range(0, document.text.len()), "newText": formatted }])
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
@@ -0,0 +1,403 @@
+//! Macros, expanded over the token stream between lexing and parsing.
+//!
+//! `:\name(\a, \b) body ;` defines a macro, anywhere in the program, and `\name(x, y)` pastes
+//! its body in with every `\a` and `\b` replaced by the tokens passed for them. A macro without
+//! parameters can be called without the parentheses. Bodies can call other macros, but a macro
+//! can't be defined inside another.
+//!
+//! Expansion is hygienic for functions: a function defined in a macro body gets a fresh id each
+//! time the macro is expanded, so two expansions, or an expansion and the rest of the program,
+//! can't clobber each other's functions. Calls to functions the body doesn't define are left
+//! alone. Cells aren't renamed; they're the program's memory, and sharing them is the point.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use crate::lexer::{Token, TokenKind};
+
+/// How deeply macro calls may nest, which also stops a macro that calls itself.
+pub(crate) const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// How many tokens expansion may produce, so a few macros that each call the last one twice
+/// can't exhaust memory.
+pub(crate) const MAX_EXPANDED_TOKENS: usize = 1 << 20;
+
+#[derive(Debug)]
+pub enum MacroError {
+    UndefinedMacro(Token),
+    DuplicateMacro(Token),
+    NestedDefinition(Token),
+    ExpectedParameter(Token),
+    UnterminatedDefinition(Token),
+    UnterminatedCall(Token),
+    WrongArgumentCount(Token, usize, usize),
+    ExpandedTooDeeply(Token),
+    ExpandedTooLarge(Token),
+}
+
+impl MacroError {
+    pub(crate) fn token(&self) -> &Token {
+        match self {
+            MacroError::UndefinedMacro(token)
+            | MacroError::DuplicateMacro(token)
+            | MacroError::NestedDefinition(token)
+            | MacroError::ExpectedParameter(token)
+            | MacroError::UnterminatedDefinition(token)
+            | MacroError::UnterminatedCall(token)
+            | MacroError::WrongArgumentCount(token, _, _)
+            | MacroError::ExpandedTooDeeply(token)
+            | MacroError::ExpandedTooLarge(token) => token,
+        }
+    }
+
+    /// The line and column the error was found at.
+    pub fn location(&self) -> (usize, usize) {
+        (self.token().loc.line, self.token().loc.col)
+    }
+}
+
+impl Display for MacroError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let token = self.token();
+        match self {
+            MacroError::UndefinedMacro(_) => {
+                f.write_fmt(format_args!("No macro named '{}' at line {} col {}.", token.kind, token.loc.line, token.loc.col))
+            },
+            MacroError::DuplicateMacro(_) => {
+                f.write_fmt(format_args!("Macro '{}' is defined again at line {} col {}.", token.kind, token.loc.line, token.loc.col))
+            },
+            MacroError::NestedDefinition(_) => {
+                f.write_fmt(format_args!("Macro '{}' is defined inside another macro at line {} col {}.", token.kind, token.loc.line, token.loc.col))
+            },
+            MacroError::ExpectedParameter(_) => {
+                f.write_fmt(format_args!("Expected a parameter like '\\name' at line {} col {}, got '{}'.", token.loc.line, token.loc.col, token.kind))
+            },
+            MacroError::UnterminatedDefinition(_) => {
+                f.write_fmt(format_args!("Macro '{}' defined at line {} col {} is missing its closing ';'.", token.kind, token.loc.line, token.loc.col))
+            },
+            MacroError::UnterminatedCall(_) => {
+                f.write_fmt(format_args!("Call to '{}' at line {} col {} is missing its closing ')'.", token.kind, token.loc.line, token.loc.col))
+            },
+            MacroError::WrongArgumentCount(_, expected, found) => {
+                f.write_fmt(format_args!("Macro '{}' takes {} argument(s), but was given {} at line {} col {}.", token.kind, expected, found, token.loc.line, token.loc.col))
+            },
+            MacroError::ExpandedTooDeeply(_) => {
+                f.write_fmt(format_args!("Macro calls nested more than {} deep at line {} col {}; does '{}' call itself?", MAX_EXPANSION_DEPTH, token.loc.line, token.loc.col, token.kind))
+            },
+            MacroError::ExpandedTooLarge(_) => {
+                f.write_fmt(format_args!("Expanding '{}' at line {} col {} makes more than {} tokens.", token.kind, token.loc.line, token.loc.col, MAX_EXPANDED_TOKENS))
+            },
+        }?;
+
+        f.write_str(&token.loc.origin_note())
+    }
+}
+
+type MacroResult<T> = Result<T, MacroError>;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// Index just past the `;` closing a block that starts at `tokens[0]`, counting the `?` and `:`
+/// blocks inside it.
+pub(crate) fn block_end(tokens: &[Token]) -> Option<usize> {
+    let mut depth = 1usize;
+    for (idx, tok) in tokens.iter().enumerate() {
+        match tok.kind {
+            TokenKind::Question => depth += 1,
+            // Any other ":" is an if's else, which shares the if's ";"
+            TokenKind::FuncDef if starts_definition(tokens, idx) => depth += 1,
+            TokenKind::Eos => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(idx + 1);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    None
+}
+
+/// Whether the `:` at `tokens[idx]` defines a function or macro, rather than starting an else.
+fn starts_definition(tokens: &[Token], idx: usize) -> bool {
+    matches!(tokens.get(idx + 1).map(|tok| &tok.kind), Some(TokenKind::LParen | TokenKind::Macro(_)))
+}
+
+/// Index just past the `)` matching the `(` at `tokens[0]`, with the comma-separated arguments
+/// in between.
+fn arguments(tokens: &[Token]) -> Option<(usize, Vec<Vec<Token>>)> {
+    let mut args = vec![Vec::new()];
+    let mut depth = 0usize;
+
+    for (idx, tok) in tokens.iter().enumerate() {
+        match tok.kind {
+            TokenKind::LParen => {
+                depth += 1;
+                if depth == 1 {
+                    continue;
+                }
+            },
+            TokenKind::RParen => {
+                depth -= 1;
+                if depth == 0 {
+                    // `()` passes nothing, rather than one empty argument
+                    if args.len() == 1 && args[0].is_empty() {
+                        args.clear();
+                    }
+                    return Some((idx + 1, args));
+                }
+            },
+            TokenKind::Comma if depth == 1 => {
+                args.push(Vec::new());
+                continue;
+            },
+            _ => {},
+        }
+        args.last_mut().unwrap().push(tok.clone());
+    }
+
+    None
+}
+
+/// Pull macro definitions out of the token stream, returning them and everything else.
+fn definitions(tokens: Vec<Token>) -> MacroResult<(HashMap<String, Macro>, Vec<Token>)> {
+    let mut macros = HashMap::new();
+    let mut rest = Vec::new();
+    let mut idx = 0;
+
+    while idx < tokens.len() {
+        let (TokenKind::FuncDef, Some(TokenKind::Macro(name))) = (&tokens[idx].kind, tokens.get(idx + 1).map(|tok| &tok.kind)) else {
+            rest.push(tokens[idx].clone());
+            idx += 1;
+            continue;
+        };
+        let name_token = &tokens[idx + 1];
+        idx += 2;
+
+        let (after, params) = match tokens.get(idx).map(|tok| &tok.kind) {
+            Some(TokenKind::LParen) => arguments(&tokens[idx..]).ok_or_else(|| MacroError::UnterminatedCall(name_token.clone()))?,
+            _ => (0, Vec::new()),
+        };
+        idx += after;
+
+        let params = params.into_iter().map(|param| match param.as_slice() {
+            [Token { kind: TokenKind::Macro(param), .. }] => Ok(param.clone()),
+            [tok, ..] => Err(MacroError::ExpectedParameter(tok.clone())),
+            [] => Err(MacroError::ExpectedParameter(name_token.clone())),
+        }).collect::<MacroResult<Vec<String>>>()?;
+
+        let len = block_end(&tokens[idx..]).ok_or_else(|| MacroError::UnterminatedDefinition(name_token.clone()))?;
+        // Leave off the closing `;`, and comments, which would only land out of place
+        let body: Vec<Token> = tokens[idx..idx + len - 1].iter().filter(|tok| !matches!(tok.kind, TokenKind::Comment(_))).cloned().collect();
+        idx += len;
+
+        if let Some(nested) = body.windows(2).find(|pair| pair[0].kind == TokenKind::FuncDef && matches!(pair[1].kind, TokenKind::Macro(_))) {
+            return Err(MacroError::NestedDefinition(nested[1].clone()));
+        }
+        if macros.insert(name.clone(), Macro { params, body }).is_some() {
+            return Err(MacroError::DuplicateMacro(name_token.clone()));
+        }
+    }
+
+    Ok((macros, rest))
+}
+
+struct Expander {
+    macros: HashMap<String, Macro>,
+    // Next function id no one has used, for renaming functions defined in macro bodies
+    next_id: usize,
+    expanded: usize,
+}
+
+impl Expander {
+    /// A copy of a macro's body for one call: functions it defines get fresh ids, parameters
+    /// are replaced by their arguments, and every token from the body remembers the call.
+    fn instantiate(&mut self, name: &str, call: &Token, args: &[Vec<Token>]) -> Vec<Token> {
+        let body = &self.macros[name].body;
+        let params = &self.macros[name].params;
+
+        // Each function id the body defines, and what it becomes in this copy
+        let mut renamed: Vec<(TokenKind, usize)> = Vec::new();
+        for window in body.windows(3) {
+            if let [Token { kind: TokenKind::FuncDef, .. }, Token { kind: TokenKind::LParen, .. }, id] = window {
+                if matches!(id.kind, TokenKind::Number(_) | TokenKind::Ident(_)) && !renamed.iter().any(|(kind, _)| *kind == id.kind) {
+                    renamed.push((id.kind.clone(), self.next_id));
+                    self.next_id += 1;
+                }
+            }
+        }
+
+        let mut out = Vec::with_capacity(body.len());
+        for (idx, tok) in body.iter().enumerate() {
+            let is_function_id = idx >= 2
+                && matches!(body[idx - 2].kind, TokenKind::FuncDef | TokenKind::FuncCall)
+                && body[idx - 1].kind == TokenKind::LParen;
+
+            match &tok.kind {
+                TokenKind::Macro(param) if params.contains(param) => {
+                    // Arguments were written at the call site, so they keep their own locations
+                    let arg = params.iter().position(|name| name == param).unwrap();
+                    out.extend(args[arg].iter().cloned());
+                },
+                kind if is_function_id && renamed.iter().any(|(from, _)| from == kind) => {
+                    let (_, id) = renamed.iter().find(|(from, _)| from == kind).unwrap();
+                    out.push(Token::new(TokenKind::Number(*id), tok.loc.expanded_at(&call.loc)));
+                },
+                _ => out.push(Token::new(tok.kind.clone(), tok.loc.expanded_at(&call.loc))),
+            }
+        }
+
+        out
+    }
+
+    fn expand_into(&mut self, tokens: &[Token], out: &mut Vec<Token>, depth: usize) -> MacroResult<()> {
+        let mut idx = 0;
+
+        while idx < tokens.len() {
+            let call = &tokens[idx];
+            let TokenKind::Macro(name) = &call.kind else {
+                out.push(call.clone());
+                idx += 1;
+                continue;
+            };
+            idx += 1;
+
+            let Some(expected) = self.macros.get(name).map(|mac| mac.params.len()) else {
+                return Err(MacroError::UndefinedMacro(call.clone()));
+            };
+
+            let args = match tokens.get(idx).map(|tok| &tok.kind) {
+                Some(TokenKind::LParen) => {
+                    let (after, args) = arguments(&tokens[idx..]).ok_or_else(|| MacroError::UnterminatedCall(call.clone()))?;
+                    idx += after;
+                    args
+                },
+                _ => Vec::new(),
+            };
+            if args.len() != expected {
+                return Err(MacroError::WrongArgumentCount(call.clone(), expected, args.len()));
+            }
+            if depth >= MAX_EXPANSION_DEPTH {
+                return Err(MacroError::ExpandedTooDeeply(call.clone()));
+            }
+
+            let body = self.instantiate(name, call, &args);
+            self.expanded += body.len();
+            if self.expanded > MAX_EXPANDED_TOKENS {
+                return Err(MacroError::ExpandedTooLarge(call.clone()));
+            }
+
+            // The body may call macros of its own
+            self.expand_into(&body, out, depth + 1)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Expand every macro call in a token stream, removing the definitions.
+pub fn expand(tokens: Vec<Token>) -> MacroResult<Vec<Token>> {
+    // Fresh function ids start past every id used by number, in macros or out
+    let next_id = tokens.windows(3).filter_map(|window| match (&window[0].kind, &window[1].kind, &window[2].kind) {
+        (TokenKind::FuncDef | TokenKind::FuncCall, TokenKind::LParen, TokenKind::Number(id)) => id.checked_add(1),
+        _ => None,
+    }).max().unwrap_or(0);
+
+    let (macros, rest) = definitions(tokens)?;
+    if macros.is_empty() && !rest.iter().any(|tok| matches!(tok.kind, TokenKind::Macro(_))) {
+        return Ok(rest);
+    }
+
+    let mut expander = Expander { macros, next_id, expanded: 0 };
+    let mut out = Vec::with_capacity(rest.len());
+    expander.expand_into(&rest, &mut out, 0)?;
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::{Lexer, Token, TokenKind};
+    use crate::parser::Parser;
+    use crate::program::Program;
+
+    use super::{expand, MacroError};
+
+    fn expand_source(source: &str) -> Result<Vec<Token>, MacroError> {
+        expand(Lexer::new(source.into()).lex().unwrap().tokens)
+    }
+
+    fn expands_to(source: &str, expected: &str) {
+        let tokens = expand_source(source).unwrap();
+        let program = Parser::new(tokens).parse_program().unwrap();
+
+        assert_eq!(program.statements, Program::parse_str(expected).statements);
+    }
+
+    #[test]
+    fn test_expand_with_arguments() {
+        expands_to("[8]\n:\\times(\\n, \\step) [\\n]{\\step-}; \\times(3, >+<) \\times(2, _)", "[8]\n[3]{>+<-}[2]{_-}");
+    }
+
+    #[test]
+    fn test_expand_without_parentheses() {
+        expands_to("[8]\n\\clear :\\clear [0];", "[8]\n[0]");
+    }
+
+    #[test]
+    fn test_nested_calls() {
+        expands_to("[8]\n:\\twice(\\x) \\x\\x; :\\four(\\x) \\twice(\\twice(\\x)); \\four(+)", "[8]\n++++");
+    }
+
+    #[test]
+    fn test_functions_are_hygienic() {
+        let source = "[8]\n:(0).; :(helper)_; :\\show :(0)&; :(helper)&; ^(0)^(helper)^(1); \\show \\show ^(0)";
+        // The body's own :(0) and :(helper) are renamed per call, but its ^(1) is left alone
+        expands_to(source, "[8]\n:(0).; :(helper)_; :(2)&; :(3)&; ^(2)^(3)^(1) :(4)&; :(5)&; ^(4)^(5)^(1) ^(0)");
+    }
+
+    #[test]
+    fn test_expansion_locations() {
+        let tokens = expand_source("[8]\n:\\bad ] ;\n+\\bad").unwrap();
+        let bracket = tokens.iter().find(|tok| tok.kind == TokenKind::EndDefine).unwrap();
+
+        // The token lives in the body, and remembers the call
+        assert_eq!((bracket.loc.line, bracket.loc.col), (2, 7));
+        let call = bracket.loc.expansion.as_ref().unwrap();
+        assert_eq!((call.line, call.col), (3, 2));
+
+        let err = Parser::new(tokens).parse_program().err().unwrap();
+        assert!(err.to_string().ends_with("at line 2 col 7. Expanded from the macro call at line 3 col 2."));
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        assert!(matches!(expand_source("[8]\n\\nope"), Err(MacroError::UndefinedMacro(_))));
+        assert!(matches!(expand_source("[8]\n:\\a; :\\a;"), Err(MacroError::DuplicateMacro(_))));
+        assert!(matches!(expand_source("[8]\n:\\a :\\b; ;"), Err(MacroError::NestedDefinition(_))));
+        assert!(matches!(expand_source("[8]\n:\\a(x) ;"), Err(MacroError::ExpectedParameter(_))));
+        assert!(matches!(expand_source("[8]\n:\\a +"), Err(MacroError::UnterminatedDefinition(_))));
+        assert!(matches!(expand_source("[8]\n:\\a(\\x) \\x; \\a(+"), Err(MacroError::UnterminatedCall(_))));
+        assert!(matches!(expand_source("[8]\n:\\a(\\x) \\x; \\a(+, -)"), Err(MacroError::WrongArgumentCount(_, 1, 2))));
+        assert!(matches!(expand_source("[8]\n:\\a \\a; \\a"), Err(MacroError::ExpandedTooDeeply(_))));
+
+        let err = expand_source("[8]\n:\\a \\b; :\\b \\nope; \\a").err().unwrap();
+        assert_eq!(err.to_string(), "No macro named '\\nope' at line 2 col 13. Expanded from the macro call at line 2 col 5, called from line 2 col 20.");
+    }
+
+    #[test]
+    fn test_expansion_size_limit() {
+        // Each level doubles the one below it
+        let mut source = String::from("[8]\n:\\m0 +;");
+        for level in 1..=24 {
+            source.push_str(&format!(" :\\m{} \\m{}\\m{};", level, level - 1, level - 1));
+        }
+        source.push_str(" \\m24");
+
+        assert!(matches!(expand_source(&source), Err(MacroError::ExpandedTooLarge(_))));
+    }
+}
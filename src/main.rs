@@ -1,85 +1,867 @@
-mod lexer;
-mod ast;
-mod parser;
-mod program;
-mod vm;
-
-use clap::{Parser};
+use clap::{Parser, ValueEnum};
+use std::fmt::Display;
 use std::fs;
-use std::path::Path;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+
+use minlang2::{brainfuck, bytecode, disasm, dump, generator, include, json, lexer, macros, parser, printer, semantics};
+use minlang2::debugger::Debugger;
+use minlang2::ast::StatementKind;
+use minlang2::lexer::{LexerOptions, Token, TokenKind};
+use minlang2::parser::ParseError;
+use minlang2::preload::Preload;
+use minlang2::snapshot::Snapshot;
+use minlang2::tui::{self, Tui};
+use minlang2::printer::{FormatError, Printer};
+use minlang2::program::{CellWidth, Program, Table};
+use minlang2::vm::Vm;
 
-use crate::vm::Vm;
+/// Something is wrong with the program or a file given for it, or `fmt --check` found files
+/// that need formatting.
+const EXIT_INVALID: i32 = 1;
+/// A file couldn't be read or written.
+const EXIT_IO: i32 = 3;
+/// The program went wrong while running, like going to a cell past the end of the table.
+const EXIT_RUNTIME: i32 = 4;
+// The command line not making sense is 2, which clap exits with itself
 
 #[derive(clap::Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
-    /// Source file path
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// Where a program comes from and how to read it.
+#[derive(clap::Args, Debug)]
+struct SourceArgs {
+    /// Source file path, or - to read it from stdin
     source_path: String,
-    /// Whether to stop at the lexing phase
-    #[arg(short, long)]
-    lex: bool,
-    /// Whether to stop at the parsing phase
-    #[arg(short, long)]
-    parse: bool,
-    /// Whether to run the program in a VM
-    #[arg(short, long)]
-    run: bool,
-    /// Whether to verbosely print information from the ending phase
+    /// Read the source as a program in this format instead of as minlang
+    #[arg(long, value_enum)]
+    from: Option<Format>,
+    /// Reject characters outside of comments that are not instructions
+    #[arg(long)]
+    strict: bool,
+    /// Also look for included files in this directory (can be repeated)
+    #[arg(short = 'I', long = "include-path")]
+    include_paths: Vec<String>,
+}
+
+/// The table a program starts with, if not the one its header asks for.
+#[derive(clap::Args, Debug)]
+struct TableArgs {
+    /// Make table cells bytes that wrap around, like brainfuck's, whatever the header says
+    #[arg(long)]
+    byte_cells: bool,
+    /// Start from the table in this JSON or CSV file, in the forms --dump-table writes
+    #[arg(long, value_name = "PATH")]
+    init: Option<String>,
+    /// Set a cell, the cursor, a save register or the table size before running, as in 3=42,
+    /// cursor=2, $1=5 or size=100 (can be repeated)
+    #[arg(long, value_name = "ASSIGNMENT")]
+    set: Vec<String>,
+}
+
+/// What snapshots keep of the command line, to load their program again.
+#[derive(clap::Parser, Debug)]
+struct Origin {
+    #[command(flatten)]
+    source: SourceArgs,
+    #[command(flatten)]
+    table: TableArgs,
+}
+
+/// How to report the table once the program finishes.
+#[derive(clap::Args, Debug)]
+struct DumpArgs {
+    /// Print the table in this format after execution completes
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    dump_table: Option<TableFormat>,
+    /// Write the table to this file instead of stdout
+    #[arg(long, value_name = "PATH", requires = "dump_table")]
+    dump_to: Option<String>,
+}
+
+/// Forms the table can be reported in, described in `src/dump.rs`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum TableFormat {
+    /// The size, cursor, registers and every non-zero cell with its index
+    Compact,
+    /// Rows of cells in hex, decimal and ASCII, like a hexdump
+    Hex,
+    /// A JSON object with the pointers and every cell
+    Json,
+    /// A row for every cell, with the cursor and registers on it
+    Csv,
+}
+
+/// When and where a running program's state is saved, besides whenever it gets SIGUSR1.
+#[derive(clap::Args, Debug)]
+struct SnapshotArgs {
+    /// Save a snapshot once this many steps have run since the program started, and stop. On
+    /// resume, this has to be past the steps the snapshot had already run
+    #[arg(long, value_name = "STEPS")]
+    snapshot_at: Option<u64>,
+    /// Where to save snapshots
+    #[arg(long, value_name = "PATH", default_value = "snapshot.bin")]
+    snapshot: String,
+}
+
+/// Forms a parsed program can be read back from.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    /// The JSON form of the syntax tree, described in `src/json.rs`
+    AstJson,
+    /// The binary form a program can be shipped and run in, described in `src/bytecode.rs`
+    Bytecode,
+}
+
+/// Whether to report for people or for other programs.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Output {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Ways to print a parsed program.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+enum AstFormat {
+    /// The JSON form of the syntax tree, described in `src/json.rs`
+    #[default]
+    Json,
+    /// minlang source, with every include and macro pasted in
+    Source,
+    /// Rust's debug view of the `Program`
+    Debug,
+    /// Every name the program used and the number it stands for
+    Symbols,
+}
+
+/// What `build` can compile a program into.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+enum Target {
+    /// The binary form `run --from bytecode` reads, described in `src/bytecode.rs`
+    #[default]
+    Bytecode,
+    /// A brainfuck program, if the program only uses operations brainfuck has
+    Brainfuck,
+}
+
+#[derive(clap::Args, Debug)]
+struct RunArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    #[command(flatten)]
+    table: TableArgs,
+    /// Run the program in a debugger that can step backward as well as forward
     #[arg(short, long)]
-    verbose: bool,
+    debug: bool,
+    /// Watch the program run in a terminal UI that shows the tape, its output and its source
+    #[arg(long, conflicts_with = "debug")]
+    tui: bool,
+    /// Give the program this file to read instead of stdin, which the debugger and TUI read nothing from
+    #[arg(long, value_name = "PATH")]
+    input: Option<String>,
     /// Show the table after execution completes
     #[arg(short, long)]
     show_registers: bool,
+    #[command(flatten)]
+    snapshots: SnapshotArgs,
+    #[command(flatten)]
+    dump: DumpArgs,
 }
 
-fn main() {
-    let args = Args::parse();
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Run a program
+    Run(RunArgs),
+    /// Check a program for errors without running it
+    Check {
+        #[command(flatten)]
+        source: SourceArgs,
+        #[command(flatten)]
+        table: TableArgs,
+        /// How to report what was found
+        #[arg(long, value_enum, default_value_t)]
+        format: Output,
+    },
+    /// List the tokens in a source file, before includes and macros
+    Tokens {
+        /// Source file path, or - to read it from stdin
+        source_path: String,
+        /// Reject characters outside of comments that are not instructions
+        #[arg(long)]
+        strict: bool,
+        /// List comments too
+        #[arg(long)]
+        keep_comments: bool,
+        /// How to list them
+        #[arg(long, value_enum, default_value_t)]
+        format: Output,
+    },
+    /// Print a program as it was parsed
+    Ast {
+        #[command(flatten)]
+        source: SourceArgs,
+        /// How to print it
+        #[arg(long, value_enum, default_value_t)]
+        format: AstFormat,
+    },
+    /// Compile a program into another form
+    Build {
+        #[command(flatten)]
+        source: SourceArgs,
+        /// What to compile it into
+        #[arg(long, value_enum, default_value_t)]
+        target: Target,
+        /// Write the result to a file instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Leave the table mapping instructions back to the source out of bytecode
+        #[arg(long)]
+        strip: bool,
+    },
+    /// Format minlang source files, printing the result unless told otherwise
+    Fmt {
+        /// Source file paths, or - to read from stdin
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Only report which files aren't formatted, and fail if any aren't
+        #[arg(long, conflicts_with = "write")]
+        check: bool,
+        /// Rewrite the files in place
+        #[arg(long)]
+        write: bool,
+        /// How to report what --check found
+        #[arg(long, value_enum, default_value_t)]
+        format: Output,
+    },
+    /// Run statements as they're typed, on a table that carries over from one to the next
+    Repl {
+        /// How many cells the table has
+        #[arg(long, default_value_t = 32)]
+        size: usize,
+        /// Make table cells bytes that wrap around, like brainfuck's
+        #[arg(long)]
+        byte_cells: bool,
+        /// Also look for included files in this directory (can be repeated)
+        #[arg(short = 'I', long = "include-path")]
+        include_paths: Vec<String>,
+        /// Print the table in this format after everything that runs
+        #[arg(long, value_enum, value_name = "FORMAT")]
+        dump_table: Option<TableFormat>,
+    },
+    /// Generate a program that prints the given text
+    GenPrint {
+        /// Text to print
+        #[arg(required_unless_present = "file")]
+        text: Option<String>,
+        /// Read the text to print from a file instead, or - for stdin
+        #[arg(short, long, conflicts_with = "text")]
+        file: Option<String>,
+        /// Write the program to a file instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+        /// How many cells the program may build characters in
+        #[arg(short, long, default_value_t = generator::DEFAULT_CELLS)]
+        cells: usize,
+    },
+    /// Translate a brainfuck program into minlang
+    BfToMini {
+        /// Brainfuck source file path, or - to read it from stdin
+        source_path: String,
+        /// Write the program to a file instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Carry on running a program from a snapshot of it
+    Resume {
+        /// Snapshot file path
+        snapshot_path: String,
+        /// Show the table after execution completes
+        #[arg(short, long)]
+        show_registers: bool,
+        #[command(flatten)]
+        snapshots: SnapshotArgs,
+        #[command(flatten)]
+        dump: DumpArgs,
+    },
+    /// List the instructions in a bytecode file
+    Disasm {
+        /// Bytecode file path, or - to read it from stdin
+        source_path: String,
+        /// The file it was compiled from, to quote next to each instruction if it has debug info
+        #[arg(long)]
+        source: Option<String>,
+        /// Write the listing to a file instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+}
 
-    let source_path = args.source_path;
+/// Why a command failed, which decides what it exits with.
+#[derive(Debug)]
+enum Failure {
+    /// Something is wrong with the program or a file given for it: what found it, what it
+    /// says, and the line and column it's about, if it's about one.
+    Invalid { stage: &'static str, message: String, at: Option<(usize, usize)> },
+    /// A file couldn't be read or written.
+    Io(String),
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Invalid { stage, message, .. } => f.write_fmt(format_args!("{} error: {}", stage, message)),
+            Failure::Io(message) => f.write_str(message),
+        }
+    }
+}
 
-    // Parse path into system path
-    let path = Path::new(&source_path);
+impl Failure {
+    fn invalid(stage: &'static str, err: impl Display) -> Failure {
+        Failure::Invalid { stage, message: err.to_string(), at: None }
+    }
 
-    let source_contents = fs::read_to_string(path).expect(format!("Unable to open file with path '{}'", source_path).as_str());
+    fn at(self, (line, col): (usize, usize)) -> Failure {
+        match self {
+            Failure::Invalid { stage, message, .. } => Failure::Invalid { stage, message, at: Some((line, col)) },
+            failure => failure,
+        }
+    }
 
-    // Lex the source file
-    let mut lexr = lexer::Lexer::new(source_contents);
+    fn code(&self) -> i32 {
+        match self {
+            Failure::Invalid { .. } => EXIT_INVALID,
+            Failure::Io(_) => EXIT_IO,
+        }
+    }
 
-    let lex_result = lexr.lex();
+    fn to_json(&self) -> Value {
+        match self {
+            Failure::Invalid { stage, message, at } => json!({
+                "stage": stage.to_lowercase(),
+                "message": message,
+                "line": at.map(|(line, _)| line),
+                "col": at.map(|(_, col)| col),
+            }),
+            Failure::Io(message) => json!({ "stage": "io", "message": message, "line": null, "col": null }),
+        }
+    }
+}
 
-    let token_stream = if let Ok(strm) = lex_result {
-        strm
-    } else {
-        panic!("Lexer error: {}", lex_result.err().unwrap());
+type CliResult<T> = Result<T, Failure>;
+
+/// Read a file, or all of stdin for `-`.
+fn read(path: &str) -> CliResult<Vec<u8>> {
+    if path == "-" {
+        let mut bytes = Vec::new();
+        return std::io::stdin().read_to_end(&mut bytes).map(|_| bytes).map_err(|err| Failure::Io(format!("Unable to read stdin: {}", err)));
+    }
+    fs::read(path).map_err(|err| Failure::Io(format!("Unable to open file with path '{}': {}", path, err)))
+}
+
+fn read_text(path: &str) -> CliResult<String> {
+    String::from_utf8(read(path)?).map_err(|_| Failure::Io(format!("File with path '{}' isn't UTF-8", path)))
+}
+
+fn write_file(path: &str, bytes: &[u8]) -> CliResult<()> {
+    fs::write(path, bytes).map_err(|err| Failure::Io(format!("Unable to write file with path '{}': {}", path, err)))
+}
+
+fn write_output(output: Option<String>, bytes: &[u8]) -> CliResult<()> {
+    match output {
+        Some(path) => write_file(&path, bytes),
+        None => std::io::stdout().write_all(bytes).map_err(|err| Failure::Io(format!("Unable to write to stdout: {}", err))),
+    }
+}
+
+/// Report panics from here on as the running program going wrong, and stop with
+/// `EXIT_RUNTIME` if `fatal`.
+fn report_runtime_errors(fatal: bool) {
+    std::panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = payload.downcast_ref::<&str>().copied().or(payload.downcast_ref::<String>().map(String::as_str)).unwrap_or("unknown error");
+        let _ = std::io::stdout().flush();
+        eprintln!("Runtime error: {}", message);
+        if fatal {
+            exit(EXIT_RUNTIME);
+        }
+    }));
+}
+
+fn bf_to_mini(source_path: String, output: Option<String>) -> CliResult<()> {
+    let source = read_text(&source_path)?;
+    let program = brainfuck::from_brainfuck(&source).map_err(|err| Failure::invalid("Brainfuck", err))?;
+
+    // The header says the cells are bytes, so the result runs as it did in brainfuck
+    write_output(output, Printer::new().print_program(&program).as_bytes())
+}
+
+fn disassemble(source_path: String, source: Option<String>, output: Option<String>) -> CliResult<()> {
+    let bytes = read(&source_path)?;
+    let source = source.map(|path| read_text(&path)).transpose()?;
+
+    let listing = disasm::disassemble(&bytes, source.as_deref()).map_err(|err| Failure::invalid("Bytecode", err))?;
+    write_output(output, listing.as_bytes())
+}
+
+fn gen_print(text: Option<String>, file: Option<String>, output: Option<String>, cells: usize) -> CliResult<()> {
+    let text = match (text, file) {
+        (Some(text), _) => text,
+        (None, Some(file)) => read_text(&file)?,
+        (None, None) => unreachable!(),
+    };
+
+    let source = generator::generate_print(&text, cells).map_err(|err| Failure::invalid("Generator", err))?;
+    write_output(output, source.as_bytes())
+}
+
+/// Lex minlang source, pull in its includes and expand its macros. `path` is where to look
+/// for includes from, if the source came from a file.
+fn expand(path: Option<&Path>, strict: bool, include_paths: &[String], text: String) -> CliResult<Vec<Token>> {
+    let options = LexerOptions { keep_comments: false, strict };
+    let stream = lexer::Lexer::with_options(text, options).lex().map_err(|err| {
+        let loc = err.location();
+        Failure::invalid("Lexer", &err).at((loc.line, loc.col))
+    })?;
+
+    // Pull in included files
+    let search_paths: Vec<PathBuf> = include_paths.iter().map(PathBuf::from).collect();
+    let tokens = include::include(stream.tokens, path, &search_paths).map_err(|err| {
+        let loc = err.location();
+        Failure::invalid("Include", &err).at((loc.line, loc.col))
+    })?;
+
+    macros::expand(tokens).map_err(|err| Failure::invalid("Macro", &err).at(err.location()))
+}
+
+fn parse_error(err: ParseError) -> Failure {
+    let at = err.location();
+    Failure::invalid("Parser", err).at(at)
+}
+
+/// Lex, include, expand and parse minlang source.
+fn compile(path: Option<&Path>, strict: bool, include_paths: &[String], text: String) -> CliResult<Program> {
+    let tokens = expand(path, strict, include_paths, text)?;
+    parser::Parser::new(tokens).parse_program().map_err(parse_error)
+}
+
+/// Read and parse the program `source` names, without checking it. Also gives its minlang
+/// source, if that's what it was.
+fn parse(source: &SourceArgs) -> CliResult<(Program, Option<String>)> {
+    let path = &source.source_path;
+    match source.from {
+        Some(Format::AstJson) => Ok((json::load(&read_text(path)?).map_err(|err| Failure::invalid("AST JSON", err))?, None)),
+        Some(Format::Bytecode) => Ok((bytecode::read(&read(path)?).map_err(|err| Failure::invalid("Bytecode", err))?, None)),
+        None => {
+            let text = read_text(path)?;
+            let from = (path != "-").then(|| Path::new(path));
+            Ok((compile(from, source.strict, &source.include_paths, text.clone())?, Some(text)))
+        },
+    }
+}
+
+/// Fill the table in from `--init` and `--set`, in that order.
+fn preload(table_args: &TableArgs, table: &mut Table) -> CliResult<()> {
+    let mut preload = match &table_args.init {
+        Some(path) => Preload::from_file(&read_text(path)?).map_err(|err| Failure::invalid("Initial table", format!("in '{}': {}", path, err)))?,
+        None => Preload::default(),
     };
+    for assignment in &table_args.set {
+        preload.assign(assignment).map_err(|err| Failure::invalid("Initial table", err))?;
+    }
+
+    preload.apply(table).map_err(|err| Failure::invalid("Initial table", err))
+}
+
+/// Check a parsed program is fit to run.
+fn check_semantics(prog: &Program) -> CliResult<()> {
+    semantics::check(prog).map_err(|err| {
+        let loc = &err.token().loc;
+        let at = (loc.line, loc.col);
+        Failure::invalid("Semantic", err).at(at)
+    })
+}
+
+/// Read, compile and check a program, with the table it asks for.
+fn load(source: &SourceArgs, table: &TableArgs) -> CliResult<(Program, Option<String>)> {
+    let (mut prog, text) = parse(source)?;
+
+    if table.byte_cells {
+        prog.table.width = CellWidth::Byte;
+    }
+    // The checks go by the table's size, which the initial table can change
+    preload(table, &mut prog.table)?;
+    check_semantics(&prog)?;
+
+    Ok((prog, text))
+}
+
+/// The options that decide what program `run` loads, written as they would be on the command
+/// line. Snapshots keep them so `resume` can load the same program again from anywhere.
+fn program_options(source: &SourceArgs, table: &TableArgs) -> Vec<String> {
+    let absolute = |path: &str| fs::canonicalize(path).map_or(path.to_string(), |path| path.to_string_lossy().into_owned());
 
-    // Bail if only lexing
-    if args.lex {
-        if args.verbose {
-            println!("{:?}", token_stream.tokens);
+    let mut options = Vec::new();
+    if let Some(format) = source.from {
+        options.extend(["--from".to_string(), format.to_possible_value().unwrap().get_name().to_string()]);
+    }
+    for (set, flag) in [(source.strict, "--strict"), (table.byte_cells, "--byte-cells")] {
+        if set {
+            options.push(flag.to_string());
         }
-        exit(0);
     }
+    for path in &source.include_paths {
+        options.extend(["--include-path".to_string(), absolute(path)]);
+    }
+    if let Some(path) = &table.init {
+        options.extend(["--init".to_string(), absolute(path)]);
+    }
+    for assignment in &table.set {
+        options.extend(["--set".to_string(), assignment.clone()]);
+    }
+    options.push(absolute(&source.source_path));
 
-    // Parse the program
-    let mut parsr = parser::Parser::new(token_stream.tokens);
+    options
+}
+
+fn save_snapshot(vm: &mut Vm, origin: &[String], path: &str) -> CliResult<()> {
+    vm.output.flush().expect("Unable to write program output");
+    write_file(path, &Snapshot::take(vm, origin.to_vec()).to_bytes())?;
+    eprintln!("Saved a snapshot after {} steps to '{}'", vm.steps, path);
+    Ok(())
+}
+
+fn dump_table(table: &Table, format: TableFormat) -> String {
+    match format {
+        TableFormat::Compact => dump::compact(table),
+        TableFormat::Hex => dump::hex(table),
+        TableFormat::Json => format!("{}\n", dump::json(table)),
+        TableFormat::Csv => dump::csv(table),
+    }
+}
 
-    let parse_result = parsr.parse_program();
+/// Run the program, saving snapshots as `snapshots` asks and whenever SIGUSR1 comes in.
+fn execute(mut vm: Vm, origin: &[String], show_registers: bool, snapshots: &SnapshotArgs, dump: &DumpArgs) -> CliResult<()> {
+    let requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&requested)).expect("Unable to listen for SIGUSR1");
 
-    let prog = if let Ok(prg) = parse_result {
-        prg
+    report_runtime_errors(true);
+    loop {
+        if snapshots.snapshot_at == Some(vm.steps) && vm.pc.is_some() {
+            return save_snapshot(&mut vm, origin, &snapshots.snapshot);
+        }
+        if requested.swap(false, Ordering::Relaxed) {
+            save_snapshot(&mut vm, origin, &snapshots.snapshot)?;
+        }
+        if !vm.step() {
+            break;
+        }
+    }
+
+    if show_registers {
+        vm.show_registers();
+    }
+    vm.output.flush().expect("Unable to write program output");
+
+    match dump.dump_table {
+        Some(format) => write_output(dump.dump_to.clone(), dump_table(&vm.program.table, format).as_bytes()),
+        None => Ok(()),
+    }
+}
+
+/// What the program reads: the `--input` file, or else `or`.
+fn program_input(input: &Option<String>, or: impl Read + 'static) -> CliResult<Box<dyn Read>> {
+    match input {
+        Some(path) => Ok(Box::new(fs::File::open(path).map_err(|err| Failure::Io(format!("Unable to open file with path '{}': {}", path, err)))?)),
+        None => Ok(Box::new(or)),
+    }
+}
+
+/// Read debugger commands from stdin until it runs out or says to quit.
+fn debug(prog: Program, source: Option<String>, input: Box<dyn Read>) {
+    let mut debugger = Debugger::new(prog, input, source.as_deref());
+
+    println!("{}", debugger.status());
+    let mut line = String::new();
+    loop {
+        print!("(debug) ");
+        std::io::stdout().flush().expect("Unable to write to stdout");
+
+        line.clear();
+        if std::io::stdin().read_line(&mut line).expect("Unable to read from stdin") == 0 {
+            println!();
+            break;
+        }
+        if matches!(line.trim(), "quit" | "q") {
+            break;
+        }
+        println!("{}", debugger.command(&line));
+    }
+}
+
+fn run(args: RunArgs) -> CliResult<()> {
+    let (prog, source) = load(&args.source, &args.table)?;
+
+    if args.debug {
+        report_runtime_errors(true);
+        debug(prog, source, program_input(&args.input, std::io::empty())?);
+        Ok(())
+    } else if args.tui {
+        report_runtime_errors(true);
+        let tui = Tui::new(prog, program_input(&args.input, std::io::empty())?, source.as_deref());
+        tui::run(tui).map_err(|err| Failure::Io(format!("Unable to draw to the terminal: {}", err)))
     } else {
-        panic!("Parser error: {}", parse_result.err().unwrap());
+        let vm = Vm::with_io(prog, program_input(&args.input, std::io::stdin())?, std::io::stdout());
+        execute(vm, &program_options(&args.source, &args.table), args.show_registers, &args.snapshots, &args.dump)
+    }
+}
+
+fn resume(snapshot_path: String, show_registers: bool, snapshots: SnapshotArgs, dump: DumpArgs) -> CliResult<()> {
+    let snapshot = Snapshot::from_bytes(&read(&snapshot_path)?).map_err(|err| Failure::invalid("Snapshot", err))?;
+
+    let origin = snapshot.origin.clone();
+    let args = Origin::try_parse_from(std::iter::once("minlang2".to_string()).chain(origin.iter().cloned())).map_err(|err| {
+        Failure::invalid("Snapshot", format!("the options it gives for loading its program aren't valid: {}", err))
+    })?;
+    let (prog, _) = load(&args.source, &args.table)?;
+
+    let mut vm = Vm::new(prog);
+    snapshot.restore(&mut vm).map_err(|err| Failure::invalid("Snapshot", err))?;
+    // Steps are counted from the start of the program, so a step the snapshot is already past
+    // would never come round again
+    if let Some(at) = snapshots.snapshot_at.filter(|&at| at <= vm.steps) {
+        return Err(Failure::invalid("Snapshot", format!("it was taken after {} steps, so --snapshot-at {} has already passed", vm.steps, at)));
+    }
+
+    execute(vm, &origin, show_registers, &snapshots, &dump)
+}
+
+fn check(source: SourceArgs, table: TableArgs, format: Output) -> CliResult<()> {
+    let result = load(&source, &table).map(|_| ());
+
+    if format == Output::Json {
+        let errors: Vec<Value> = result.as_ref().err().map(Failure::to_json).into_iter().collect();
+        println!("{}", json!({ "ok": result.is_ok(), "errors": errors }));
+    }
+    result
+}
+
+fn tokens(source_path: String, strict: bool, keep_comments: bool, format: Output) -> CliResult<()> {
+    let text = read_text(&source_path)?;
+    let stream = lexer::Lexer::with_options(text, LexerOptions { keep_comments, strict }).lex().map_err(|err| {
+        let loc = err.location();
+        Failure::invalid("Lexer", &err).at((loc.line, loc.col))
+    })?;
+
+    // The variant's name, without whatever it holds
+    let kind = |kind: &TokenKind| format!("{:?}", kind).split('(').next().unwrap_or_default().to_string();
+
+    let listing = match format {
+        Output::Text => stream.tokens.iter().map(|token| format!("{}:{}\t{}\t{}\n", token.loc.line, token.loc.col, kind(&token.kind), token.kind)).collect(),
+        Output::Json => {
+            let tokens: Vec<Value> = stream.tokens.iter().map(|token| json!({
+                "kind": kind(&token.kind),
+                "text": token.kind.to_string(),
+                "line": token.loc.line,
+                "col": token.loc.col,
+                "start": token.loc.start,
+                "end": token.loc.end,
+            })).collect();
+            format!("{}\n", Value::Array(tokens))
+        },
+    };
+    write_output(None, listing.as_bytes())
+}
+
+fn ast(source: SourceArgs, format: AstFormat) -> CliResult<()> {
+    let (prog, _) = parse(&source)?;
+
+    let text = match format {
+        AstFormat::Json => format!("{}\n", serde_json::to_string_pretty(&json::to_json(&prog)).unwrap()),
+        AstFormat::Source => Printer::new().print_program(&prog),
+        AstFormat::Debug => format!("{:#?}\n", prog),
+        AstFormat::Symbols => prog.symbols.to_string(),
+    };
+    write_output(None, text.as_bytes())
+}
+
+fn build(source: SourceArgs, target: Target, output: Option<String>, strip: bool) -> CliResult<()> {
+    let (prog, _) = parse(&source)?;
+    check_semantics(&prog)?;
+
+    let bytes = match target {
+        Target::Bytecode => bytecode::write(&prog, !strip),
+        Target::Brainfuck => (brainfuck::to_brainfuck(&prog).map_err(|err| Failure::invalid("Brainfuck", err))? + "\n").into_bytes(),
     };
+    write_output(output, &bytes)
+}
+
+/// Source formatted the way the printer lays it out, comments and all.
+fn format_source(text: &str) -> CliResult<String> {
+    printer::format_source(text).map_err(|err| {
+        let stage = match err {
+            FormatError::Lex(_) => "Lexer",
+            FormatError::Parse(_) => "Parser",
+            FormatError::Unsupported(_) => "Format",
+        };
+        let at = err.location();
+        Failure::invalid(stage, err).at(at)
+    })
+}
+
+fn fmt(paths: Vec<String>, check: bool, write: bool, format: Output) -> CliResult<()> {
+    let mut unformatted = Vec::new();
+    let mut report = Vec::new();
+
+    for path in &paths {
+        let text = read_text(path)?;
+        let formatted = format_source(&text).map_err(|failure| match failure {
+            Failure::Invalid { stage, message, at } => Failure::Invalid { stage, message: format!("in '{}': {}", path, message), at },
+            failure => failure,
+        })?;
+        let changed = formatted != text;
+
+        if check {
+            report.push(json!({ "path": path, "formatted": !changed }));
+            if changed {
+                unformatted.push(path.clone());
+            }
+        } else if write && path != "-" {
+            if changed {
+                write_file(path, formatted.as_bytes())?;
+            }
+        } else {
+            write_output(None, formatted.as_bytes())?;
+        }
+    }
+
+    if check {
+        match format {
+            Output::Text => unformatted.iter().for_each(|path| println!("{}", path)),
+            Output::Json => println!("{}", Value::Array(report)),
+        }
+        if !unformatted.is_empty() {
+            return Err(Failure::invalid("Format", format!("{} of {} files aren't formatted", unformatted.len(), paths.len())));
+        }
+    }
+
+    Ok(())
+}
+
+/// Read statements from stdin and run each as it's finished, on a table that carries over.
+///
+/// Everything entered so far is parsed again each time, so names, functions and macros defined
+/// earlier still work, but only the statements that are new get run, after the functions
+/// defined before them. A statement that isn't finished yet, like a loop missing its `}`,
+/// waits for more lines.
+fn repl(size: usize, byte_cells: bool, include_paths: Vec<String>, dump: Option<TableFormat>) -> CliResult<()> {
+    let header = format!("[{}]\n", size);
+    let mut table = Table::new(size);
+    if byte_cells {
+        table.width = CellWidth::Byte;
+    }
+    let mut entered = String::new();
+    let mut pending = String::new();
+    let mut ran = 0;
+
+    report_runtime_errors(false);
+    let mut line = String::new();
+    loop {
+        print!("{}", if pending.is_empty() { "> " } else { ". " });
+        std::io::stdout().flush().expect("Unable to write to stdout");
+
+        line.clear();
+        if std::io::stdin().read_line(&mut line).map_err(|err| Failure::Io(format!("Unable to read stdin: {}", err)))? == 0 {
+            println!();
+            return Ok(());
+        }
+        pending.push_str(&line);
+        if pending.trim().is_empty() {
+            pending.clear();
+            continue;
+        }
+
+        let source = format!("{}{}{}", header, entered, pending);
+        let parsed = expand(None, false, &include_paths, source).and_then(|tokens| match parser::Parser::new(tokens).parse_program() {
+            Err(ParseError::UnexpectedEof(..)) => Ok(None),
+            parsed => parsed.map(Some).map_err(parse_error),
+        });
+        let mut prog = match parsed {
+            Ok(Some(prog)) => prog,
+            Ok(None) => continue,
+            Err(failure) => {
+                eprintln!("{}", failure);
+                pending.clear();
+                continue;
+            },
+        };
+
+        // Keep the functions from before, so the new statements can call them
+        let new = prog.statements.split_off(ran.min(prog.statements.len()));
+        let total = prog.statements.len() + new.len();
+        prog.statements.retain(|stmt| matches!(stmt.kind, StatementKind::DefineFn(..)));
+        prog.statements.extend(new);
+        prog.table = table.clone();
 
-    if args.parse {
-        if args.verbose {
-            println!("{:?}", prog);
+        if let Err(failure) = check_semantics(&prog) {
+            eprintln!("{}", failure);
+            pending.clear();
+            continue;
+        }
+
+        let mut vm = Vm::with_io(prog, std::io::empty(), Vec::new());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.run(false)));
+        let output = String::from_utf8_lossy(&vm.output).into_owned();
+        print!("{}", output);
+        if !output.is_empty() && !output.ends_with('\n') {
+            println!();
+        }
+        if result.is_err() {
+            // Leave the table as it was before the statements that went wrong
+            pending.clear();
+            continue;
+        }
+
+        table = vm.program.table;
+        entered.push_str(&pending);
+        pending.clear();
+        ran = total;
+
+        if let Some(format) = dump {
+            print!("{}", dump_table(&table, format));
         }
     }
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Command::Run(args) => run(args),
+        Command::Check { source, table, format } => check(source, table, format),
+        Command::Tokens { source_path, strict, keep_comments, format } => tokens(source_path, strict, keep_comments, format),
+        Command::Ast { source, format } => ast(source, format),
+        Command::Build { source, target, output, strip } => build(source, target, output, strip),
+        Command::Fmt { paths, check, write, format } => fmt(paths, check, write, format),
+        Command::Repl { size, byte_cells, include_paths, dump_table } => repl(size, byte_cells, include_paths, dump_table),
+        Command::GenPrint { text, file, output, cells } => gen_print(text, file, output, cells),
+        Command::BfToMini { source_path, output } => bf_to_mini(source_path, output),
+        Command::Resume { snapshot_path, show_registers, snapshots, dump } => resume(snapshot_path, show_registers, snapshots, dump),
+        Command::Disasm { source_path, source, output } => disassemble(source_path, source, output),
+    };
 
-    if args.run {
-        Vm::new(prog).run(args.show_registers);
+    if let Err(failure) = result {
+        eprintln!("{}", failure);
+        exit(failure.code());
     }
 }
@@ -1,15 +1,16 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fmt::Display;
 use std::iter::Peekable;
 
 use crate::ast::*;
 use crate::lexer::*;
-use crate::program::DEFAULT_TABLE_SIZE;
+use crate::program::{Version, DEFAULT_TABLE_SIZE, MAX_LOCALS, SAVE_REGISTERS};
 use crate::program::Program;
 use crate::program::Table;
+use crate::symbols::SymbolTable;
 
 #[derive(Debug)]
-pub(crate) enum ParseError {
+pub enum ParseError {
     ExpectedToken(TokenKind, Token),
     ExpectedNumber(Token),
     ExpectedParameter(Token),
@@ -17,80 +18,238 @@ pub(crate) enum ParseError {
     UnexpectedToken(Token),
     UnexpectedEof(usize, usize),
     ExpectedStatement(usize, usize),
+    UndefinedFunction(Token),
+    UndefinedCell(Token),
+    DuplicateFunction(Token),
+    ConflictingCell(Token, usize),
+    ConflictingFunction(Token, usize, Box<Token>),
+    ValueOutOfRange(Token),
+    NegativeCell(Token),
+    NoSuchRegister(Token),
+    NotInVersion(Token, Version),
+    NestedTooDeeply(Token),
+    TooManyLocals(Token),
 }
 
 impl Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ParseError::ExpectedToken(kind, token) => {
-                return f.write_fmt(format_args!("Expected token of type '{}' at line {} col {}, got '{}'.", 
-                    kind, token.loc.line, token.loc.col, token.loc.span
-                ));
+                f.write_fmt(format_args!("Expected token of type '{}' at line {} col {}, got '{}'.", 
+                    kind, token.loc.line, token.loc.col, token.kind
+                ))
             },
             ParseError::ExpectedNumber(token) => {
-                return f.write_fmt(format_args!("Expected a number at line {} col {}, got '{}'.",
-                    token.loc.line, token.loc.col, token.loc.span
-                ));
+                f.write_fmt(format_args!("Expected a number at line {} col {}, got '{}'.",
+                    token.loc.line, token.loc.col, token.kind
+                ))
             },
             ParseError::ExpectedParameter(token) => {
-                return f.write_fmt(format_args!("Expected a number or '$' at line {} col {}, got '{}'.",
-                    token.loc.line, token.loc.col, token.loc.span
-                ));
+                f.write_fmt(format_args!("Expected a number or '$' at line {} col {}, got '{}'.",
+                    token.loc.line, token.loc.col, token.kind
+                ))
             },
             ParseError::UnexpectedTableToken(token) => {
-                return f.write_fmt(format_args!("Unexpected table token '{}' at line {} col {}.",
-                    token.loc.span, token.loc.line, token.loc.col
-                ));
+                f.write_fmt(format_args!("Unexpected table token '{}' at line {} col {}.",
+                    token.kind, token.loc.line, token.loc.col
+                ))
             },
             ParseError::UnexpectedToken(token) => {
-                return f.write_fmt(format_args!("Unexpected token '{}' at line {} col {}.",
-                    token.loc.span, token.loc.line, token.loc.col
-                ));
+                f.write_fmt(format_args!("Unexpected token '{}' at line {} col {}.",
+                    token.kind, token.loc.line, token.loc.col
+                ))
             },
             ParseError::UnexpectedEof(line, col) => {
-                return f.write_fmt(format_args!("Unexpected EOF at line {} col {}.",
+                f.write_fmt(format_args!("Unexpected EOF at line {} col {}.",
                     line, col
-                ));
+                ))
             },
             ParseError::ExpectedStatement(line, col) => {
-                return f.write_fmt(format_args!("Expected a statement at line {} col {}.",
+                f.write_fmt(format_args!("Expected a statement at line {} col {}.",
                     line, col
-                ));
+                ))
             },
+            ParseError::UndefinedFunction(token) => {
+                f.write_fmt(format_args!("No function named '{}' at line {} col {}.",
+                    token.kind, token.loc.line, token.loc.col
+                ))
+            },
+            ParseError::UndefinedCell(token) => {
+                f.write_fmt(format_args!("No cell named '{}' at line {} col {}; bind it with '@({} = n)'.",
+                    token.kind, token.loc.line, token.loc.col, token.kind
+                ))
+            },
+            ParseError::DuplicateFunction(token) => {
+                f.write_fmt(format_args!("Function '{}' is defined again at line {} col {}.",
+                    token.kind, token.loc.line, token.loc.col
+                ))
+            },
+            ParseError::ConflictingCell(token, idx) => {
+                f.write_fmt(format_args!("Cell '{}' is bound again at line {} col {}, but is already cell {}.",
+                    token.kind, token.loc.line, token.loc.col, idx
+                ))
+            },
+            ParseError::ValueOutOfRange(token) => {
+                f.write_fmt(format_args!("{} at line {} col {} doesn't fit in a cell.",
+                    token.kind, token.loc.line, token.loc.col
+                ))
+            },
+            ParseError::NotInVersion(token, version) => {
+                f.write_fmt(format_args!("'{}' at line {} col {} needs version {} of the language, but the file declares an earlier one.",
+                    token.kind, token.loc.line, token.loc.col, version
+                ))
+            },
+            ParseError::NoSuchRegister(token) => {
+                f.write_fmt(format_args!("No save register {} at line {} col {}; there are {}, $0 to ${}.",
+                    token.kind, token.loc.line, token.loc.col, SAVE_REGISTERS, SAVE_REGISTERS - 1
+                ))
+            },
+            ParseError::NegativeCell(token) => {
+                f.write_fmt(format_args!("Expected a cell at line {} col {}, but {} is negative.",
+                    token.loc.line, token.loc.col, token.kind
+                ))
+            },
+            ParseError::NestedTooDeeply(token) => {
+                f.write_fmt(format_args!("Blocks nested more than {} deep at line {} col {}.",
+                    MAX_NESTING, token.loc.line, token.loc.col
+                ))
+            },
+            ParseError::TooManyLocals(token) => {
+                f.write_fmt(format_args!("A function can have at most {} locals, but the one at line {} col {} asks for {}.",
+                    MAX_LOCALS, token.loc.line, token.loc.col, token.kind
+                ))
+            },
+            ParseError::ConflictingFunction(token, id, first) => {
+                f.write_fmt(format_args!("Function {} is defined at line {} col {}, but {} already defines it at {}; use names for functions in included files.",
+                    id, token.loc.line, token.loc.col, first.loc.file.as_ref().map_or("the main file".to_string(), |file| file.path.display().to_string()), first.loc.describe()
+                ))
+            },
+        }?;
+
+        match self.token() {
+            Some(token) => f.write_str(&token.loc.origin_note()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ParseError {
+    /// The token the error is about, if there is one.
+    pub(crate) fn token(&self) -> Option<&Token> {
+        match self {
+            ParseError::ExpectedToken(_, token)
+            | ParseError::ExpectedNumber(token)
+            | ParseError::ExpectedParameter(token)
+            | ParseError::UnexpectedTableToken(token)
+            | ParseError::UnexpectedToken(token)
+            | ParseError::UndefinedFunction(token)
+            | ParseError::UndefinedCell(token)
+            | ParseError::DuplicateFunction(token)
+            | ParseError::ConflictingCell(token, _)
+            | ParseError::ConflictingFunction(token, ..)
+            | ParseError::ValueOutOfRange(token)
+            | ParseError::NegativeCell(token)
+            | ParseError::NoSuchRegister(token)
+            | ParseError::NotInVersion(token, _)
+            | ParseError::NestedTooDeeply(token)
+            | ParseError::TooManyLocals(token) => Some(token),
+            ParseError::UnexpectedEof(..) | ParseError::ExpectedStatement(..) => None,
+        }
+    }
+
+    /// The line and column the error was found at.
+    pub fn location(&self) -> (usize, usize) {
+        match self {
+            ParseError::UnexpectedEof(line, col) | ParseError::ExpectedStatement(line, col) => (*line, *col),
+            _ => self.token().map(|token| (token.loc.line, token.loc.col)).unwrap_or((1, 1)),
         }
     }
 }
 
 type ParseResult<T> = Result<T, ParseError>;
 
-pub(crate) struct Parser {
+/// How deeply loops, ifs and function definitions may nest before we give up rather than
+/// overflow the stack. The original parser ran out of stack a little past this in debug builds,
+/// so nothing it could parse is turned away.
+pub(crate) const MAX_NESTING: usize = 1000;
+
+pub struct Parser {
     tokens: Peekable<TokenStream>,
+    // Comments waiting to be placed in the AST, only filled by `with_comments`
+    comments: VecDeque<Token>,
     line: usize,
     col: usize,
+    // How many blocks the statement being parsed is inside
+    depth: usize,
+    symbols: SymbolTable,
+    // Named functions defined so far, to catch a second definition
+    defined: BTreeSet<String>,
+    // Set by the table header
+    version: Version,
 }
 
 impl Parser {
-    pub(crate) fn new(tokens: Vec<Token>) -> Parser {
-        Parser { tokens: TokenStream::new(tokens).peekable(), line: 1, col: 1 }
+    pub fn new(tokens: Vec<Token>) -> Parser {
+        // Comments only matter to tooling that reads the token stream, never to the grammar
+        let tokens = tokens.into_iter().filter(|tok| !matches!(tok.kind, TokenKind::Comment(_))).collect();
+        Parser::with_pending_comments(tokens, VecDeque::new())
+    }
+
+    /// A parser that keeps comment tokens as `StatementKind::Comment` statements, for tools like
+    /// the formatter that need to write them back out.
+    ///
+    /// Comments land between the statements they were found between. One in the middle of a
+    /// statement, e.g. between `^(` and `)`, is moved to just after it.
+    pub fn with_comments(tokens: Vec<Token>) -> Parser {
+        let (comments, tokens): (Vec<Token>, Vec<Token>) = tokens.into_iter().partition(|tok| matches!(tok.kind, TokenKind::Comment(_)));
+        Parser::with_pending_comments(tokens, comments.into())
+    }
+
+    fn with_pending_comments(tokens: Vec<Token>, comments: VecDeque<Token>) -> Parser {
+        // Names are resolved up front so they can be used before they're defined
+        let symbols = SymbolTable::collect(&tokens);
+        Parser {
+            tokens: TokenStream::new(tokens).peekable(),
+            comments,
+            line: 1,
+            col: 1,
+            depth: 0,
+            symbols,
+            defined: BTreeSet::new(),
+            version: Version::default(),
+        }
+    }
+
+    /// Comment statements for every pending comment that starts before `offset`.
+    fn comments_before(&mut self, offset: usize) -> Vec<Statement> {
+        let mut stmts = Vec::new();
+        while self.comments.front().is_some_and(|tok| tok.loc.start < offset) {
+            let tok = self.comments.pop_front().unwrap();
+            if let TokenKind::Comment(text) = &tok.kind {
+                stmts.push(Statement::new(StatementKind::Comment(text.clone()), tok));
+            }
+        }
+
+        stmts
     }
 
-    pub(crate) fn next(&mut self) -> Option<Token> {
+    fn next(&mut self) -> Option<Token> {
         let next = self.tokens.next();
 
-        if next.is_some() { 
-            self.line = next.as_ref().unwrap().loc.line;
-            self.col = next.as_ref().unwrap().loc.col + next.as_ref().unwrap().loc.len - 1;
+        if let Some(tok) = &next {
+            self.line = tok.loc.line;
+            self.col = tok.loc.last_col();
         }
 
         next
     }
 
-    pub(crate) fn peek(&mut self) -> Option<Token> {
+    fn peek(&mut self) -> Option<Token> {
         self.tokens.peek().cloned()
     }
 
     fn expect_number(&mut self) -> ParseResult<usize> {
-        if let Some(tok) = self.tokens.next() {
+        if let Some(tok) = self.next() {
             match tok.kind {
                 TokenKind::Number(val) => {
                     Ok(val)
@@ -104,17 +263,104 @@ impl Parser {
         }
     }
 
-    fn expect_param(&mut self) -> ParseResult<ParameterKind> {
-        if let Some(tok) = self.tokens.next() {
+    /// The value of a number, negative number or character token, and for a character, how it
+    /// was written. `None` if the token isn't one of those.
+    fn literal(tok: &Token) -> Option<ParseResult<(isize, Option<Name>)>> {
+        let value = match tok.kind {
+            TokenKind::Number(val) => isize::try_from(val).ok(),
+            TokenKind::Negative(val) => 0isize.checked_sub_unsigned(val),
+            TokenKind::Char(chr) => {
+                let name = Name { name: tok.kind.to_string(), binds: false };
+                return Some(Ok((chr as isize, Some(name))));
+            },
+            _ => return None,
+        };
+
+        Some(value.map(|val| (val, None)).ok_or_else(|| ParseError::ValueOutOfRange(tok.clone())))
+    }
+
+    /// A value to store in a cell or compare one against.
+    fn expect_value(&mut self) -> ParseResult<(isize, Option<Name>)> {
+        let Some(tok) = self.next() else {
+            return Err(ParseError::UnexpectedEof(self.line, self.col));
+        };
+
+        Parser::literal(&tok).unwrap_or(Err(ParseError::ExpectedNumber(tok)))
+    }
+
+    /// Reject `tok` if the file declares a version of the language from before `version`.
+    fn require(&self, tok: &Token, version: Version) -> ParseResult<()> {
+        if self.version < version {
+            return Err(ParseError::NotInVersion(tok.clone(), version));
+        }
+
+        Ok(())
+    }
+
+    /// The save register a `$n` names, if there is one.
+    fn register(tok: &Token, register: usize) -> ParseResult<usize> {
+        if register < SAVE_REGISTERS {
+            Ok(register)
+        } else {
+            Err(ParseError::NoSuchRegister(tok.clone()))
+        }
+    }
+
+    fn expect_param(&mut self) -> ParseResult<(ParameterKind, Option<Name>)> {
+        if let Some(tok) = self.next() {
             match tok.kind {
+                TokenKind::Save(register) => {
+                    Ok((ParameterKind::Saved(Parser::register(&tok, register)?), None))
+                }
+                _ => {
+                    let (val, name) = Parser::literal(&tok).unwrap_or(Err(ParseError::ExpectedParameter(tok)))?;
+                    Ok((ParameterKind::Numeric(val), name))
+                }
+            }
+        } else {
+            Err(ParseError::UnexpectedEof(self.line, self.col))
+        }
+    }
+
+    /// A parameter, optionally preceded by how to compare against it.
+    fn expect_condition(&mut self) -> ParseResult<(Condition, Option<Name>)> {
+        let comparison = match self.peek().map(|tok| tok.kind) {
+            Some(TokenKind::Bang) => Comparison::NotEqual,
+            Some(TokenKind::MovL | TokenKind::MovR) if self.version < Version::V2 => {
+                return Err(ParseError::NotInVersion(self.peek().unwrap(), Version::V2));
+            },
+            Some(TokenKind::MovL) => Comparison::Less,
+            Some(TokenKind::MovR) => Comparison::Greater,
+            _ => {
+                let (param, name) = self.expect_param()?;
+                return Ok((Condition::equal(param), name));
+            },
+        };
+        self.next();
+
+        let comparison = match (comparison, self.peek().map(|tok| tok.kind)) {
+            (Comparison::Less, Some(TokenKind::CopyTo)) => { self.next(); Comparison::LessEqual },
+            (Comparison::Greater, Some(TokenKind::CopyTo)) => { self.next(); Comparison::GreaterEqual },
+            (comparison, _) => comparison,
+        };
+
+        let (param, name) = self.expect_param()?;
+        Ok((Condition { comparison, param }, name))
+    }
+
+    /// A function id, written as a number or a name.
+    fn expect_function_id(&mut self) -> ParseResult<(usize, Option<Name>)> {
+        if let Some(tok) = self.next() {
+            match &tok.kind {
                 TokenKind::Number(val) => {
-                    Ok(ParameterKind::Numeric(val))
+                    Ok((*val, None))
+                },
+                TokenKind::Ident(name) => {
+                    let id = self.symbols.function(name).ok_or_else(|| ParseError::UndefinedFunction(tok.clone()))?;
+                    Ok((id, Some(Name { name: name.clone(), binds: false })))
                 },
-                TokenKind::Save => {
-                    Ok(ParameterKind::Saved)
-                }
                 _ => {
-                    Err(ParseError::ExpectedParameter(tok))
+                    Err(ParseError::ExpectedNumber(tok))
                 }
             }
         } else {
@@ -122,11 +368,42 @@ impl Parser {
         }
     }
 
+    /// A cell, written as a number, `$`, a name, or a name being bound as in `counter = 3`.
+    fn expect_cell(&mut self) -> ParseResult<(ParameterKind, Option<Name>)> {
+        let Some(tok) = self.peek() else {
+            return Err(ParseError::UnexpectedEof(self.line, self.col));
+        };
+        let TokenKind::Ident(name) = &tok.kind else {
+            return match self.expect_param()? {
+                (ParameterKind::Numeric(idx), _) if idx < 0 => Err(ParseError::NegativeCell(tok)),
+                param => Ok(param),
+            };
+        };
+        self.next();
+
+        let bound = self.symbols.cell(name);
+        if self.peek().is_some_and(|next| next.kind == TokenKind::CopyTo) {
+            self.expect_token(TokenKind::CopyTo)?;
+            let idx = self.expect_number()?;
+
+            // The symbol table holds the first binding, so any other index is a second one
+            return match bound {
+                Some(first) if first != idx => Err(ParseError::ConflictingCell(tok.clone(), first)),
+                _ => Ok((ParameterKind::Numeric(idx as isize), Some(Name { name: name.clone(), binds: true }))),
+            };
+        }
+
+        match bound {
+            Some(idx) => Ok((ParameterKind::Numeric(idx as isize), Some(Name { name: name.clone(), binds: false }))),
+            None => Err(ParseError::UndefinedCell(tok.clone())),
+        }
+    }
+
     fn expect_token(&mut self, kind: TokenKind) -> ParseResult<()> {
         if let Some(tok) = self.tokens.next() {
             if tok.kind == kind {
                 self.line = tok.loc.line;
-                self.col = tok.loc.col + tok.loc.len - 1;
+                self.col = tok.loc.last_col();
                 Ok(())
             } else {
                 Err(ParseError::ExpectedToken(kind, tok))
@@ -138,8 +415,18 @@ impl Parser {
 
     // digit = "0".."9" ;
     // number = { digit } ;
-
-    // TableStmt = "[", number, "]" ;
+    // letter = "a".."z" | "A".."Z" ;
+    // name = letter, { letter | digit | "_" } ;
+    // FunctionId = number | name ;
+    // Register = "$", [number] ;
+    // Cell = number | Register | name, ["=", number] ;
+    // escape = "\\", ("n" | "t" | "r" | "0" | "\\" | "'") ;
+    // Value = ["-"], number | "'", (escape | ? any character but "'", "\\" or newline ?), "'" ;
+    // Comparison = "!" | "<" | "<=" | ">" | ">=" ;
+    // Condition = [Comparison], Value | Register ;
+
+    // TableOption = "version", "=", ("1" | "2") | "cells", "=", ("native" | "byte") | "tape", "=", ("clamp" | "wrap") ;
+    // TableStmt = "[", number, { ",", TableOption }, "]" ;
     // IncStmt = "+" ;
     // DecStmt = "-" ;
     // MulStmt = "*" ;
@@ -147,32 +434,47 @@ impl Parser {
     // PrintStmt = "." ; 
     // MovRightStmt = ">" ;
     // MovLeftStmt = "<" ;
-    // LoopStmt = "{", Block, "}", ["(", number | "$", ")"] ;
-    // DefStmt = "[", number, "]" ;
-    // IfStmt = "?", "(", number | "$", ")", Block, Eos ;
-    // GotoStmt = "@", "(", number | "$", ")" ;
-    // SaveStmt = "$" ;
+    // LoopStmt = "{", Block, "}", ["(", Condition, ")"] ;
+    // DefStmt = "[", Value, "]" ;
+    // IfStmt = "?", "(", Condition, ")", Block, [":", Block], Eos ;
+    // GotoStmt = "@", "(", Cell, ")" ;
+    // SaveStmt = Register ;
     // PrintAsciiStmt = "&" ;
-    // CopyStmt = "=", "(", number | "$", ")" ;
+    // CopyStmt = "=", "(", Cell, ")" ;
     // ModuloStmt = "%" ;
-    // DefFnStmt = ":", "(", number, ")", Block, Eos ;
-    // CallFnStmt = "^", "(", number, ")" ;
+    // DefFnStmt = ":", "(", FunctionId, ")", ["(", number, ")"], Block, Eos ;
+    // CallFnStmt = "^", "(", FunctionId, ")" ;
     // PrintNewlStmt = "_" ;
     // FlipStmt = "~" ;
+    // InputStmt = "!" ;
+    // BreakStmt = "|" ;
+    // ContinueStmt = "`" ;
+    // ReturnStmt = "^" ; (* not followed by "(" *)
     // Eos = ";" ;
 
+    // LineComment = "#", { ? any character except newline ? } ;
+    // BlockComment = "#|", { BlockComment | ? any character ? }, "|#" ;
+
+    // Expanded before parsing:
+    // MacroName = "\\", name ;
+    // MacroDef = ":", MacroName, ["(", [MacroName, { ",", MacroName }], ")"], Block, Eos ;
+    // MacroCall = MacroName, ["(", [Tokens, { ",", Tokens }], ")"] ;
+    // Include = '"', { ? any character but '"' ? }, '"' ;
+
     // SourceFile = TableStmt, "\n" | "\r", Block ;
     // Block = { Statement } ;
     // Statement = TableStmt | IncStmt | DecStmt | MulStmt | DivStmt | PrintStmt | MovRightStmt | MovLeftStmt | LoopStmt | FlipStmt
-    //             | DefStmt | IfStmt | GotoStmt | SaveStmt | PrintAsciiStmt | CopyStmt | ModuloStmt | DefFnStmt | CallFnStmt | PrintNewlStmt ;
+    //             | DefStmt | IfStmt | GotoStmt | SaveStmt | PrintAsciiStmt | CopyStmt | ModuloStmt | DefFnStmt | CallFnStmt | PrintNewlStmt
+    //             | InputStmt | BreakStmt | ContinueStmt | ReturnStmt ;
 
     fn parse_table_stmt(&mut self) -> ParseResult<Option<Statement>> {
         // Check if there is a table statement
         let table_stmt = if let Some(tok) = self.tokens.peek() {
             match tok.kind {
-                TokenKind::Table(val) => {
+                TokenKind::Table(header) => {
                     // Return the statement
-                    Some(Statement::new(StatementKind::Table(val), tok.clone()))
+                    self.version = header.version;
+                    Some(Statement::new(StatementKind::Table(header), tok.clone()))
                 }
                 _ => {
                     None
@@ -191,75 +493,104 @@ impl Parser {
 
     fn parse_copy_stmt(&mut self, token: Token) -> ParseResult<Statement> {
         // Caller ate the equal sign
-        // CopyStmt = "=", "(", number | "$", ")" ;
+        // CopyStmt = "=", "(", Cell, ")" ;
 
         // "("
         self.expect_token(TokenKind::LParen)?;
 
-        // number | "$"
-        let param = self.expect_param()?;
+        // Cell
+        let (param, name) = self.expect_cell()?;
 
         // ")"
         self.expect_token(TokenKind::RParen)?;
 
-        Ok(Statement::new(StatementKind::Copy(param), token))
+        Ok(Statement::named(StatementKind::Copy(param), token, name))
     }
 
     fn parse_goto_stmt(&mut self, token: Token) -> ParseResult<Statement> {
         // Caller ate the @ sign
-        // GotoStmt = "@", "(", number | "$", ")" ;
+        // GotoStmt = "@", "(", Cell, ")" ;
 
         // "("
         self.expect_token(TokenKind::LParen)?;
 
-        // number | "$"
-        let param = self.expect_param()?;
+        // Cell
+        let (param, name) = self.expect_cell()?;
 
         // ")"
         self.expect_token(TokenKind::RParen)?;
 
-        Ok(Statement::new(StatementKind::Goto(param), token))
+        Ok(Statement::named(StatementKind::Goto(param), token, name))
     }
 
     fn parse_defn_stmt(&mut self, token: Token) -> ParseResult<Statement> {
         // Caller ate the "["
-        // DefStmt = "[", number, "]" ;
-        let num = self.expect_number()?;
+        // DefStmt = "[", Value, "]" ;
+        let (num, name) = self.expect_value()?;
         
         // "]"
         self.expect_token(TokenKind::EndDefine)?;
 
-        Ok(Statement::new(StatementKind::Define(num), token))
+        Ok(Statement::named(StatementKind::Define(num), token, name))
     }
 
     fn parse_call_fn_stmt(&mut self, token: Token) -> ParseResult<Statement> {
         // Caller ate the "^"
-        // CallFnStmt = "^", "(", number, ")" ;
+        // CallFnStmt = "^", "(", FunctionId, ")" ;
+        // ReturnStmt = "^" ; (* not followed by "(" *)
 
+        if !matches!(self.peek(), Some(Token { kind: TokenKind::LParen, .. })) {
+            self.require(&token, Version::V2)?;
+            return Ok(Statement::new(StatementKind::Return, token));
+        }
         self.expect_token(TokenKind::LParen)?;
 
-        let function_id = self.expect_number()?;
+        let (function_id, name) = self.expect_function_id()?;
 
         self.expect_token(TokenKind::RParen)?;
 
-        Ok(Statement::new(StatementKind::CallFn(function_id), token))
+        Ok(Statement::named(StatementKind::CallFn(function_id), token, name))
     }
 
     fn parse_define_fn_stmt(&mut self, token: Token) -> ParseResult<Statement> {
         // Caller ate the ":"
-        // DefFnStmt = ":", "(", number, ")", Block, Eos ;
+        // DefFnStmt = ":", "(", FunctionId, ")", ["(", number, ")"], Block, Eos ;
         // This is not going to be enjoyable
 
         self.expect_token(TokenKind::LParen)?;
 
-        let function_id = self.expect_number()?;
+        let name_token = self.peek();
+        let (function_id, name) = self.expect_function_id()?;
+        if let (Some(name), Some(name_token)) = (&name, name_token) {
+            if !self.defined.insert(name.name.clone()) {
+                return Err(ParseError::DuplicateFunction(name_token));
+            }
+        }
 
         self.expect_token(TokenKind::RParen)?;
 
+        // A second pair of parentheses gives the function a frame with that many locals
+        let locals = match self.peek() {
+            Some(tok @ Token { kind: TokenKind::LParen, .. }) => {
+                self.require(&tok, Version::V2)?;
+                self.expect_token(TokenKind::LParen)?;
+                let locals_token = self.peek();
+                let locals = self.expect_number()?;
+                if locals > MAX_LOCALS {
+                    // It's there, or there'd be no number
+                    return Err(ParseError::TooManyLocals(locals_token.unwrap()));
+                }
+                self.expect_token(TokenKind::RParen)?;
+                Some(locals)
+            },
+            _ => None,
+        };
+
         let mut stmts: Vec<Statement> = Vec::new();
 
         // Parse a whole bunch of statements
         while let Some(tok) = self.peek() {
+            stmts.extend(self.comments_before(tok.loc.start));
             match tok.kind {
                 TokenKind::Eos => {
                     // End of the function
@@ -274,16 +605,17 @@ impl Parser {
 
         self.expect_token(TokenKind::Eos)?;
 
-        Ok(Statement::new(StatementKind::DefineFn(function_id, stmts), token.clone()))
+        Ok(Statement::named(StatementKind::DefineFn(function_id, locals, stmts), token.clone(), name))
     }
 
     fn parse_loop_stmt(&mut self, token: Token) -> ParseResult<Statement> {
         // Caller ate "{"
-        // LoopStmt = "{", Block, "}", ["(", number | "$", ")"] ;
+        // LoopStmt = "{", Block, "}", ["(", Condition, ")"] ;
 
         let mut stmts: Vec<Statement> = Vec::new();
 
         while let Some(tok) = self.peek() {
+            stmts.extend(self.comments_before(tok.loc.start));
             match tok.kind {
                 TokenKind::EndLoop => {
                     // End of the loop
@@ -305,7 +637,7 @@ impl Parser {
                     // Eat paren
                     self.expect_token(TokenKind::LParen)?;
 
-                    let temp_param = self.expect_param()?;
+                    let temp_param = self.expect_condition()?;
 
                     self.expect_token(TokenKind::RParen)?;
 
@@ -318,71 +650,113 @@ impl Parser {
             }
         } else { None };
 
-        Ok(Statement::new(StatementKind::Loop(stmts, param), token))
+        let (param, name) = param.unzip();
+        Ok(Statement::named(StatementKind::Loop(stmts, param), token, name.flatten()))
     }
 
     fn parse_if_stmt(&mut self, token: Token) -> ParseResult<Statement> {
         // Caller ate "?"
-        // IfStmt = "?", "(", number | "$", ")", Block, Eos ;
+        // IfStmt = "?", "(", Condition, ")", Block, [":", Block], Eos ;
 
         self.expect_token(TokenKind::LParen)?;
 
-        let param = self.expect_param()?;
+        let (condition, name) = self.expect_condition()?;
 
         self.expect_token(TokenKind::RParen)?;
 
-        // Parse a block
+        // Parse a block, and then another if it ends with a ":" rather than a ";"
         let mut stmts: Vec<Statement> = Vec::new();
+        let mut else_stmts: Option<Vec<Statement>> = None;
 
         while let Some(tok) = self.peek() {
+            let block = else_stmts.as_mut().unwrap_or(&mut stmts);
+            block.extend(self.comments_before(tok.loc.start));
             match tok.kind {
                 TokenKind::Eos => {
                     // End of the if block
                     // Break and expect EOS.
                     break;
                 },
+                TokenKind::FuncDef => {
+                    let def = self.next().unwrap();
+                    match self.peek().map(|tok| tok.kind) {
+                        Some(TokenKind::LParen) => {
+                            let stmt = self.parse_nested(def, Parser::parse_define_fn_stmt)?;
+                            else_stmts.as_mut().unwrap_or(&mut stmts).push(stmt);
+                        },
+                        // Any other ":" starts the else block, once
+                        _ if else_stmts.is_none() => {
+                            self.require(&def, Version::V2)?;
+                            else_stmts = Some(Vec::new());
+                        },
+                        _ => return Err(ParseError::UnexpectedToken(def)),
+                    }
+                },
                 _ => {
-                    stmts.push(self.parse_stmt()?);
+                    let stmt = self.parse_stmt()?;
+                    else_stmts.as_mut().unwrap_or(&mut stmts).push(stmt);
                 }
             }
         }
 
         self.expect_token(TokenKind::Eos)?;
 
-        Ok(Statement::new(StatementKind::If(param, stmts), token.clone()))
+        Ok(Statement::named(StatementKind::If(condition, stmts, else_stmts), token.clone(), name))
+    }
+
+    /// Parse a statement that contains a block, refusing to recurse without bound.
+    fn parse_nested(&mut self, token: Token, parse: fn(&mut Parser, Token) -> ParseResult<Statement>) -> ParseResult<Statement> {
+        if self.depth >= MAX_NESTING {
+            return Err(ParseError::NestedTooDeeply(token));
+        }
+
+        self.depth += 1;
+        let result = parse(self, token);
+        self.depth -= 1;
+
+        result
+    }
+
+    /// What a single-character instruction parses to, or `None` if `kind` needs more parsing.
+    ///
+    /// These are kept out of `parse_stmt`, which recurses through every nested block, so that
+    /// its stack frame stays small.
+    fn simple_stmt(kind: &TokenKind) -> Option<StatementKind> {
+        let stmt = match kind {
+            TokenKind::Inc => StatementKind::Inc,
+            TokenKind::Dec => StatementKind::Dec,
+            TokenKind::Mul => StatementKind::Mul,
+            TokenKind::Div => StatementKind::Div,
+            TokenKind::MovR => StatementKind::MovR,
+            TokenKind::MovL => StatementKind::MovL,
+            TokenKind::Print => StatementKind::Print,
+            TokenKind::PrintAscii => StatementKind::PrintAscii,
+            TokenKind::Modulo => StatementKind::Modulo,
+            TokenKind::Newline => StatementKind::PrintNewline,
+            TokenKind::Tilde => StatementKind::FlipSign,
+            TokenKind::Bang => StatementKind::Input,
+            TokenKind::Pipe => StatementKind::Break,
+            TokenKind::Backtick => StatementKind::Continue,
+            _ => return None,
+        };
+
+        Some(stmt)
     }
 
     fn parse_stmt(&mut self) -> ParseResult<Statement> {
         if let Some(tok) = self.next() {
+            if let Some(kind) = Parser::simple_stmt(&tok.kind) {
+                return Ok(Statement::new(kind, tok));
+            }
+
             match tok.kind {
-                TokenKind::Table(size) => { 
+                TokenKind::Table(_size) => { 
                     // Should not encounter a table after the first line
                     return Err(ParseError::UnexpectedTableToken(tok.clone())); 
                 },
-                TokenKind::Inc => {
-                    return Ok(Statement::new(StatementKind::Inc, tok));
-                },
-                TokenKind::Dec => {
-                    return Ok(Statement::new(StatementKind::Dec, tok))
-                },
-                TokenKind::Mul => {
-                    return Ok(Statement::new(StatementKind::Mul, tok));
-                },
-                TokenKind::Div => {
-                    return Ok(Statement::new(StatementKind::Div, tok));
-                },
-                TokenKind::MovR => {
-                    return Ok(Statement::new(StatementKind::MovR, tok));
-                },
-                TokenKind::MovL => {
-                    return Ok(Statement::new(StatementKind::MovL, tok));
-                },
-                TokenKind::Print => {
-                    return Ok(Statement::new(StatementKind::Print, tok));
-                },
                 TokenKind::StartLoop => {
                     // Need to parse a loop
-                    return self.parse_loop_stmt(tok.clone());
+                    return self.parse_nested(tok, Parser::parse_loop_stmt);
                 },
                 TokenKind::StartDefine => {
                     // Need to parse a define
@@ -391,50 +765,38 @@ impl Parser {
                 TokenKind::Question => 
                 {
                     // Need to parse an if
-                    return self.parse_if_stmt(tok.clone());
+                    return self.parse_nested(tok, Parser::parse_if_stmt);
+                },
+                TokenKind::Save(register) => {
+                    return Ok(Statement::new(StatementKind::Save(Parser::register(&tok, register)?), tok));
                 },
                 TokenKind::Goto => {
                     // Need to parse a goto
                     return self.parse_goto_stmt(tok.clone());
                 },
-                TokenKind::Save => {
-                    return Ok(Statement::new(StatementKind::Save, tok));
-                },
-                TokenKind::PrintAscii => {
-                    return Ok(Statement::new(StatementKind::PrintAscii, tok));
-                },
                 TokenKind::CopyTo => {
                     // Need to parse a copy
                     return self.parse_copy_stmt(tok.clone());
                 },
-                TokenKind::Modulo => {
-                    return Ok(Statement::new(StatementKind::Modulo, tok));
-                },
                 TokenKind::FuncDef => {
                     // Need to parse a func def
-                    return self.parse_define_fn_stmt(tok.clone());
+                    return self.parse_nested(tok, Parser::parse_define_fn_stmt);
                 },
                 TokenKind::FuncCall => {
                     // Need to parse a func call
                     return self.parse_call_fn_stmt(tok.clone());
                 },
-                TokenKind::Newline => {
-                    return Ok(Statement::new(StatementKind::PrintNewline, tok));
-                },
-                TokenKind::Tilde => {
-                    return Ok(Statement::new(StatementKind::FlipSign, tok));
-                },
                 _ => { 
                     return Err(ParseError::UnexpectedToken(tok));
                  }
             }
         }
 
-        return Err(ParseError::ExpectedStatement(self.line, self.col));
+        Err(ParseError::ExpectedStatement(self.line, self.col))
     }
     
 
-    pub(crate) fn parse_program(&mut self) -> ParseResult<Program> {
+    pub fn parse_program(&mut self) -> ParseResult<Program> {
         let mut statements: Vec<Statement> = Vec::new();
         let mut functions: BTreeMap<usize, Statement> = BTreeMap::new();
         
@@ -445,8 +807,8 @@ impl Parser {
         let table = if let Some(table_stmt) = wrapped_table {
             let table_kind = table_stmt.kind;
             match table_kind {
-                StatementKind::Table(size) => {
-                    Table::new(size)
+                StatementKind::Table(header) => {
+                    Table::from_header(&header)
                 },
                 _ => {
                     Table::new(DEFAULT_TABLE_SIZE)
@@ -457,12 +819,20 @@ impl Parser {
         };
 
         // Continually parse statements
-        while let Some(_) = self.peek() {
+        while let Some(tok) = self.peek() {
+            statements.extend(self.comments_before(tok.loc.start));
             let stmt = self.parse_stmt()?;
 
             // Some statements need special parsing
             match stmt.kind {
-                StatementKind::DefineFn(id, _) => {
+                StatementKind::DefineFn(id, ..) => {
+                    // Files written separately can't know which numbers the others took
+                    if let Some(first) = functions.get(&id) {
+                        let path = |stmt: &Statement| stmt.token.loc.file.as_ref().map(|file| file.path.clone());
+                        if path(first) != path(&stmt) {
+                            return Err(ParseError::ConflictingFunction(stmt.token.clone(), id, Box::new(first.token.clone())));
+                        }
+                    }
                     functions.insert(id, stmt.clone());
                 },
                 _ => {
@@ -474,14 +844,25 @@ impl Parser {
             statements.push(stmt);
         }
 
-        Ok(Program::new(statements, functions, table))
+        // Anything left over trails the last statement
+        statements.extend(self.comments_before(usize::MAX));
+
+        let mut program = Program::new(statements, functions, table);
+        program.symbols = self.symbols.clone();
+        program.version = self.version;
+
+        Ok(program)
     }
 }
 
+#[cfg(test)]
 #[allow(unused_imports)]
 mod tests {
+    use proptest::prelude::*;
+
     use crate::lexer::Lexer;
-    use crate::parser::Parser;
+    use crate::parser::{ParseError, Parser, MAX_NESTING};
+    use crate::program::{with_deep_stack, MAX_LOCALS};
 
     #[test]
     fn test_it_works() {
@@ -496,4 +877,155 @@ mod tests {
 
         println!("{:?}", prog_result.ok().unwrap());
     }
-}
\ No newline at end of file
+
+    fn parse(source: &str) -> Result<crate::program::Program, ParseError> {
+        Parser::new(Lexer::new(source.into()).lex().unwrap().tokens).parse_program()
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        with_deep_stack(|| {
+            let fine = format!("[4]\n{}{}", "{".repeat(MAX_NESTING), "}".repeat(MAX_NESTING));
+            assert!(parse(&fine).is_ok());
+
+            let deep = format!("[4]\n{}{}", "?(0)".repeat(MAX_NESTING + 1), ";".repeat(MAX_NESTING + 1));
+            let err = parse(&deep).err().unwrap();
+            assert!(matches!(err, ParseError::NestedTooDeeply(_)));
+            assert_eq!(err.location(), (2, 4 * MAX_NESTING + 1));
+        });
+    }
+
+    #[test]
+    fn test_names_resolve() {
+        let program = parse("[8]\n^(greet)@(counter)+:(0);:(greet)=(counter = 3)&;^(1)").unwrap();
+
+        // 0 and 1 are already used by number, so the first named function gets 2
+        assert_eq!(program.symbols.function("greet"), Some(2));
+        assert_eq!(program.symbols.cell("counter"), Some(3));
+        assert_eq!(program.statements, parse("[8]\n^(2)@(3)+:(0);:(2)=(3)&;^(1)").unwrap().statements);
+        assert_eq!(program.symbols.to_string(), "function greet = 2\ncell counter = 3\n");
+    }
+
+    #[test]
+    fn test_undefined_names() {
+        assert!(matches!(parse("[8]\n^(nope)"), Err(ParseError::UndefinedFunction(_))));
+        assert!(matches!(parse("[8]\n@(nope)"), Err(ParseError::UndefinedCell(_))));
+        // Names aren't values
+        assert!(matches!(parse("[8]\n@(x = 1)?(x);"), Err(ParseError::ExpectedParameter(_))));
+    }
+
+    #[test]
+    fn test_names_defined_twice() {
+        assert!(matches!(parse("[8]\n:(f);:(f);"), Err(ParseError::DuplicateFunction(_))));
+        assert!(matches!(parse("[8]\n@(x = 1)@(x = 2)"), Err(ParseError::ConflictingCell(_, 1))));
+        // Binding a name to the same cell again is harmless
+        assert!(parse("[8]\n@(x = 1)=(x = 1)").is_ok());
+    }
+
+    #[test]
+    fn test_conditions_and_else() {
+        use crate::ast::{Comparison, Condition, ParameterKind, StatementKind};
+
+        let program = parse("[8]\n?(!3)+:-;{}(<=$)?(>0):(0);:;").unwrap();
+        let kinds: Vec<StatementKind> = program.statements.into_iter().map(|stmt| stmt.kind).collect();
+
+        let condition = |comparison, param| Condition { comparison, param };
+        let StatementKind::If(first, then, Some(otherwise)) = &kinds[0] else { panic!("{:?}", kinds[0]) };
+        assert_eq!(*first, condition(Comparison::NotEqual, ParameterKind::Numeric(3)));
+        assert_eq!((then.len(), otherwise.len()), (1, 1));
+        assert_eq!(kinds[1], StatementKind::Loop(vec![], Some(condition(Comparison::LessEqual, ParameterKind::Saved(0)))));
+        // A ":" followed by "(" is still a function definition, so this else is the second ":"
+        let StatementKind::If(_, then, Some(otherwise)) = &kinds[2] else { panic!("{:?}", kinds[2]) };
+        assert!(matches!(then[0].kind, StatementKind::DefineFn(0, None, _)));
+        assert!(otherwise.is_empty());
+
+        assert!(matches!(parse("[8]\n?(1)+:-:;"), Err(ParseError::UnexpectedToken(_))));
+        assert!(matches!(parse("[8]\n?(<)+;"), Err(ParseError::ExpectedParameter(_))));
+    }
+
+    #[test]
+    fn test_function_frames() {
+        use crate::ast::StatementKind;
+
+        assert!(matches!(parse("[8]\n:(0)()+;"), Err(ParseError::ExpectedNumber(_))));
+
+        let program = parse("[8]\n:(0)(2)+;:(1)(0);:(2)-;").unwrap();
+        let locals: Vec<Option<usize>> = program.functions.values().map(|def| match def.kind {
+            StatementKind::DefineFn(_, locals, _) => locals,
+            _ => unreachable!(),
+        }).collect();
+        assert_eq!(locals, vec![Some(2), Some(0), None]);
+
+        // Locals have to fit in a frame, even in a function that's never called
+        assert!(parse(&format!("[8]\n:(0)({})+;", MAX_LOCALS)).is_ok());
+        let err = parse("[8]\n:(0)(18446744073709551615)+;").err().unwrap();
+        assert!(matches!(err, ParseError::TooManyLocals(_)));
+        assert_eq!(err.location(), (2, 6));
+    }
+
+    #[test]
+    fn test_literals() {
+        use crate::ast::{Comparison, Condition, Name, ParameterKind, StatementKind};
+
+        let program = parse("[8]\n[-3]['A']?(<'a');{}(>=-1)@(2)").unwrap();
+        assert_eq!(program.statements[0].kind, StatementKind::Define(-3));
+        assert_eq!(program.statements[1].kind, StatementKind::Define(65));
+        // Characters remember how they were written, like names do
+        assert_eq!(program.statements[1].name, Some(Name { name: "'A'".into(), binds: false }));
+        assert_eq!(program.statements[2].kind, StatementKind::If(Condition { comparison: Comparison::Less, param: ParameterKind::Numeric(97) }, vec![], None));
+        assert!(matches!(program.statements[3].kind, StatementKind::Loop(_, Some(Condition { param: ParameterKind::Numeric(-1), .. }))));
+
+        assert!(matches!(parse("[8]\n@(-2)"), Err(ParseError::NegativeCell(_))));
+        assert!(matches!(parse("[8]\n[$]"), Err(ParseError::ExpectedNumber(_))));
+        assert!(matches!(parse("[8]\n[18446744073709551615]"), Err(ParseError::ValueOutOfRange(_))));
+        assert!(parse("[8]\n[-9223372036854775808]").is_ok());
+    }
+
+    #[test]
+    fn test_eof_column_counts_characters() {
+        // 'é' is four bytes but three characters, the last of them in column 4
+        assert!(matches!(parse("[8]\n['é'"), Err(ParseError::UnexpectedEof(2, 4))));
+        assert!(matches!(parse("[8]\n?('é')"), Err(ParseError::UnexpectedEof(2, 6))));
+    }
+
+    #[test]
+    fn test_save_registers() {
+        use crate::ast::{ParameterKind, StatementKind};
+
+        let program = parse("[8]\n$ $0 $15 @($3) =($)").unwrap();
+        let kinds: Vec<StatementKind> = program.statements.into_iter().map(|stmt| stmt.kind).collect();
+        assert_eq!(kinds, vec![
+            StatementKind::Save(0), StatementKind::Save(0), StatementKind::Save(15),
+            StatementKind::Goto(ParameterKind::Saved(3)), StatementKind::Copy(ParameterKind::Saved(0)),
+        ]);
+
+        assert!(matches!(parse("[8]\n$16"), Err(ParseError::NoSuchRegister(_))));
+        assert!(matches!(parse("[8]\n?($99999999999999999999999);"), Err(ParseError::NoSuchRegister(_))));
+        // The register has to touch the "$"
+        assert!(matches!(parse("[8]\n$ 1"), Err(ParseError::UnexpectedToken(_))));
+    }
+
+    #[test]
+    fn test_version_one() {
+        use crate::program::{TapePolicy, Version};
+
+        let program = parse("[8, version=1, tape=wrap]\n:(0)+;{^(0)}(3)?($).;").unwrap();
+        assert_eq!(program.version, Version::V1);
+        assert_eq!(program.table.tape, TapePolicy::Wrap);
+
+        for source in ["?(<3);", "{}(>=1)", "?(1)+:-;", ":(0)(2);", ":(0)^;"] {
+            let result = parse(&format!("[8, version=1]\n{}", source));
+            assert!(matches!(result, Err(ParseError::NotInVersion(_, Version::V2))), "{}: {:?}", source, result.err());
+            assert!(parse(&format!("[8]\n{}", source)).is_ok());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_parse_never_panics(source in "(\\[[0-9]{0,3}\\])?[-+*/<>.{}()\\[\\]?@$&=%:^_;~|`'0-9A ]*") {
+            if let Ok(stream) = Lexer::new(source).lex() {
+                let _ = Parser::new(stream.tokens).parse_program();
+            }
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! Filling the table in before a program runs, so a function can be tried on the cells it
+//! expects without a program to set them up first.
+//!
+//! A table can be read from the JSON and CSV forms `dump` writes, so the table one run ends
+//! with can start the next, and single cells and pointers can be set with assignments like
+//! `3=42`. JSON and CSV both only need what they change:
+//!
+//! - JSON is an object with any of `size`, `cursor`, `saved` (up to 16 registers, starting
+//!   from `$`) and `cells`, either an array of values from cell 0 or an object of
+//!   `"index": value`. `width` is accepted and ignored, since the header decides that.
+//! - CSV has a header row naming its columns, of which `index` and `value` are needed and
+//!   `cursor` and `saved` are read if they're there, in the form `dump::csv` writes them.
+//!
+//! Assignments are `index=value`, `cursor=index`, `$=index` or `$n=index`, and `size=n`.
+//! Whatever sets the size is applied before anything else, and growing the table adds zeros.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use serde_json::Value;
+
+use crate::program::{CellWidth, Table, SAVE_REGISTERS};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PreloadError {
+    /// Something couldn't be read, and what was expected instead.
+    Malformed(String),
+    /// A cell set past the end of the table, and the table's size.
+    CellOutOfRange(usize, usize),
+    /// The cursor or a register pointing past the end of the table, where, and the table's size.
+    PointerOutOfRange(String, usize, usize),
+    /// A value a byte cell can't hold, and its cell.
+    TooWide(isize, usize),
+}
+
+impl Display for PreloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreloadError::Malformed(expected) => f.write_fmt(format_args!("Expected {}.", expected)),
+            PreloadError::CellOutOfRange(idx, size) => {
+                f.write_fmt(format_args!("Cell {} is set, but the table only has {} cells.", idx, size))
+            },
+            PreloadError::PointerOutOfRange(what, idx, size) => {
+                f.write_fmt(format_args!("The {} points at cell {}, but the table only has {} cells.", what, idx, size))
+            },
+            PreloadError::TooWide(value, idx) => {
+                f.write_fmt(format_args!("Cell {} is set to {}, but the table's cells are bytes.", idx, value))
+            },
+        }
+    }
+}
+
+type PreloadResult<T> = Result<T, PreloadError>;
+
+/// Everything a file or a list of assignments sets, before it's checked against the table.
+#[derive(Debug, Default)]
+pub struct Preload {
+    pub(crate) size: Option<usize>,
+    pub(crate) cursor: Option<usize>,
+    pub(crate) saved: BTreeMap<usize, usize>,
+    pub(crate) cells: BTreeMap<usize, isize>,
+}
+
+fn malformed<T>(expected: impl Into<String>) -> PreloadResult<T> {
+    Err(PreloadError::Malformed(expected.into()))
+}
+
+fn index(value: &Value, what: &str) -> PreloadResult<usize> {
+    value.as_u64().map(|idx| idx as usize).map_or_else(|| malformed(format!("{} to be a cell index, not {}", what, value)), Ok)
+}
+
+fn cell(value: &Value, idx: usize) -> PreloadResult<isize> {
+    value.as_i64().map(|value| value as isize).map_or_else(|| malformed(format!("cell {} to be an integer, not {}", idx, value)), Ok)
+}
+
+/// `$` or `$n` as a register number.
+fn register(name: &str) -> PreloadResult<usize> {
+    let register = match name.strip_prefix('$') {
+        Some("") => Some(0),
+        Some(number) => number.parse::<usize>().ok(),
+        None => None,
+    };
+    match register {
+        Some(register) if register < SAVE_REGISTERS => Ok(register),
+        _ => malformed(format!("a register from $ to ${}, not '{}'", SAVE_REGISTERS - 1, name)),
+    }
+}
+
+impl Preload {
+    pub(crate) fn from_json(text: &str) -> PreloadResult<Preload> {
+        let value: Value = serde_json::from_str(text).map_err(|err| PreloadError::Malformed(format!("JSON ({})", err)))?;
+        let Some(object) = value.as_object() else { return malformed("a JSON object"); };
+
+        let mut preload = Preload::default();
+        for (key, value) in object {
+            match key.as_str() {
+                "size" => preload.size = Some(index(value, "the size")?),
+                "cursor" => preload.cursor = Some(index(value, "the cursor")?),
+                "saved" => {
+                    let Some(saved) = value.as_array().filter(|saved| saved.len() <= SAVE_REGISTERS) else {
+                        return malformed(format!("saved to be an array of at most {} cell indices", SAVE_REGISTERS));
+                    };
+                    for (register, idx) in saved.iter().enumerate() {
+                        preload.saved.insert(register, index(idx, &format!("${}", register))?);
+                    }
+                },
+                "cells" => match value {
+                    Value::Array(values) => for (idx, value) in values.iter().enumerate() {
+                        preload.cells.insert(idx, cell(value, idx)?);
+                    },
+                    Value::Object(values) => for (idx, value) in values {
+                        let Ok(idx) = idx.parse::<usize>() else { return malformed(format!("cells to be keyed by index, not '{}'", idx)); };
+                        preload.cells.insert(idx, cell(value, idx)?);
+                    },
+                    _ => return malformed("cells to be an array or an object"),
+                },
+                "width" => {},
+                key => return malformed(format!("size, cursor, saved, width or cells, not '{}'", key)),
+            }
+        }
+
+        Ok(preload)
+    }
+
+    pub(crate) fn from_csv(text: &str) -> PreloadResult<Preload> {
+        let mut lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+        let Some((_, header)) = lines.next() else { return malformed("a header row"); };
+        let columns: Vec<&str> = header.split(',').map(str::trim).collect();
+        let column = |name: &str| columns.iter().position(|column| *column == name);
+        let (Some(index_column), Some(value_column)) = (column("index"), column("value")) else {
+            return malformed("index and value columns");
+        };
+
+        let mut preload = Preload::default();
+        for (number, line) in lines {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let field = |column: usize| fields.get(column).copied().unwrap_or_default();
+            let row = |expected: &str| PreloadError::Malformed(format!("{} on line {}", expected, number + 1));
+
+            let idx = field(index_column).parse::<usize>().map_err(|_| row("a cell index"))?;
+            let value = field(value_column).parse::<isize>().map_err(|_| row("an integer value"))?;
+            preload.cells.insert(idx, value);
+
+            match column("cursor").map(field) {
+                Some("true") => preload.cursor = Some(idx),
+                None | Some("false") | Some("") => {},
+                Some(_) => return Err(row("true or false for the cursor")),
+            }
+            for name in column("saved").map(field).unwrap_or_default().split_whitespace() {
+                preload.saved.insert(register(name)?, idx);
+            }
+        }
+
+        Ok(preload)
+    }
+
+    /// Read a file in either form, going by whether it looks like JSON.
+    pub fn from_file(text: &str) -> PreloadResult<Preload> {
+        if text.trim_start().starts_with('{') {
+            Preload::from_json(text)
+        } else {
+            Preload::from_csv(text)
+        }
+    }
+
+    /// Add an assignment like `3=42`, `cursor=2`, `$1=5` or `size=100`.
+    pub fn assign(&mut self, assignment: &str) -> PreloadResult<()> {
+        let Some((target, value)) = assignment.split_once('=') else {
+            return malformed(format!("an assignment like 3=42, not '{}'", assignment));
+        };
+        let (target, value) = (target.trim(), value.trim());
+        let number = || value.parse::<usize>().map_err(|_| PreloadError::Malformed(format!("a cell index after '{}=', not '{}'", target, value)));
+
+        match target {
+            "size" => self.size = Some(number()?),
+            "cursor" => self.cursor = Some(number()?),
+            target if target.starts_with('$') => {
+                self.saved.insert(register(target)?, number()?);
+            },
+            target => {
+                let idx = target.parse::<usize>().map_err(|_| PreloadError::Malformed(format!("a cell, cursor, a register or size before '=', not '{}'", target)))?;
+                let value = value.parse::<isize>().map_err(|_| PreloadError::Malformed(format!("an integer after '{}=', not '{}'", target, value)))?;
+                self.cells.insert(idx, value);
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Apply everything to `table`, resizing it first if a size was given.
+    pub fn apply(&self, table: &mut Table) -> PreloadResult<()> {
+        if let Some(size) = self.size {
+            table.size = size;
+            table.array.resize(size, 0);
+        }
+
+        let in_range = |what: String, idx: usize| match idx < table.size {
+            true => Ok(()),
+            false => Err(PreloadError::PointerOutOfRange(what, idx, table.size)),
+        };
+        for (&idx, &value) in &self.cells {
+            if idx >= table.size {
+                return Err(PreloadError::CellOutOfRange(idx, table.size));
+            }
+            if table.width == CellWidth::Byte && !(0..=255).contains(&value) {
+                return Err(PreloadError::TooWide(value, idx));
+            }
+        }
+        if let Some(cursor) = self.cursor {
+            in_range("cursor".to_string(), cursor)?;
+        }
+        for (&register, &idx) in &self.saved {
+            in_range(if register == 0 { "$ register".to_string() } else { format!("${} register", register) }, idx)?;
+        }
+
+        for (&idx, &value) in &self.cells {
+            table[idx] = value;
+        }
+        if let Some(cursor) = self.cursor {
+            table.curr = cursor;
+        }
+        for (&register, &idx) in &self.saved {
+            table.saved[register] = idx;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dump;
+    use crate::program::{CellWidth, Table};
+
+    use super::{Preload, PreloadError};
+
+    fn table() -> Table {
+        let mut table = Table::new(12);
+        table.array[1] = 42;
+        table.array[4] = -7;
+        table.curr = 4;
+        table.saved[0] = 1;
+        table.saved[3] = 11;
+        table
+    }
+
+    fn loaded(preload: Preload, size: usize) -> Table {
+        let mut table = Table::new(size);
+        preload.apply(&mut table).unwrap();
+        table
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let json = loaded(Preload::from_file(&dump::json(&table()).to_string()).unwrap(), 4);
+        assert_eq!(dump::compact(&json), dump::compact(&table()));
+
+        // CSV doesn't say how big the table is
+        let csv = loaded(Preload::from_file(&dump::csv(&table())).unwrap(), 12);
+        assert_eq!(dump::compact(&csv), dump::compact(&table()));
+    }
+
+    #[test]
+    fn test_assign() {
+        let mut preload = Preload::default();
+        for assignment in ["2=42", "cursor=3", "$=2", "$15=1", "size=6", "5=-1"] {
+            preload.assign(assignment).unwrap();
+        }
+        let table = loaded(preload, 4);
+        assert_eq!(table.array, vec![0, 0, 42, 0, 0, -1]);
+        assert_eq!((table.curr, table.saved[0], table.saved[15]), (3, 2, 1));
+
+        let mut preload = Preload::default();
+        assert_eq!(preload.assign("$16=1"), Err(PreloadError::Malformed("a register from $ to $15, not '$16'".to_string())));
+        assert!(preload.assign("x=1").is_err() && preload.assign("3").is_err() && preload.assign("cursor=-1").is_err());
+    }
+
+    #[test]
+    fn test_rejects() {
+        let apply = |text: &str, width: CellWidth| {
+            let mut table = Table { width, ..Table::new(4) };
+            Preload::from_file(text).and_then(|preload| preload.apply(&mut table)).unwrap_err().to_string()
+        };
+
+        assert_eq!(apply(r#"{"cells": {"4": 1}}"#, CellWidth::Native), "Cell 4 is set, but the table only has 4 cells.");
+        assert_eq!(apply(r#"{"cursor": 2, "saved": [0, 9]}"#, CellWidth::Native), "The $1 register points at cell 9, but the table only has 4 cells.");
+        assert_eq!(apply(r#"{"cells": [1, 300]}"#, CellWidth::Byte), "Cell 1 is set to 300, but the table's cells are bytes.");
+        assert_eq!(apply(r#"{"cells": [1, "a"]}"#, CellWidth::Native), "Expected cell 1 to be an integer, not \"a\".");
+        assert_eq!(apply("index,value\n0,1\n1,x\n", CellWidth::Native), "Expected an integer value on line 3.");
+        assert_eq!(apply("value\n1\n", CellWidth::Native), "Expected index and value columns.");
+    }
+}
@@ -0,0 +1,319 @@
+use std::fmt::Display;
+
+use crate::ast::*;
+use crate::lexer::{LexError, Lexer, LexerOptions, Token, TokenKind};
+use crate::parser::{ParseError, Parser};
+use crate::program::Program;
+
+const INDENT: &str = "    ";
+
+/// Pretty-prints a parsed program back into source that parses to the same AST.
+///
+/// Function definitions get their own lines with an indented body and a closing `;` on its own
+/// line, like `examples/example.mini`. Everything else is run together, since most programs
+/// are long runs of single-character instructions.
+pub struct Printer {
+    out: String,
+    line: String,
+    depth: usize,
+}
+
+impl Printer {
+    pub fn new() -> Printer {
+        Printer { out: String::new(), line: String::new(), depth: 0 }
+    }
+
+    /// Move whatever is on the current line into the output.
+    fn flush(&mut self) {
+        if !self.line.is_empty() {
+            for _ in 0..self.depth {
+                self.out.push_str(INDENT);
+            }
+            self.out.push_str(self.line.trim_end());
+            self.out.push('\n');
+            self.line.clear();
+        }
+    }
+
+    fn push_block(&mut self, stmts: &[Statement]) {
+        for stmt in stmts {
+            self.push_stmt(stmt);
+        }
+    }
+
+    /// A cell parameter, by name if it was written with one.
+    fn cell(stmt: &Statement, param: &ParameterKind) -> String {
+        match &stmt.name {
+            Some(Name { name, binds: true }) => format!("{} = {}", name, param),
+            Some(Name { name, binds: false }) => name.clone(),
+            None => param.to_string(),
+        }
+    }
+
+    /// A function id or value, by name or as a character if it was written as one.
+    fn written(stmt: &Statement, value: impl Display) -> String {
+        match &stmt.name {
+            Some(Name { name, .. }) => name.clone(),
+            None => value.to_string(),
+        }
+    }
+
+    fn push_stmt(&mut self, stmt: &Statement) {
+        match &stmt.kind {
+            StatementKind::Table(size) => self.line.push_str(&format!("[{}]", size)),
+            StatementKind::Inc => self.line.push('+'),
+            StatementKind::Dec => self.line.push('-'),
+            StatementKind::Mul => self.line.push('*'),
+            StatementKind::Div => self.line.push('/'),
+            StatementKind::MovR => self.line.push('>'),
+            StatementKind::MovL => self.line.push('<'),
+            StatementKind::Print => self.line.push('.'),
+            StatementKind::Loop(stmts, param) => {
+                self.line.push('{');
+                self.push_block(stmts);
+                self.line.push('}');
+                if let Some(Condition { comparison, param }) = param {
+                    self.line.push_str(&format!("({}{})", comparison, Printer::written(stmt, param)));
+                }
+            },
+            StatementKind::Define(val) => self.line.push_str(&format!("[{}]", Printer::written(stmt, val))),
+            StatementKind::If(Condition { comparison, param }, stmts, else_stmts) => {
+                self.line.push_str(&format!("?({}{})", comparison, Printer::written(stmt, param)));
+                self.push_block(stmts);
+                if let Some(else_stmts) = else_stmts {
+                    self.line.push(':');
+                    self.push_block(else_stmts);
+                }
+                self.line.push(';');
+            },
+            StatementKind::Goto(param) => self.line.push_str(&format!("@({})", Printer::cell(stmt, param))),
+            StatementKind::Save(register) => self.line.push_str(&ParameterKind::Saved(*register).to_string()),
+            StatementKind::PrintAscii => self.line.push('&'),
+            StatementKind::Copy(param) => self.line.push_str(&format!("=({})", Printer::cell(stmt, param))),
+            StatementKind::Modulo => self.line.push('%'),
+            StatementKind::DefineFn(id, locals, stmts) => {
+                self.flush();
+                self.line.push_str(&format!(":({})", Printer::written(stmt, id)));
+                if let Some(locals) = locals {
+                    self.line.push_str(&format!("({})", locals));
+                }
+                self.flush();
+
+                self.depth += 1;
+                self.push_block(stmts);
+                self.flush();
+                self.depth -= 1;
+
+                self.line.push(';');
+                self.flush();
+            },
+            StatementKind::CallFn(id) => self.line.push_str(&format!("^({})", Printer::written(stmt, id))),
+            StatementKind::PrintNewline => self.line.push('_'),
+            StatementKind::FlipSign => self.line.push('~'),
+            StatementKind::Input => self.line.push('!'),
+            StatementKind::Break => self.line.push('|'),
+            StatementKind::Continue => self.line.push('`'),
+            StatementKind::Return => self.line.push('^'),
+            StatementKind::Comment(text) => {
+                if !self.line.is_empty() {
+                    self.line.push(' ');
+                }
+                self.line.push_str(text);
+                // A line comment runs to the end of the line, so nothing else can follow it
+                if text.starts_with("#|") {
+                    self.line.push(' ');
+                } else {
+                    self.flush();
+                }
+            },
+            StatementKind::End => {},
+        }
+    }
+
+    /// Print a bare list of statements, without a table header.
+    pub(crate) fn print_statements(mut self, stmts: &[Statement]) -> String {
+        self.push_block(stmts);
+        self.flush();
+        self.out
+    }
+
+    pub fn print_program(mut self, program: &Program) -> String {
+        self.out.push_str(&format!("[{}]\n", program.header()));
+        self.print_statements(&program.statements)
+    }
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Printer::new()
+    }
+}
+
+/// Why source couldn't be formatted.
+#[derive(Debug)]
+pub enum FormatError {
+    Lex(LexError),
+    Parse(ParseError),
+    /// A macro call or include, which the printer can't keep.
+    Unsupported(Token),
+}
+
+impl FormatError {
+    /// The line and column the error was found at.
+    pub fn location(&self) -> (usize, usize) {
+        match self {
+            FormatError::Lex(err) => (err.location().line, err.location().col),
+            FormatError::Parse(err) => err.location(),
+            FormatError::Unsupported(token) => (token.loc.line, token.loc.col),
+        }
+    }
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormatError::Lex(err) => f.write_fmt(format_args!("{}", err)),
+            FormatError::Parse(err) => f.write_fmt(format_args!("{}", err)),
+            FormatError::Unsupported(token) => f.write_fmt(format_args!(
+                "'{}' at line {} col {} is a macro call or include, and files with those can't be formatted yet.",
+                token.kind, token.loc.line, token.loc.col
+            )),
+        }
+    }
+}
+
+/// Source formatted the way the printer lays it out, comments and all.
+pub fn format_source(text: &str) -> Result<String, FormatError> {
+    let options = LexerOptions { keep_comments: true, strict: false };
+    let stream = Lexer::with_options(text.to_string(), options).lex().map_err(FormatError::Lex)?;
+
+    // The printer works from the AST, where macros and includes are already expanded, so it
+    // would inline them
+    if let Some(token) = stream.tokens.iter().find(|tok| matches!(tok.kind, TokenKind::Macro(_) | TokenKind::Str(_))) {
+        return Err(FormatError::Unsupported(token.clone()));
+    }
+
+    let program = Parser::with_comments(stream.tokens).parse_program().map_err(FormatError::Parse)?;
+    Ok(Printer::new().print_program(&program))
+}
+
+#[cfg(test)]
+#[allow(unused_imports)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::ast::*;
+    use crate::lexer::{Lexer, LexerOptions, Token, TokenKind, TokenLocation};
+    use crate::parser::Parser;
+    use crate::printer::{format_source, FormatError, Printer};
+    use crate::program::{Program, Table, SAVE_REGISTERS};
+
+    fn stmt(kind: StatementKind) -> Statement {
+        Statement::new(kind, Token::new(TokenKind::Eos, TokenLocation::new(0, 0, 0, 0)))
+    }
+
+    fn param() -> impl Strategy<Value = ParameterKind> {
+        prop_oneof![
+            (-1000isize..1000).prop_map(ParameterKind::Numeric),
+            (0..SAVE_REGISTERS).prop_map(ParameterKind::Saved),
+        ]
+    }
+
+    /// Cells can't be negative, unlike other parameters.
+    fn cell() -> impl Strategy<Value = ParameterKind> {
+        prop_oneof![
+            (0isize..1000).prop_map(ParameterKind::Numeric),
+            (0..SAVE_REGISTERS).prop_map(ParameterKind::Saved),
+        ]
+    }
+
+    fn condition() -> impl Strategy<Value = Condition> {
+        let comparison = prop_oneof![
+            Just(Comparison::Equal),
+            Just(Comparison::NotEqual),
+            Just(Comparison::Less),
+            Just(Comparison::LessEqual),
+            Just(Comparison::Greater),
+            Just(Comparison::GreaterEqual),
+        ];
+        (comparison, param()).prop_map(|(comparison, param)| Condition { comparison, param })
+    }
+
+    fn statement() -> impl Strategy<Value = Statement> {
+        let leaf = prop_oneof![
+            Just(StatementKind::Inc),
+            Just(StatementKind::Dec),
+            Just(StatementKind::Mul),
+            Just(StatementKind::Div),
+            Just(StatementKind::MovR),
+            Just(StatementKind::MovL),
+            Just(StatementKind::Print),
+            (0..SAVE_REGISTERS).prop_map(StatementKind::Save),
+            Just(StatementKind::PrintAscii),
+            Just(StatementKind::Modulo),
+            Just(StatementKind::PrintNewline),
+            Just(StatementKind::FlipSign),
+            Just(StatementKind::Input),
+            Just(StatementKind::Break),
+            Just(StatementKind::Continue),
+            Just(StatementKind::Return),
+            (-1000isize..1000).prop_map(StatementKind::Define),
+            cell().prop_map(StatementKind::Goto),
+            cell().prop_map(StatementKind::Copy),
+            (0usize..16).prop_map(StatementKind::CallFn),
+        ].prop_map(stmt);
+
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            prop_oneof![
+                (prop::collection::vec(inner.clone(), 0..8), prop::option::of(condition()))
+                    .prop_map(|(body, condition)| stmt(StatementKind::Loop(body, condition))),
+                (condition(), prop::collection::vec(inner.clone(), 0..8), prop::option::of(prop::collection::vec(inner.clone(), 0..8)))
+                    .prop_map(|(condition, body, else_body)| stmt(StatementKind::If(condition, body, else_body))),
+                ((0usize..16), prop::option::of(0usize..4), prop::collection::vec(inner, 0..8))
+                    .prop_map(|(id, locals, body)| stmt(StatementKind::DefineFn(id, locals, body))),
+            ]
+        })
+    }
+
+    #[test]
+    fn test_print_example() {
+        let source = "[32]\n:(0)++>?(0)-<;;:(1)&_._;++^(0)^(1)>++^(1)****^(1)";
+        let printed = Printer::new().print_program(&Program::parse_str(source));
+
+        assert_eq!(printed, "[32]\n:(0)\n    ++>?(0)-<;\n;\n:(1)\n    &_._\n;\n++^(0)^(1)>++^(1)****^(1)\n");
+    }
+
+    #[test]
+    fn test_print_keeps_comments() {
+        let source = "[8]\n# header\n+++ # three\n:(0) #| inline |# . ;\n# trailing";
+        let printed = format_source(source).unwrap();
+        assert_eq!(printed, "[8]\n# header\n+++ # three\n:(0)\n    #| inline |# .\n;\n# trailing\n");
+        // The comments don't change what the program means
+        assert_eq!(Program::parse_str(&printed).statements, Program::parse_str(source).statements);
+
+        // Macros and includes would be pasted in, so they're left alone
+        let err = format_source("[8]\n+ \\twice(+) \"lib.mini\"").err().unwrap();
+        assert!(matches!(err, FormatError::Unsupported(_)));
+        assert_eq!(err.location(), (2, 3));
+    }
+
+    #[test]
+    fn test_print_keeps_names() {
+        let source = "[8]\n:(greet)\n    @(counter = 3)&\n;\n^(greet)=(counter)^(0)\n";
+        assert_eq!(Printer::new().print_program(&Program::parse_str(source)), source);
+    }
+
+    proptest! {
+        #[test]
+        fn test_print_round_trips(size in 1usize..4096, stmts in prop::collection::vec(statement(), 0..32)) {
+            let program = Program::new(stmts, Default::default(), Table::new(size));
+            let printed = Printer::new().print_program(&program);
+            let reparsed = Program::parse_str(&printed);
+
+            prop_assert_eq!(reparsed.table.size, size);
+            prop_assert_eq!(&reparsed.statements, &program.statements);
+            // Printing is stable once the source is in printed form
+            prop_assert_eq!(Printer::new().print_program(&reparsed), printed);
+        }
+    }
+}
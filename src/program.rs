@@ -1,56 +1,227 @@
 use std::collections::BTreeMap;
+use std::fmt::Display;
 
 use crate::ast::*;
+use crate::symbols::SymbolTable;
 
 pub(crate) const DEFAULT_TABLE_SIZE: usize = 256;
+/// Most cells every function frame together may take, so runaway recursion stops with an error.
+pub(crate) const MAX_FRAME_CELLS: usize = 1 << 24;
+/// Most locals one function can ask for, which fill its frame along with the caller's cell.
+pub(crate) const MAX_LOCALS: usize = MAX_FRAME_CELLS - 1;
+/// How many cells a program can remember at once, as `$0` (or just `$`) to `$15`.
+pub(crate) const SAVE_REGISTERS: usize = 16;
 
-#[derive(Debug)]
-pub(crate) struct Table {
+/// How much a single cell can hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CellWidth {
+    /// A full `isize`. Arithmetic that would overflow leaves the cell alone.
+    #[default]
+    Native,
+    /// A byte, wrapping around at 0 and 255 like most brainfuck implementations.
+    Byte,
+}
+
+/// What the cursor does at the ends of the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum TapePolicy {
+    /// Stay put at the first cell, and only go past the last into function frames.
+    #[default]
+    Clamp,
+    /// Go around to the other end of the table.
+    Wrap,
+}
+
+/// Which version of the language a file is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Version {
+    /// The original minlang: no comments, names, macros, includes, frames, comparisons, else
+    /// blocks, early exits, literals or extra save registers. Characters those use are ignored,
+    /// like any other character that isn't an instruction.
+    V1,
+    /// Everything this implementation supports. A header without a version means this one, so
+    /// files written for the original need `version=1` to keep their prose as prose.
+    #[default]
+    V2,
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Version::V1 => f.write_fmt(format_args!("1")),
+            Version::V2 => f.write_fmt(format_args!("2")),
+        }
+    }
+}
+
+/// Everything the `[n, ...]` line at the top of a file says: how big the table is and,
+/// optionally, which version of the language the file is written in and how its table behaves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub(crate) size: usize,
+    pub(crate) version: Version,
+    pub(crate) width: CellWidth,
+    pub(crate) tape: TapePolicy,
+}
+
+impl Header {
+    /// A header asking for `size` cells and nothing else.
+    pub(crate) fn new(size: usize) -> Header {
+        Header { size, version: Version::default(), width: CellWidth::default(), tape: TapePolicy::default() }
+    }
+
+    /// Apply a `key=value` option, or say why it can't be.
+    pub(crate) fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match (key, value) {
+            ("version", "1") => self.version = Version::V1,
+            ("version", "2") => self.version = Version::V2,
+            ("cells", "native") => self.width = CellWidth::Native,
+            ("cells", "byte") => self.width = CellWidth::Byte,
+            ("tape", "clamp") => self.tape = TapePolicy::Clamp,
+            ("tape", "wrap") => self.tape = TapePolicy::Wrap,
+            ("version" | "cells" | "tape", _) => return Err(format!("'{}' isn't a {} this implementation knows", value, key)),
+            _ => return Err(format!("there is no '{}' option", key)),
+        }
+
+        Ok(())
+    }
+}
+
+/// The inside of the header, leaving out options that are already the default.
+impl Display for Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}", self.size))?;
+        if self.version != Version::default() {
+            f.write_fmt(format_args!(", version={}", self.version))?;
+        }
+        if self.width == CellWidth::Byte {
+            f.write_fmt(format_args!(", cells=byte"))?;
+        }
+        if self.tape == TapePolicy::Wrap {
+            f.write_fmt(format_args!(", tape=wrap"))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Table {
     pub(crate) array: Vec<isize>,
     pub(crate) size: usize,
     pub(crate) curr: usize,
-    pub(crate) saved: usize,
+    /// The cell each save register points at. They all start at cell 0, where the cursor does,
+    /// so using one before saving anything to it is well defined.
+    pub(crate) saved: [usize; SAVE_REGISTERS],
+    pub width: CellWidth,
+    pub(crate) tape: TapePolicy,
 }
 
 impl std::ops::Index<usize> for Table {
     type Output = isize;
 
     fn index(&self, index: usize) -> &Self::Output {
-        self.array.get(index).expect(format_args!("Index {} is out of bounds for table of size {}.", index, self.array.len()).to_string().as_str())
+        self.array.get(index).unwrap_or_else(|| panic!("Index {} is out of bounds for table of size {}.", index, self.array.len()))
     }
 }
 
 impl std::ops::IndexMut<usize> for Table {
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
         let length = self.array.len();
-        self.array.get_mut(index).expect(format_args!("Index {} is out of bounds for table of size {}.", index, length).to_string().as_str())
+        self.array.get_mut(index).unwrap_or_else(|| panic!("Index {} is out of bounds for table of size {}.", index, length))
     }
 }
 
 impl Table {
-    pub(crate) fn new(size: usize) -> Table {
-        let mut array: Vec<isize> = Vec::new();
-        array.resize(size, 0isize);
+    pub fn new(size: usize) -> Table {
+        let array: Vec<isize> = vec![0; size];
         Table {
             array,
             size,
             curr: 0,
-            saved: usize::MAX,
+            saved: [0; SAVE_REGISTERS],
+            width: CellWidth::Native,
+            tape: TapePolicy::Clamp,
+        }
+    }
+
+    /// A table of the size and behavior a header asks for.
+    pub(crate) fn from_header(header: &Header) -> Table {
+        Table { width: header.width, tape: header.tape, ..Table::new(header.size) }
+    }
+
+    /// Make room for `count` zeroed local cells past the end of the table and any frames already
+    /// there, returning the index of the first. The frame lasts until `pop_frame(base)`.
+    pub(crate) fn push_frame(&mut self, count: usize) -> usize {
+        let base = self.array.len();
+        if (base - self.size).checked_add(count).is_none_or(|cells| cells > MAX_FRAME_CELLS) {
+            panic!("Function frames need more than {} cells; is a function recursing forever?", MAX_FRAME_CELLS);
+        }
+
+        self.array.resize(base + count, 0);
+        base
+    }
+
+    /// Drop the frame starting at `base`, and any pushed after it.
+    pub(crate) fn pop_frame(&mut self, base: usize) {
+        self.array.truncate(base);
+    }
+
+    /// Bring a value into the range a cell can hold.
+    pub(crate) fn fit(&self, value: isize) -> isize {
+        match self.width {
+            CellWidth::Native => value,
+            CellWidth::Byte => value.rem_euclid(256),
         }
     }
 }
 
 #[derive(Debug)]
-pub(crate) struct Program {
-    pub(crate) statements: Vec<Statement>,
+pub struct Program {
+    pub statements: Vec<Statement>,
     pub(crate) functions: BTreeMap<usize, Statement>,
-    pub(crate) table: Table,
-    pub(crate) line: usize,
-    pub(crate) col: usize,
+    pub table: Table,
+    /// Every name the program used and the number it stands for.
+    pub symbols: SymbolTable,
+    /// The version of the language the program was written in.
+    pub(crate) version: Version,
 }
 
 impl Program {
     pub(crate) fn new(statements: Vec<Statement>, functions: BTreeMap<usize, Statement>, table: Table) -> Program {
-        Program { statements, functions, table, line: 1, col: 1 }
+        Program { statements, functions, table, symbols: SymbolTable::default(), version: Version::default() }
+    }
+
+    /// The header that would give this program its table and version.
+    pub(crate) fn header(&self) -> Header {
+        Header { size: self.table.size, version: self.version, width: self.table.width, tape: self.table.tape }
+    }
+
+    /// Lex and parse `source`, for tests that start from a program's text.
+    #[cfg(test)]
+    pub(crate) fn parse_str(source: &str) -> Program {
+        let tokens = crate::lexer::Lexer::new(source.into()).lex().unwrap().tokens;
+        crate::parser::Parser::new(tokens).parse_program().unwrap()
     }
+}
+
+/// A program with most kinds of statement in it, including a function with a frame, a named
+/// goto and a non-default header, for tests of the formats a program is saved in.
+#[cfg(test)]
+pub(crate) const SAMPLE_SOURCE: &str = "[16, cells=byte, tape=wrap]\n:(show)(1) ?(<=-1) ~ : > ['A'] ; & ^ ;\n@(counter = 3) [7] $2 { - ?($2) | ; ` }(!0) =($) ^(show) _ % * / ! . < >";
+
+/// Check that `loaded` would run the same as `program`. Statements compare by shape, so this
+/// doesn't check where they came from.
+#[cfg(test)]
+pub(crate) fn assert_same_program(loaded: &Program, program: &Program) {
+    assert_eq!(loaded.statements, program.statements);
+    assert_eq!(loaded.functions, program.functions);
+    assert_eq!(loaded.header(), program.header());
+}
+
+/// Run `f` on a thread with the stack a program nested `MAX_NESTING` deep needs, which the test
+/// harness's own threads don't have in debug builds.
+#[cfg(test)]
+pub(crate) fn with_deep_stack<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    std::thread::Builder::new().stack_size(64 << 20).spawn(f).unwrap().join().unwrap()
 }
\ No newline at end of file
@@ -0,0 +1,158 @@
+//! Checks on a parsed program that the grammar alone can't express.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use crate::ast::{ParameterKind, Statement, StatementKind};
+use crate::lexer::Token;
+use crate::program::Program;
+
+#[derive(Debug)]
+pub enum SemanticError {
+    BreakOutsideLoop(Token),
+    ContinueOutsideLoop(Token),
+    ReturnOutsideFunction(Token),
+    /// A cell past the end of the table, and how many cells the table has.
+    CellOutOfRange(Token, usize, usize),
+    /// A call to a function id nothing defines.
+    UndefinedFunction(Token, usize),
+}
+
+impl Display for SemanticError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let token = self.token();
+        match self {
+            SemanticError::BreakOutsideLoop(_) | SemanticError::ContinueOutsideLoop(_) => {
+                f.write_fmt(format_args!("'{}' at line {} col {} is not inside a loop.",
+                    token.kind, token.loc.line, token.loc.col
+                ))
+            },
+            SemanticError::ReturnOutsideFunction(_) => {
+                f.write_fmt(format_args!("'{}' at line {} col {} is not inside a function.",
+                    token.kind, token.loc.line, token.loc.col
+                ))
+            },
+            SemanticError::CellOutOfRange(_, idx, size) => {
+                f.write_fmt(format_args!("'{}' at line {} col {} uses cell {}, but the table only has {} cells.",
+                    token.kind, token.loc.line, token.loc.col, idx, size
+                ))
+            },
+            SemanticError::UndefinedFunction(_, id) => {
+                f.write_fmt(format_args!("'{}' at line {} col {} calls function {}, but no function has that id.",
+                    token.kind, token.loc.line, token.loc.col, id
+                ))
+            },
+        }?;
+
+        f.write_str(&token.loc.origin_note())
+    }
+}
+
+impl SemanticError {
+    /// The token the error is about.
+    pub fn token(&self) -> &Token {
+        match self {
+            SemanticError::BreakOutsideLoop(token)
+            | SemanticError::ContinueOutsideLoop(token)
+            | SemanticError::ReturnOutsideFunction(token)
+            | SemanticError::CellOutOfRange(token, ..)
+            | SemanticError::UndefinedFunction(token, _) => token,
+        }
+    }
+}
+
+type SemanticResult<T> = Result<T, SemanticError>;
+
+/// What a statement is nested in, as far as leaving it goes.
+#[derive(Debug, Clone, Copy)]
+struct Context<'a> {
+    in_loop: bool,
+    in_function: bool,
+    /// How many cells the table has.
+    size: usize,
+    /// The functions that can be called.
+    functions: &'a BTreeMap<usize, Statement>,
+}
+
+fn check_block(stmts: &[Statement], context: Context) -> SemanticResult<()> {
+    for stmt in stmts {
+        match &stmt.kind {
+            StatementKind::Goto(ParameterKind::Numeric(idx)) | StatementKind::Copy(ParameterKind::Numeric(idx)) if *idx as usize >= context.size => {
+                return Err(SemanticError::CellOutOfRange(stmt.token.clone(), *idx as usize, context.size));
+            },
+            StatementKind::CallFn(id) if !context.functions.contains_key(id) => {
+                return Err(SemanticError::UndefinedFunction(stmt.token.clone(), *id));
+            },
+            StatementKind::Break if !context.in_loop => return Err(SemanticError::BreakOutsideLoop(stmt.token.clone())),
+            StatementKind::Continue if !context.in_loop => return Err(SemanticError::ContinueOutsideLoop(stmt.token.clone())),
+            StatementKind::Return if !context.in_function => return Err(SemanticError::ReturnOutsideFunction(stmt.token.clone())),
+            StatementKind::Loop(body, _) => check_block(body, Context { in_loop: true, ..context })?,
+            StatementKind::If(_, body, else_body) => {
+                check_block(body, context)?;
+                check_block(else_body.as_deref().unwrap_or_default(), context)?;
+            },
+            // A function body can't break out of the loop it happens to be defined in
+            StatementKind::DefineFn(_, _, body) => check_block(body, Context { in_loop: false, in_function: true, ..context })?,
+            _ => {},
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that every `|` and `` ` `` is inside a loop, every return is inside a function, and
+/// every cell `@` and `=` name is in the table, and every `^(n)` calls a function the program
+/// defines. The table's size is whatever `program.table`
+/// has, which is the header's unless something has resized it since.
+pub fn check(program: &Program) -> SemanticResult<()> {
+    check_block(&program.statements, Context { in_loop: false, in_function: false, size: program.table.size, functions: &program.functions })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::program::Program;
+
+    use super::{check, SemanticError};
+
+    fn check_source(source: &str) -> Result<(), SemanticError> {
+        check(&Program::parse_str(source))
+    }
+
+    #[test]
+    fn test_leaving_blocks() {
+        assert!(check_source("[8]\n{ ?(3) | ; ` }").is_ok());
+        assert!(check_source("[8]\n:(0) { ^ } ?(1) ^ ; ;").is_ok());
+
+        assert!(matches!(check_source("[8]\n?(3) | ;"), Err(SemanticError::BreakOutsideLoop(_))));
+        assert!(matches!(check_source("[8]\n`"), Err(SemanticError::ContinueOutsideLoop(_))));
+        assert!(matches!(check_source("[8]\n{ ^ }"), Err(SemanticError::ReturnOutsideFunction(_))));
+        // Functions are their own scope, wherever they're defined
+        assert!(matches!(check_source("[8]\n{ :(0) | ; }"), Err(SemanticError::BreakOutsideLoop(_))));
+    }
+
+    #[test]
+    fn test_error_location() {
+        let err = check_source("[8]\n+\n  |").unwrap_err();
+        assert_eq!(err.to_string(), "'|' at line 3 col 3 is not inside a loop.");
+    }
+
+    #[test]
+    fn test_cells_in_range() {
+        assert!(check_source("[8]\n@(7) =(0) :(1) ?(9) @(7) ; ;").is_ok());
+
+        let err = check_source("[8]\n@(7)\n:(1) =(8) ;").unwrap_err();
+        assert!(matches!(err, SemanticError::CellOutOfRange(_, 8, 8)));
+        assert_eq!(err.to_string(), "'=' at line 3 col 6 uses cell 8, but the table only has 8 cells.");
+    }
+
+    #[test]
+    fn test_undefined_function() {
+        assert!(check_source("[4]\n^(1) :(1) + ;").is_ok());
+
+        let err = check_source("[4]\n+\n^(5)").unwrap_err();
+        assert!(matches!(err, SemanticError::UndefinedFunction(_, 5)));
+        assert_eq!(err.to_string(), "'^' at line 3 col 1 calls function 5, but no function has that id.");
+        // Only top-level definitions can be called
+        assert!(matches!(check_source("[4]\n{ :(1) + ; } ^(1)"), Err(SemanticError::UndefinedFunction(_, 1))));
+    }
+}
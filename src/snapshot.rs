@@ -0,0 +1,382 @@
+//! Saving a running program's state to a file, to pick up where it left off later.
+//!
+//! A snapshot holds everything the VM needs to carry on: the table, including any function
+//! frames, its cursor and save registers, the next instruction, the calls that haven't returned,
+//! the loops being run and how many steps have run. It doesn't hold the program, just a hash of it, so resuming
+//! needs the same program and refuses any other. What the program printed before the snapshot
+//! isn't kept, and input is read afresh.
+//!
+//! Every integer is little-endian:
+//!
+//! ```text
+//! magic          b"MINS"
+//! format         u16, FORMAT_VERSION
+//! program hash   u64, see `program_hash`
+//! origin         count u32, then each string (u32 length, UTF-8)
+//! steps          u64
+//! pc             u64, u64::MAX once the program is over
+//! table          cells u8 (0 native, 1 byte), tape u8 (0 clamp, 1 wrap), cursor u64,
+//!                SAVE_REGISTERS save registers as u64s, cell count u32, then each cell as an i64
+//! calls          count u32, then per call, outermost first: where it returns to (a target),
+//!                has-frame u8, and if so the frame's base, the caller's cursor and the
+//!                caller's save registers as u64s
+//! loops          count u32, then per loop, outermost first: its instruction and how many calls
+//!                deep it runs as u64s, and what its condition compares against as an i64
+//! ```
+//!
+//! A target is a tag u8 (0 for an instruction, 1 for returning, 2 for the end of the program)
+//! and a u64, the instruction number for tag 0.
+
+use std::fmt::Display;
+use std::io::Write;
+
+use crate::bytecode::{self, put_str, put_u32, put_u64, BytecodeError, Reader};
+use crate::program::{CellWidth, Program, TapePolicy, SAVE_REGISTERS};
+use crate::ast::StatementKind;
+use crate::vm::{Call, Frame, Looping, Target, Vm, MAX_CALL_DEPTH};
+
+pub(crate) const MAGIC: &[u8; 4] = b"MINS";
+/// Bumped whenever the VM state a snapshot holds changes shape. A snapshot only exists to resume
+/// one run, so one from another version is refused rather than converted.
+pub(crate) const FORMAT_VERSION: u16 = 1;
+
+const NO_PC: u64 = u64::MAX;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The file doesn't start with `MAGIC`.
+    NotSnapshot,
+    /// The snapshot was taken by a version of minlang2 that saved different VM state.
+    UnsupportedVersion(u16),
+    /// The file is truncated or corrupt.
+    Unreadable(BytecodeError),
+    /// The hash of the program the snapshot was taken from, and of the one being resumed.
+    WrongProgram(u64, u64),
+    /// The state can't belong to the program, though the hashes match.
+    Mismatch(String),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::NotSnapshot => f.write_fmt(format_args!("Not a minlang2 snapshot file.")),
+            SnapshotError::UnsupportedVersion(version) => f.write_fmt(format_args!(
+                "Snapshot format version {} isn't supported; this version of minlang2 reads version {}.",
+                version, FORMAT_VERSION
+            )),
+            SnapshotError::Unreadable(err) => f.write_fmt(format_args!("{}", err)),
+            SnapshotError::WrongProgram(taken, resumed) => f.write_fmt(format_args!(
+                "The snapshot was taken from a different program (hash {:016x}, but this one's is {:016x}); has it changed since?",
+                taken, resumed
+            )),
+            SnapshotError::Mismatch(why) => f.write_fmt(format_args!("The snapshot doesn't fit the program: {}.", why)),
+        }
+    }
+}
+
+impl From<BytecodeError> for SnapshotError {
+    fn from(err: BytecodeError) -> Self {
+        SnapshotError::Unreadable(err)
+    }
+}
+
+type SnapshotResult<T> = Result<T, SnapshotError>;
+
+fn corrupt<T>(at: usize, why: impl Into<String>) -> SnapshotResult<T> {
+    Err(BytecodeError::Corrupt(at, why.into()).into())
+}
+
+/// A hash of everything about a program that affects how it runs, which is what its bytecode
+/// without a debug table holds. It's 64-bit FNV-1a, so it's the same on every machine.
+pub(crate) fn program_hash(program: &Program) -> u64 {
+    bytecode::write(program, false).iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub(crate) program_hash: u64,
+    /// Whatever the taker needs to find the program again, like the command line that ran it.
+    pub origin: Vec<String>,
+    pub(crate) steps: u64,
+    pub(crate) pc: Option<usize>,
+    pub(crate) calls: Vec<Call>,
+    pub(crate) loops: Vec<Looping>,
+    pub(crate) array: Vec<isize>,
+    pub(crate) curr: usize,
+    pub(crate) saved: [usize; SAVE_REGISTERS],
+    pub(crate) width: CellWidth,
+    pub(crate) tape: TapePolicy,
+}
+
+fn put_target(out: &mut Vec<u8>, target: Target) {
+    let (tag, pc) = match target {
+        Target::At(pc) => (0, pc),
+        Target::Return => (1, 0),
+        Target::Exit => (2, 0),
+    };
+    out.push(tag);
+    put_u64(out, pc);
+}
+
+fn put_registers(out: &mut Vec<u8>, saved: &[usize; SAVE_REGISTERS]) {
+    for idx in saved {
+        put_u64(out, *idx);
+    }
+}
+
+fn target(reader: &mut Reader) -> SnapshotResult<Target> {
+    let at = reader.pos;
+    let tag = reader.u8("a call")?;
+    let pc = reader.usize("a call")?;
+    match tag {
+        0 => Ok(Target::At(pc)),
+        1 => Ok(Target::Return),
+        2 => Ok(Target::Exit),
+        other => corrupt(at, format!("there's no return target kind {}", other)),
+    }
+}
+
+fn registers(reader: &mut Reader) -> SnapshotResult<[usize; SAVE_REGISTERS]> {
+    let mut saved = [0; SAVE_REGISTERS];
+    for idx in saved.iter_mut() {
+        *idx = reader.usize("the save registers")?;
+    }
+
+    Ok(saved)
+}
+
+impl Snapshot {
+    /// The state `vm` is in right now.
+    pub fn take<W: Write>(vm: &Vm<W>, origin: Vec<String>) -> Snapshot {
+        let table = &vm.program.table;
+        Snapshot {
+            program_hash: program_hash(&vm.program),
+            origin,
+            steps: vm.steps,
+            pc: vm.pc,
+            calls: vm.calls.clone(),
+            loops: vm.loops.clone(),
+            array: table.array.clone(),
+            curr: table.curr,
+            saved: table.saved,
+            width: table.width,
+            tape: table.tape,
+        }
+    }
+
+    /// Put `vm`, which should have just been made from the same program, back in this state.
+    pub fn restore<W: Write>(self, vm: &mut Vm<W>) -> SnapshotResult<()> {
+        let hash = program_hash(&vm.program);
+        if hash != self.program_hash {
+            return Err(SnapshotError::WrongProgram(self.program_hash, hash));
+        }
+
+        // The hashes match, so anything out of place means the file was tampered with
+        let mismatch = |why: String| Err(SnapshotError::Mismatch(why));
+        let (size, cells, instructions) = (vm.program.table.size, self.array.len(), vm.code.instructions.len());
+        if cells < size {
+            return mismatch(format!("it has {} cells, but the table has {}", cells, size));
+        }
+        if let Some(idx) = std::iter::once(&self.curr).chain(&self.saved).find(|idx| **idx >= cells) {
+            return mismatch(format!("cell {} is past the end of the table", idx));
+        }
+        if let Some(pc) = self.pc.filter(|pc| *pc >= instructions) {
+            return mismatch(format!("instruction {} is past the end of the program", pc));
+        }
+        if self.calls.len() > MAX_CALL_DEPTH {
+            return mismatch(format!("calls are nested more than {} deep", MAX_CALL_DEPTH));
+        }
+
+        let mut frames_end = cells;
+        for call in self.calls.iter().rev() {
+            if let Target::At(pc) = call.return_to {
+                if pc >= instructions {
+                    return mismatch(format!("a call returns to instruction {}, past the end of the program", pc));
+                }
+            }
+            if let Some(Frame { base, curr, saved }) = &call.frame {
+                // Frames are stacked past the table, each caller's before its callee's
+                if *base < size || *base >= frames_end || std::iter::once(curr).chain(saved).any(|idx| idx >= base) {
+                    return mismatch(format!("a function frame at cell {} is out of place", base));
+                }
+                frames_end = *base;
+            }
+        }
+
+        // Loops are entered outermost first, so none runs in fewer calls than the one around it
+        let mut depth = 0;
+        for looping in &self.loops {
+            if !matches!(vm.code.instructions.get(looping.pc), Some(instruction) if matches!(instruction.kind, StatementKind::Loop(..))) {
+                return mismatch(format!("instruction {} isn't a loop", looping.pc));
+            }
+            if looping.depth < depth || looping.depth > self.calls.len() {
+                return mismatch(format!("the loop at instruction {} is out of place", looping.pc));
+            }
+            depth = looping.depth;
+        }
+
+        let table = &mut vm.program.table;
+        table.array = self.array;
+        table.curr = self.curr;
+        table.saved = self.saved;
+        table.width = self.width;
+        table.tape = self.tape;
+        vm.pc = self.pc;
+        vm.calls = self.calls;
+        vm.loops = self.loops;
+        vm.steps = self.steps;
+
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.program_hash.to_le_bytes());
+
+        put_u32(&mut out, self.origin.len());
+        for arg in &self.origin {
+            put_str(&mut out, arg);
+        }
+        out.extend_from_slice(&self.steps.to_le_bytes());
+        out.extend_from_slice(&self.pc.map_or(NO_PC, |pc| pc as u64).to_le_bytes());
+
+        out.push(match self.width { CellWidth::Native => 0, CellWidth::Byte => 1 });
+        out.push(match self.tape { TapePolicy::Clamp => 0, TapePolicy::Wrap => 1 });
+        put_u64(&mut out, self.curr);
+        put_registers(&mut out, &self.saved);
+        put_u32(&mut out, self.array.len());
+        for cell in &self.array {
+            out.extend_from_slice(&(*cell as i64).to_le_bytes());
+        }
+
+        put_u32(&mut out, self.calls.len());
+        for call in &self.calls {
+            put_target(&mut out, call.return_to);
+            out.push(call.frame.is_some() as u8);
+            if let Some(Frame { base, curr, saved }) = &call.frame {
+                put_u64(&mut out, *base);
+                put_u64(&mut out, *curr);
+                put_registers(&mut out, saved);
+            }
+        }
+
+        put_u32(&mut out, self.loops.len());
+        for looping in &self.loops {
+            put_u64(&mut out, looping.pc);
+            put_u64(&mut out, looping.depth);
+            out.extend_from_slice(&(looping.against as i64).to_le_bytes());
+        }
+
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> SnapshotResult<Snapshot> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(SnapshotError::NotSnapshot);
+        }
+        let mut reader = Reader { bytes, pos: MAGIC.len() };
+
+        match reader.u16("the format version")? {
+            FORMAT_VERSION => {},
+            version => return Err(SnapshotError::UnsupportedVersion(version)),
+        }
+        let program_hash = reader.u64("the program hash")?;
+
+        let origin = (0..reader.u32("the origin")?).map(|_| reader.str("the origin")).collect::<Result<_, _>>()?;
+        let steps = reader.u64("the step count")?;
+        let pc = match reader.u64("the next instruction")? {
+            NO_PC => None,
+            pc => Some(usize::try_from(pc).unwrap_or(usize::MAX)),
+        };
+
+        let at = reader.pos;
+        let width = match reader.u8("the cell model")? {
+            0 => CellWidth::Native,
+            1 => CellWidth::Byte,
+            other => return corrupt(at, format!("there's no cell model {}", other)),
+        };
+        let at = reader.pos;
+        let tape = match reader.u8("the tape policy")? {
+            0 => TapePolicy::Clamp,
+            1 => TapePolicy::Wrap,
+            other => return corrupt(at, format!("there's no tape policy {}", other)),
+        };
+        let curr = reader.usize("the cursor")?;
+        let saved = registers(&mut reader)?;
+        let array = (0..reader.u32("the table")?).map(|_| reader.isize("the table")).collect::<Result<_, _>>()?;
+
+        let mut calls = Vec::new();
+        for _ in 0..reader.u32("the calls")? {
+            let return_to = target(&mut reader)?;
+            let frame = match reader.flag("a call")? {
+                true => Some(Frame { base: reader.usize("a call")?, curr: reader.usize("a call")?, saved: registers(&mut reader)? }),
+                false => None,
+            };
+            calls.push(Call { return_to, frame });
+        }
+        let mut loops = Vec::new();
+        for _ in 0..reader.u32("the loops")? {
+            loops.push(Looping { pc: reader.usize("a loop")?, depth: reader.usize("a loop")?, against: reader.isize("a loop")? });
+        }
+
+        if reader.pos != bytes.len() {
+            return corrupt(reader.pos, "there's more after the end of the snapshot");
+        }
+
+        Ok(Snapshot { program_hash, origin, steps, pc, calls, loops, array, curr, saved, width, tape })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::program::Program;
+    use crate::vm::Vm;
+
+    use super::{Snapshot, SnapshotError};
+
+    /// Counts down from 5, printing through a function with a frame.
+    const SOURCE: &str = "[4]\n:(1)(1) > [2] < . ; [5] { ^(1) - }";
+
+    #[test]
+    fn test_resume() {
+        let mut whole = Vm::with_output(Program::parse_str(SOURCE), Vec::new());
+        whole.run(false);
+
+        // Stop inside a call, so the snapshot has a frame in it
+        let mut first = Vm::with_output(Program::parse_str(SOURCE), Vec::new());
+        while first.step() && (first.calls.is_empty() || first.steps < 12) {}
+        assert!(!first.calls.is_empty());
+        let snapshot = Snapshot::take(&first, vec!["prog.mini".to_string()]);
+        let bytes = snapshot.to_bytes();
+        assert_eq!(Snapshot::from_bytes(&bytes).unwrap(), snapshot);
+
+        let mut second = Vm::with_output(Program::parse_str(SOURCE), Vec::new());
+        Snapshot::from_bytes(&bytes).unwrap().restore(&mut second).unwrap();
+        second.run(false);
+
+        first.output.extend(second.output);
+        assert_eq!(first.output, whole.output);
+        assert_eq!(second.program.table.array, whole.program.table.array);
+        assert_eq!(second.steps, whole.steps);
+    }
+
+    #[test]
+    fn test_rejects() {
+        let mut vm = Vm::with_output(Program::parse_str(SOURCE), Vec::new());
+        vm.step();
+        let bytes = Snapshot::take(&vm, Vec::new()).to_bytes();
+
+        let mut other = Vm::with_output(Program::parse_str("[4]\n+"), Vec::new());
+        assert!(matches!(Snapshot::from_bytes(&bytes).unwrap().restore(&mut other), Err(SnapshotError::WrongProgram(..))));
+
+        for len in 0..bytes.len() {
+            assert!(Snapshot::from_bytes(&bytes[..len]).is_err());
+        }
+
+        let mut moved = Snapshot::from_bytes(&bytes).unwrap();
+        moved.curr = 100;
+        let mut same = Vm::with_output(Program::parse_str(SOURCE), Vec::new());
+        assert!(matches!(moved.restore(&mut same), Err(SnapshotError::Mismatch(_))));
+    }
+}
@@ -0,0 +1,111 @@
+//! Names for functions and cells.
+//!
+//! Anywhere a function id or a cell index goes, a name can be written instead, e.g.
+//! `:(print_digit)` or `@(counter)`. Functions are numbered in the order they're defined,
+//! skipping any id the program already uses by number. Cells have no natural numbering, so a
+//! cell name is bound once with `@(counter = 3)` or `=(counter = 3)` and can be used anywhere
+//! in the program after that, or before.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+
+use crate::lexer::{Token, TokenKind};
+
+/// Every function and cell name in a program, and the number each stands for.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    pub(crate) functions: BTreeMap<String, usize>,
+    pub(crate) cells: BTreeMap<String, usize>,
+}
+
+impl SymbolTable {
+    /// Assign every name in a token stream its number, ahead of parsing so that names can be
+    /// used before they're defined.
+    ///
+    /// This never fails: if a name is defined twice, the first definition wins here and the
+    /// parser reports the second when it gets there.
+    pub(crate) fn collect(tokens: &[Token]) -> SymbolTable {
+        let mut symbols = SymbolTable::default();
+        let kinds: Vec<&TokenKind> = tokens.iter().map(|tok| &tok.kind).collect();
+
+        // Function ids written as numbers are taken
+        let taken: BTreeSet<usize> = kinds.windows(3).filter_map(|window| match window {
+            [TokenKind::FuncDef | TokenKind::FuncCall, TokenKind::LParen, TokenKind::Number(id)] => Some(*id),
+            _ => None,
+        }).collect();
+        let mut next_id = 0usize;
+
+        for at in 0..kinds.len() {
+            match &kinds[at..] {
+                [TokenKind::FuncDef, TokenKind::LParen, TokenKind::Ident(name), ..] => {
+                    if symbols.functions.contains_key(name) {
+                        continue;
+                    }
+                    while taken.contains(&next_id) {
+                        next_id += 1;
+                    }
+                    symbols.functions.insert(name.clone(), next_id);
+                    next_id += 1;
+                },
+                [TokenKind::Goto | TokenKind::CopyTo, TokenKind::LParen, TokenKind::Ident(name), TokenKind::CopyTo, TokenKind::Number(idx), ..] => {
+                    symbols.cells.entry(name.clone()).or_insert(*idx);
+                },
+                _ => {},
+            }
+        }
+
+        symbols
+    }
+
+    /// The id of the function with this name, if the program defined one.
+    pub fn function(&self, name: &str) -> Option<usize> {
+        self.functions.get(name).copied()
+    }
+
+    /// The cell this name was bound to, if the program bound it.
+    pub fn cell(&self, name: &str) -> Option<usize> {
+        self.cells.get(name).copied()
+    }
+}
+
+/// A listing of every name and the number it was given.
+impl Display for SymbolTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, id) in &self.functions {
+            f.write_fmt(format_args!("function {} = {}\n", name, id))?;
+        }
+        for (name, idx) in &self.cells {
+            f.write_fmt(format_args!("cell {} = {}\n", name, idx))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::Lexer;
+
+    use super::SymbolTable;
+
+    fn collect(source: &str) -> SymbolTable {
+        SymbolTable::collect(&Lexer::new(source.into()).lex().unwrap().tokens)
+    }
+
+    #[test]
+    fn test_functions_skip_taken_ids() {
+        let symbols = collect("[8]\n:(a);^(1):(b);:(0);:(c);:(a);");
+
+        assert_eq!(symbols.function("a"), Some(2));
+        assert_eq!(symbols.function("b"), Some(3));
+        assert_eq!(symbols.function("c"), Some(4));
+    }
+
+    #[test]
+    fn test_first_cell_binding_wins() {
+        let symbols = collect("[8]\n@(x)=(x = 4)@(x = 5)");
+
+        assert_eq!(symbols.cell("x"), Some(4));
+        assert_eq!(symbols.cell("y"), None);
+    }
+}
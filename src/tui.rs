@@ -0,0 +1,322 @@
+//! A live view of a program running in the terminal.
+//!
+//! The tape is drawn as a strip of cells that scrolls to keep the cursor in view, with the
+//! cursor and the cell `$` points at picked out. Beside it are the program's output so far and
+//! its source, with the statement about to run highlighted. The program can be played at a
+//! range of speeds, paused, and stepped one statement at a time.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::Frame;
+
+use crate::disasm;
+use crate::program::Program;
+use crate::vm::Vm;
+
+/// How many steps a second each speed runs.
+pub(crate) const SPEEDS: [u64; 10] = [1, 2, 5, 10, 25, 50, 100, 1_000, 10_000, 100_000];
+
+/// How long to wait between redraws while playing.
+const FRAME: Duration = Duration::from_millis(30);
+
+const KEYS: &str = "space play/pause  s step  +/- speed  q quit";
+
+pub struct Tui {
+    pub(crate) vm: Vm<Vec<u8>>,
+    /// The lines of the program's source, if we have it.
+    source: Option<Vec<String>>,
+    /// The lines of included files, read the first time they're shown.
+    included: HashMap<PathBuf, Option<Vec<String>>>,
+    pub(crate) playing: bool,
+    /// Which of `SPEEDS` to play at.
+    pub(crate) speed: usize,
+    /// Steps owed from time that went by between frames, for speeds slower than a frame.
+    owed: f64,
+}
+
+fn lines(text: &str) -> Vec<String> {
+    text.lines().map(str::to_string).collect()
+}
+
+impl Tui {
+    pub fn new(program: Program, input: impl Read + 'static, source: Option<&str>) -> Tui {
+        Tui { vm: Vm::with_io(program, input, Vec::new()), source: source.map(lines), included: HashMap::new(), playing: false, speed: 3, owed: 0.0 }
+    }
+
+    /// Act on a key. Returns whether to keep going.
+    pub(crate) fn key(&mut self, code: KeyCode) -> bool {
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char(' ') => self.playing = !self.playing && self.vm.pc.is_some(),
+            KeyCode::Char('s') | KeyCode::Right => {
+                self.playing = false;
+                self.vm.step();
+            },
+            KeyCode::Char('+') | KeyCode::Char('=') | KeyCode::Up => self.speed = (self.speed + 1).min(SPEEDS.len() - 1),
+            KeyCode::Char('-') | KeyCode::Down => self.speed = self.speed.saturating_sub(1),
+            _ => {},
+        }
+
+        true
+    }
+
+    /// Run as many steps as playing for `elapsed` at the current speed calls for.
+    pub(crate) fn tick(&mut self, elapsed: Duration) {
+        if !self.playing {
+            self.owed = 0.0;
+            return;
+        }
+
+        // Never fall more than a few frames behind, so a slow terminal can't pile up steps
+        let rate = SPEEDS[self.speed] as f64;
+        self.owed = (self.owed + rate * elapsed.as_secs_f64()).min((rate * FRAME.as_secs_f64() * 4.0).max(1.0));
+        while self.owed >= 1.0 {
+            self.owed -= 1.0;
+            if !self.vm.step() {
+                self.playing = false;
+                break;
+            }
+        }
+    }
+
+    pub(crate) fn render(&mut self, frame: &mut Frame) {
+        let [top, tape, status] = Layout::vertical([Constraint::Min(3), Constraint::Length(5), Constraint::Length(1)]).areas(frame.area());
+        let [source, output] = Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(top);
+
+        let source_view = self.source_view(source);
+        frame.render_widget(source_view, source);
+        frame.render_widget(self.output_view(output), output);
+        frame.render_widget(self.tape_view(tape), tape);
+        frame.render_widget(Paragraph::new(self.status()), status);
+    }
+
+    fn status(&self) -> String {
+        let state = match (self.vm.pc, self.playing) {
+            (None, _) => "finished",
+            (_, true) => "playing",
+            (_, false) => "paused",
+        };
+        format!("step {}  {}  {} steps/s    {}", self.vm.steps, state, SPEEDS[self.speed], KEYS)
+    }
+
+    /// The lines holding the statement about to run, and its line and columns in them.
+    fn current_lines(&mut self) -> Option<(&[String], usize, usize, usize)> {
+        let instruction = &self.vm.code.instructions[self.vm.pc?];
+        let loc = &instruction.loc;
+        let lines = match &loc.file {
+            None => self.source.as_ref()?,
+            Some(file) => self.included.entry(file.path.clone()).or_insert_with(|| fs::read_to_string(&file.path).ok().map(|text| lines(&text))).as_ref()?,
+        };
+
+        let start = loc.col.saturating_sub(1);
+        Some((lines, loc.line.checked_sub(1)?, start, start + (loc.end - loc.start).max(1)))
+    }
+
+    fn source_view(&mut self, area: Rect) -> Paragraph<'static> {
+        let height = area.height.saturating_sub(2) as usize;
+        let highlight = Style::new().fg(Color::Black).bg(Color::Yellow).add_modifier(Modifier::BOLD);
+        let current_line = Style::new().bg(Color::DarkGray);
+
+        let title = match self.vm.pc.map(|pc| self.vm.code.instructions[pc].loc.clone()) {
+            Some(loc) => match &loc.file {
+                Some(file) => format!(" {} line {} ", file.path.display(), loc.line),
+                None => format!(" Source line {} ", loc.line),
+            },
+            None => " Source ".to_string(),
+        };
+        let block = Block::bordered().title(title);
+
+        let Some((lines, line, start, end)) = self.current_lines() else {
+            // Without the source, list the instructions instead
+            let pc = self.vm.pc;
+            let first = pc.unwrap_or(0).saturating_sub(height / 2);
+            let text: Vec<Line> = self.vm.code.instructions.iter().enumerate().skip(first).take(height).map(|(index, instruction)| {
+                let text = format!("{:>5}  {}", index, disasm::text(&instruction.kind));
+                if Some(index) == pc { Line::styled(text, highlight) } else { Line::raw(text) }
+            }).collect();
+            return Paragraph::new(text).block(block);
+        };
+
+        let first = line.saturating_sub(height / 2);
+        let text: Vec<Line> = lines.iter().enumerate().skip(first).take(height).map(|(index, text)| {
+            if index != line {
+                return Line::raw(text.clone());
+            }
+            let chars: Vec<char> = text.chars().collect();
+            let part = |from: usize, to: usize| chars[from.min(chars.len())..to.min(chars.len())].iter().collect::<String>();
+            Line::from(vec![
+                Span::raw(part(0, start)),
+                Span::styled(part(start, end), highlight),
+                Span::raw(part(end, chars.len())),
+            ]).style(current_line)
+        }).collect();
+
+        Paragraph::new(text).block(block)
+    }
+
+    fn output_view(&self, area: Rect) -> Paragraph<'static> {
+        let text = String::from_utf8_lossy(&self.vm.output).into_owned();
+        let lines: Vec<Line> = text.split('\n').map(|line| Line::raw(line.to_string())).collect();
+        let scroll = lines.len().saturating_sub(area.height.saturating_sub(2) as usize);
+
+        Paragraph::new(lines).block(Block::bordered().title(" Output ")).scroll((scroll as u16, 0))
+    }
+
+    fn tape_view(&self, area: Rect) -> Paragraph<'static> {
+        let table = &self.vm.program.table;
+        let width = |idx: usize| idx.to_string().len().max(table[idx].to_string().len()) + 2;
+
+        // Start far enough left that the cursor sits in the middle, if there's that much tape
+        let room = area.width.saturating_sub(2) as usize;
+        let mut first = table.curr;
+        let mut used = width(table.curr) / 2;
+        while first > 0 && used + width(first - 1) <= room / 2 {
+            first -= 1;
+            used += width(first);
+        }
+
+        let (mut indices, mut values, mut markers) = (Vec::new(), Vec::new(), Vec::new());
+        let mut used = 0;
+        for idx in first..table.array.len() {
+            let width = width(idx);
+            if used + width > room {
+                break;
+            }
+            used += width;
+
+            // Cells past the end of the table belong to function frames
+            let index_style = if idx < table.size { Style::new().fg(Color::DarkGray) } else { Style::new().fg(Color::Magenta) };
+            let value_style = if idx == table.curr {
+                Style::new().fg(Color::Black).bg(Color::Yellow).add_modifier(Modifier::BOLD)
+            } else if idx == table.saved[0] {
+                Style::new().fg(Color::Black).bg(Color::Cyan)
+            } else {
+                Style::new()
+            };
+            let registers: Vec<String> = table.saved.iter().enumerate().filter(|(_, saved)| **saved == idx).map(|(register, _)| {
+                if register == 0 { "$".to_string() } else { format!("${}", register) }
+            }).collect();
+            let marker = match (idx == table.curr, registers.first()) {
+                (true, _) => "^".to_string(),
+                (false, Some(register)) => register.clone(),
+                (false, None) => String::new(),
+            };
+
+            indices.push(Span::styled(format!("{:^width$}", idx, width = width), index_style));
+            values.push(Span::styled(format!("{:^width$}", table[idx], width = width), value_style));
+            markers.push(Span::styled(format!("{:^width$}", marker, width = width), Style::new().fg(Color::Cyan)));
+        }
+
+        let title = format!(" Tape  cursor {}  $ {} ", table.curr, table.saved[0]);
+        Paragraph::new(vec![Line::from(indices), Line::from(values), Line::from(markers)]).block(Block::bordered().title(title))
+    }
+}
+
+/// Take over the terminal and show `tui` until it's quit.
+pub fn run(mut tui: Tui) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let mut last = Instant::now();
+
+    let result = loop {
+        if let Err(err) = terminal.draw(|frame| tui.render(frame)) {
+            break Err(err);
+        }
+
+        // Wait for a key for as long as a frame lasts, or for good while paused
+        let timeout = if tui.playing { FRAME.saturating_sub(last.elapsed()) } else { Duration::from_secs(60) };
+        match event::poll(timeout) {
+            Ok(true) => match event::read() {
+                Ok(Event::Key(key)) if key.kind == KeyEventKind::Press && !tui.key(key.code) => break Ok(()),
+                Ok(_) => {},
+                Err(err) => break Err(err),
+            },
+            Ok(false) => {},
+            Err(err) => break Err(err),
+        }
+
+        let now = Instant::now();
+        tui.tick(now - last);
+        last = now;
+    };
+
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ratatui::backend::TestBackend;
+    use ratatui::crossterm::event::KeyCode;
+    use ratatui::style::Color;
+    use ratatui::Terminal;
+
+    use crate::program::Program;
+
+    use super::{Tui, SPEEDS};
+
+    fn screen(tui: &mut Tui) -> (Vec<String>, ratatui::buffer::Buffer) {
+        let mut terminal = Terminal::new(TestBackend::new(60, 12)).unwrap();
+        terminal.draw(|frame| tui.render(frame)).unwrap();
+        let buffer = terminal.backend().buffer().clone();
+        let rows = (0..buffer.area.height).map(|y| (0..buffer.area.width).map(|x| buffer[(x, y)].symbol()).collect()).collect();
+        (rows, buffer)
+    }
+
+    #[test]
+    fn test_render() {
+        let source = "[4]\n[7] $ > [12] . <\n_";
+        let program = Program::parse_str(source);
+        let mut tui = Tui::new(program, std::io::empty(), Some(source));
+        for _ in 0..4 {
+            tui.key(KeyCode::Char('s'));
+        }
+
+        let (rows, buffer) = screen(&mut tui);
+        assert!(rows[0].contains("Source line 2"), "{:?}", rows);
+        assert!(rows[2].contains("[7] $ > [12] . <"), "{:?}", rows);
+        assert!(rows[6].contains("Tape  cursor 1  $ 0"), "{:?}", rows);
+        assert!(rows[7].starts_with("│ 0  1   2  3 "), "{:?}", rows);
+        assert!(rows[8].starts_with("│ 7  12  0  0 "), "{:?}", rows);
+        assert!(rows[9].starts_with("│ $  ^ "), "{:?}", rows);
+        assert!(rows[11].starts_with("step 4  paused"), "{:?}", rows);
+
+        // The print is next, and the cursor is on the 12
+        let print = rows[2].find('.').unwrap();
+        assert_eq!(buffer[(rows[2][..print].chars().count() as u16, 2)].bg, Color::Yellow);
+        let twelve = rows[8].find("12").unwrap();
+        assert_eq!(buffer[(rows[8][..twelve].chars().count() as u16, 8)].bg, Color::Yellow);
+        let seven = rows[8].find('7').unwrap();
+        assert_eq!(buffer[(rows[8][..seven].chars().count() as u16, 8)].bg, Color::Cyan);
+    }
+
+    #[test]
+    fn test_play() {
+        let program = Program::parse_str("[1] [3] { - . }");
+        let mut tui = Tui::new(program, std::io::empty(), None);
+        tui.speed = 0;
+
+        tui.key(KeyCode::Char(' '));
+        tui.tick(Duration::from_millis(500));
+        assert_eq!(tui.vm.steps, 0);
+        tui.tick(Duration::from_millis(600));
+        assert_eq!(tui.vm.steps, 1);
+
+        tui.speed = SPEEDS.len() - 1;
+        tui.tick(Duration::from_secs(1));
+        assert!(!tui.playing && tui.vm.pc.is_none());
+        assert_eq!(tui.vm.output, b"210");
+        let status = &screen(&mut tui).0[11];
+        assert!(status.starts_with("step 11  finished"), "{}", status);
+    }
+}
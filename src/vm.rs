@@ -1,160 +1,529 @@
-use crate::program::{Program, Table};
-use crate::ast::{Statement, StatementKind};
+use std::collections::BTreeMap;
+use std::io::{Read, Stdout, Write};
 
-pub(crate) struct Vm {
-    pub(crate) program: Program,
+use crate::program::{Program, TapePolicy, SAVE_REGISTERS};
+use crate::ast::{size, Condition, Statement, StatementKind};
+use crate::lexer::TokenLocation;
+
+/// How deeply calls can nest before the VM decides a function is recursing forever.
+pub(crate) const MAX_CALL_DEPTH: usize = 1 << 20;
+
+/// Where control goes once an instruction has run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Target {
+    /// The instruction with this number.
+    At(usize),
+    /// Back to whatever called the function being run.
+    Return,
+    /// Nowhere; the program is over.
+    Exit,
+}
+
+/// A statement with its blocks taken out, and where control goes after it.
+///
+/// Instructions are numbered the way bytecode numbers them: each statement is followed by the
+/// statements in its blocks. For a loop or an if, `next` is taken when the condition holds and
+/// `branch` when it doesn't, so a loop exits through `next` and an if runs its then block
+/// through `next`. A function definition skips its body through `next` and is called through
+/// `branch`. Everything else just goes on to `next`, which for a break or continue is out of or
+/// back around its loop.
+#[derive(Debug, Clone)]
+pub(crate) struct Instruction {
+    /// The statement, with any blocks it had left empty.
+    pub(crate) kind: StatementKind,
+    pub(crate) loc: TokenLocation,
+    pub(crate) next: Target,
+    pub(crate) branch: Target,
+}
+
+/// A program laid out to be run an instruction at a time.
+#[derive(Debug)]
+pub(crate) struct Code {
+    pub(crate) instructions: Vec<Instruction>,
+    /// The instruction each callable function is defined at.
+    pub(crate) functions: BTreeMap<usize, usize>,
+}
+
+impl Code {
+    pub(crate) fn new(program: &Program) -> Code {
+        let mut code = Code { instructions: Vec::new(), functions: BTreeMap::new() };
+        code.block(&program.statements, Target::Exit, None, true);
+
+        // Only top-level definitions can be called, and only the ones the program kept
+        code.functions.retain(|id, _| program.functions.contains_key(id));
+        code
+    }
+
+    /// Lay out a block that goes on to `after` once it's done. `innermost` is the innermost
+    /// loop's instruction and where it exits to, if the block is in one.
+    fn block(&mut self, stmts: &[Statement], after: Target, innermost: Option<(usize, Target)>, top_level: bool) {
+        for (idx, stmt) in stmts.iter().enumerate() {
+            let pc = self.instructions.len();
+            let next = match stmts.get(idx + 1) {
+                Some(_) => Target::At(pc + size(std::slice::from_ref(stmt))),
+                None => after,
+            };
+            // Where a block starting right after this instruction goes first
+            let start = |block: &[Statement], empty: Target| if block.is_empty() { empty } else { Target::At(pc + 1) };
+
+            let (kind, taken, branch) = match &stmt.kind {
+                StatementKind::Loop(body, until) => (StatementKind::Loop(Vec::new(), *until), next, start(body, Target::At(pc))),
+                StatementKind::If(condition, then, otherwise) => {
+                    let otherwise_start = match otherwise {
+                        Some(otherwise) if !otherwise.is_empty() => Target::At(pc + 1 + size(then)),
+                        _ => next,
+                    };
+                    (StatementKind::If(*condition, Vec::new(), otherwise.as_ref().map(|_| Vec::new())), start(then, next), otherwise_start)
+                },
+                StatementKind::DefineFn(id, locals, body) => (StatementKind::DefineFn(*id, *locals, Vec::new()), next, start(body, Target::Return)),
+                StatementKind::Break => (StatementKind::Break, innermost.map_or(next, |(_, exit)| exit), next),
+                StatementKind::Continue => (StatementKind::Continue, innermost.map_or(next, |(start, _)| Target::At(start)), next),
+                StatementKind::Return => (StatementKind::Return, Target::Return, next),
+                kind => (kind.clone(), next, next),
+            };
+            self.instructions.push(Instruction { kind, loc: stmt.token.loc.clone(), next: taken, branch });
+
+            match &stmt.kind {
+                StatementKind::Loop(body, _) => self.block(body, Target::At(pc), Some((pc, next)), false),
+                StatementKind::If(_, then, otherwise) => {
+                    self.block(then, next, innermost, false);
+                    self.block(otherwise.as_deref().unwrap_or_default(), next, innermost, false);
+                },
+                StatementKind::DefineFn(id, _, body) => {
+                    // Later definitions replace earlier ones, as they do in `Program::functions`
+                    if top_level {
+                        self.functions.insert(*id, pc);
+                    }
+                    self.block(body, Target::Return, None, false);
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+/// The caller's cursor and save registers, kept while a function runs in its own frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Frame {
+    /// The frame's first cell.
+    pub(crate) base: usize,
+    pub(crate) curr: usize,
+    pub(crate) saved: [usize; SAVE_REGISTERS],
+}
+
+/// A function call that hasn't returned yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Call {
+    pub(crate) return_to: Target,
+    /// Set if the function runs in a frame of its own.
+    pub(crate) frame: Option<Frame>,
+}
+
+/// A loop being run, with what its condition compares against. That's read once, when the
+/// loop is entered, so a `$` condition keeps comparing against the saved cell's value from then.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Looping {
+    /// The loop's instruction.
+    pub(crate) pc: usize,
+    /// How many calls deep it's being run.
+    pub(crate) depth: usize,
+    pub(crate) against: isize,
+}
+
+/// One thing a step changed, with what it takes to change it back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Change {
+    /// A cell was written; it held this before.
+    Cell(usize, isize),
+    /// The save registers were changed; they held these before.
+    Registers([usize; SAVE_REGISTERS]),
+    /// A frame was pushed onto a table this many cells long.
+    Pushed(usize),
+    /// A frame was popped, dropping these cells off the end of the table.
+    Popped(Vec<isize>),
+    Called,
+    Returned(Call),
+    /// A loop was entered.
+    Entered,
+    /// These loops were left, innermost last.
+    Left(Vec<Looping>),
+    /// This byte was read as input.
+    Read(u8),
+}
+
+/// What it takes to undo a step: the instruction it ran, where the cursor was, and everything
+/// else it changed, in the order it changed them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Undo {
+    pub(crate) pc: usize,
+    pub(crate) curr: usize,
+    pub(crate) changes: Vec<Change>,
+}
+
+/// Runs a program, reading `!` input from `input` and writing whatever it prints to `output`.
+pub struct Vm<W: Write = Stdout> {
+    pub program: Program,
+    pub(crate) code: Code,
+    /// The instruction to run next, or `None` once the program is over.
+    pub pc: Option<usize>,
+    /// Every call being run, innermost last.
+    pub(crate) calls: Vec<Call>,
+    /// Every loop being run, innermost last.
+    pub(crate) loops: Vec<Looping>,
+    /// How many instructions have run.
+    pub steps: u64,
+    /// How to undo every step run since `record` was called, oldest first.
+    pub(crate) history: Option<Vec<Undo>>,
+    /// What the step being run has changed so far, while recording.
+    changes: Vec<Change>,
+    /// Input that undone steps had read, to be read again before anything new, last first.
+    unread: Vec<u8>,
+    pub(crate) input: Box<dyn Read>,
+    pub output: W,
 }
 
 impl Vm {
-    pub(crate) fn new(program: Program) -> Vm {
-        Vm { program }
+    pub fn new(program: Program) -> Vm {
+        Vm::with_io(program, std::io::stdin(), std::io::stdout())
     }
+}
 
-    fn callfn(&mut self, defn: &Statement) {
-        match defn.kind.clone() {
-            StatementKind::DefineFn(_, stmts) => {
-                for stmt in stmts {
-                    self.execute(&stmt);
-                }
-            },
-            _ => {
-                // No?
-                unreachable!();
+impl<W: Write> Vm<W> {
+    /// A VM printing to `output` with nothing to read, so every `!` reads zero.
+    pub fn with_output(program: Program, output: W) -> Vm<W> {
+        Vm::with_io(program, std::io::empty(), output)
+    }
+
+    pub fn with_io(program: Program, input: impl Read + 'static, output: W) -> Vm<W> {
+        let code = Code::new(&program);
+        let pc = if code.instructions.is_empty() { None } else { Some(0) };
+        Vm { program, code, pc, calls: Vec::new(), loops: Vec::new(), steps: 0, history: None, changes: Vec::new(), unread: Vec::new(), input: Box::new(input), output }
+    }
+
+    fn log(&mut self, change: Change) {
+        if self.history.is_some() {
+            self.changes.push(change);
+        }
+    }
+
+    fn set(&mut self, idx: usize, value: isize) {
+        self.log(Change::Cell(idx, self.program.table[idx]));
+        self.program.table[idx] = value;
+    }
+
+    fn set_registers(&mut self, saved: [usize; SAVE_REGISTERS]) {
+        self.log(Change::Registers(self.program.table.saved));
+        self.program.table.saved = saved;
+    }
+
+    /// Store a value in the current cell, fitted to the table's cell width.
+    fn store(&mut self, value: isize) {
+        let curr = self.program.table.curr;
+        self.set(curr, self.program.table.fit(value));
+    }
+
+    fn write(&mut self, args: std::fmt::Arguments) {
+        self.output.write_fmt(args).expect("Unable to write program output");
+    }
+
+    /// Call the function `id`, which returns to `return_to`, and give where its body starts.
+    fn callfn(&mut self, id: usize, return_to: Target) -> Target {
+        let Some(&pc) = self.code.functions.get(&id) else {
+            panic!("Function {} was called, but no function has that id.", id);
+        };
+        let defn = &self.code.instructions[pc];
+        let (entry, locals) = match defn.kind {
+            StatementKind::DefineFn(_, locals, _) => (defn.branch, locals),
+            _ => unreachable!(),
+        };
+
+        if self.calls.len() == MAX_CALL_DEPTH {
+            panic!("Calls are nested more than {} deep; is a function recursing forever?", MAX_CALL_DEPTH);
+        }
+
+        // The frame's first cell holds a copy of the caller's cell, followed by the locals. The
+        // body starts there with it saved in `$`, and whatever is left in it is copied back to
+        // the caller's cell when it returns.
+        let frame = locals.map(|locals| {
+            let (curr, saved) = (self.program.table.curr, self.program.table.saved);
+            self.log(Change::Pushed(self.program.table.array.len()));
+            let base = self.program.table.push_frame(locals.saturating_add(1));
+            self.set(base, self.program.table[curr]);
+            self.program.table.curr = base;
+            let mut inside = saved;
+            inside[0] = base;
+            self.set_registers(inside);
+            Frame { base, curr, saved }
+        });
+        self.log(Change::Called);
+        self.calls.push(Call { return_to, frame });
+
+        entry
+    }
+
+    /// Send control to `target`, returning from as many calls as it takes to get somewhere.
+    fn jump(&mut self, mut target: Target) {
+        loop {
+            match target {
+                Target::At(pc) => {
+                    self.pc = Some(pc);
+                    return;
+                },
+                Target::Return if !self.calls.is_empty() => {
+                    let call = self.calls.pop().unwrap();
+                    if let Some(Frame { base, curr, saved }) = call.frame {
+                        // The caller gets its cursor and saved cells back
+                        let result = self.program.table[base];
+                        if self.history.is_some() {
+                            self.log(Change::Popped(self.program.table.array[base..].to_vec()));
+                        }
+                        self.program.table.pop_frame(base);
+                        self.set(curr, result);
+                        self.program.table.curr = curr;
+                        self.set_registers(saved);
+                    }
+                    // Along with any loops it was in the middle of
+                    let depth = self.calls.len();
+                    let inner = self.loops.iter().position(|looping| looping.depth > depth).unwrap_or(self.loops.len());
+                    self.leave(inner);
+                    target = call.return_to;
+                    self.log(Change::Returned(call));
+                },
+                // The semantic checks keep returns inside functions
+                Target::Return | Target::Exit => {
+                    self.pc = None;
+                    return;
+                },
             }
         }
     }
 
-    pub(crate) fn execute(&mut self, statement: &Statement) {
-        match statement.kind.clone() {
+    /// Leave every loop from the `from`th on.
+    fn leave(&mut self, from: usize) {
+        if from < self.loops.len() {
+            let left = self.loops.split_off(from);
+            self.log(Change::Left(left));
+        }
+    }
+
+    /// What the condition's parameter is right now.
+    fn against(&self, condition: &Condition) -> isize {
+        match condition.param {
+            crate::ast::ParameterKind::Numeric(val) => val,
+            crate::ast::ParameterKind::Saved(register) => self.program.table[self.program.table.saved[register]],
+        }
+    }
+
+    /// Whether the current cell compares to the condition's parameter the way it asks.
+    fn holds(&self, condition: &Condition) -> bool {
+        condition.comparison.holds(self.program.table[self.program.table.curr], self.against(condition))
+    }
+
+    /// Run the instruction at `pc`, and give where control goes next.
+    pub(crate) fn execute(&mut self, pc: usize) -> Target {
+        let Instruction { kind, next, branch, .. } = self.code.instructions[pc].clone();
+
+        match kind {
             crate::ast::StatementKind::Inc => {
                 // Increment current table cell
-                let curr = self.program.table.curr;
-                self.program.table[curr] = self.program.table[curr].checked_add(1).unwrap_or(self.program.table[curr]);
+                let value = self.program.table[self.program.table.curr];
+                self.store(value.checked_add(1).unwrap_or(value));
             },
             crate::ast::StatementKind::Dec => {
                 // Decrement current table cell
-                let curr = self.program.table.curr;
-                self.program.table[curr] = self.program.table[curr].checked_sub(1).unwrap_or(self.program.table[curr]);
+                let value = self.program.table[self.program.table.curr];
+                self.store(value.checked_sub(1).unwrap_or(value));
             },
             crate::ast::StatementKind::Mul => {
                 // Double current table cell
-                let curr = self.program.table.curr;
-                self.program.table[curr] = self.program.table[curr].checked_mul(2).unwrap_or(self.program.table[curr]);
+                let value = self.program.table[self.program.table.curr];
+                self.store(value.checked_mul(2).unwrap_or(value));
             },
             crate::ast::StatementKind::Div => {
                 // Halve current table cell
-                let curr = self.program.table.curr;
-                self.program.table[curr] = self.program.table[curr].checked_div(2).unwrap_or(self.program.table[curr]);
+                let value = self.program.table[self.program.table.curr];
+                self.store(value.checked_div(2).unwrap_or(value));
             },
             crate::ast::StatementKind::MovR => {
-                // Move table value to the right if possible
-                if self.program.table.curr < self.program.table.size.checked_sub(1).unwrap_or(0) {
-                    self.program.table.curr = self.program.table.curr + 1;
-                }
+                // Move table value to the right if possible, which includes into any function frames
+                let table = &mut self.program.table;
+                table.curr = match table.tape {
+                    TapePolicy::Wrap if table.curr + 1 == table.size => 0,
+                    _ if table.curr + 1 < table.array.len() => table.curr + 1,
+                    _ => table.curr,
+                };
             },
             crate::ast::StatementKind::MovL => {
                 // Move table value to the left if possible
-                if self.program.table.curr > 0 {
-                    self.program.table.curr = self.program.table.curr.checked_sub(1).unwrap_or(self.program.table.curr);
-                }
+                let table = &mut self.program.table;
+                table.curr = match table.tape {
+                    TapePolicy::Wrap if table.curr == 0 => table.size.saturating_sub(1),
+                    _ => table.curr.saturating_sub(1),
+                };
             },
             crate::ast::StatementKind::Print => {
                 let curr = self.program.table.curr;
-                print!("{}", self.program.table[curr]);
+                let value = self.program.table[curr];
+                self.write(format_args!("{}", value));
             },
-            crate::ast::StatementKind::Loop(stmts, cndt) => {
-                let condition_param = cndt.unwrap_or(crate::ast::ParameterKind::Numeric(0));
-                let condition = match condition_param {
-                    crate::ast::ParameterKind::Numeric(val) => val,
-                    crate::ast::ParameterKind::Saved => self.program.table[self.program.table.saved] as usize
-                };
-
-                let mut check_value = self.program.table[self.program.table.curr];
-                while check_value != condition as isize {
-                    for stmt in &stmts {
-                        self.execute(stmt);
-                    }
-                    check_value = self.program.table[self.program.table.curr];
+            crate::ast::StatementKind::Loop(_, cndt) => {
+                // Check before every time through the body, which jumps back here when it's done.
+                // Coming from anywhere else enters the loop afresh.
+                let condition = cndt.unwrap_or(Condition::equal(crate::ast::ParameterKind::Numeric(0)));
+                let depth = self.calls.len();
+                if !matches!(self.loops.last(), Some(looping) if looping.pc == pc && looping.depth == depth) {
+                    self.loops.push(Looping { pc, depth, against: self.against(&condition) });
+                    self.log(Change::Entered);
                 }
+                let against = self.loops.last().unwrap().against;
+                if !condition.comparison.holds(self.program.table[self.program.table.curr], against) {
+                    return branch;
+                }
+                self.leave(self.loops.len() - 1);
             },
             crate::ast::StatementKind::Define(val) => {
-                let curr = self.program.table.curr;
-                self.program.table[curr] = val as isize;
+                self.store(val);
             },
-            crate::ast::StatementKind::If(condition_param, stmts) => {
-                let condition = match condition_param {
-                    crate::ast::ParameterKind::Numeric(val) => val,
-                    crate::ast::ParameterKind::Saved => self.program.table[self.program.table.saved] as usize
-                };
-                
-                if self.program.table[self.program.table.curr] == condition as isize {
-                    for stmt in &stmts {
-                        self.execute(stmt);
-                    }
-                }
+            crate::ast::StatementKind::If(condition, ..) if !self.holds(&condition) => {
+                return branch;
             },
             crate::ast::StatementKind::Goto(param) => {
                 match param {
                     crate::ast::ParameterKind::Numeric(idx) => {
-                        self.program.table.curr = idx;
+                        // The parser only lets nonnegative indices through
+                        self.program.table.curr = idx as usize;
                     },
-                    crate::ast::ParameterKind::Saved => {
-                        self.program.table.curr = self.program.table.saved;
+                    crate::ast::ParameterKind::Saved(register) => {
+                        self.program.table.curr = self.program.table.saved[register];
                     },
                 }
             },
-            crate::ast::StatementKind::Save => {
-                self.program.table.saved = self.program.table.curr;
+            crate::ast::StatementKind::Save(register) => {
+                let mut saved = self.program.table.saved;
+                saved[register] = self.program.table.curr;
+                self.set_registers(saved);
             }
             crate::ast::StatementKind::PrintAscii => {
-                print!("{}", self.program.table[self.program.table.curr] as u8 as char);
+                let value = self.program.table[self.program.table.curr];
+                self.write(format_args!("{}", value as u8 as char));
             },
             crate::ast::StatementKind::Copy(param) => {
                 let curr = self.program.table.curr;
                 match param {
                     crate::ast::ParameterKind::Numeric(idx) => {
-                        self.program.table[curr] = self.program.table[idx];
+                        self.set(curr, self.program.table[idx as usize]);
                     },
-                    crate::ast::ParameterKind::Saved => {
-                        self.program.table[curr] = self.program.table[self.program.table.saved];
+                    crate::ast::ParameterKind::Saved(register) => {
+                        self.set(curr, self.program.table[self.program.table.saved[register]]);
                     },
                 }
             },
             crate::ast::StatementKind::Modulo => {
                 // Mod current table cell by 2
                 let curr = self.program.table.curr;
-                self.program.table[curr] = self.program.table[curr] % 2;
+                self.set(curr, self.program.table[curr] % 2);
+            },
+            crate::ast::StatementKind::Break => {
+                // The innermost loop is the last one entered, unless the break isn't in one
+                if matches!(self.loops.last(), Some(looping) if looping.depth == self.calls.len()) {
+                    self.leave(self.loops.len() - 1);
+                }
             },
             crate::ast::StatementKind::CallFn(id) => {
-                // There is no semantics phase!!! Hope your ID is valid!
-                let funcdef = self.program.functions.get(&id).unwrap().clone();
-                self.callfn(&funcdef);
+                return self.callfn(id, next);
             },
             crate::ast::StatementKind::PrintNewline => {
-                print!("\n");
+                self.write(format_args!("\n"));
             },
             crate::ast::StatementKind::FlipSign => {
-                let curr = self.program.table.curr;
-                self.program.table[curr] *= -1;
+                let value = self.program.table[self.program.table.curr];
+                self.store(value.checked_neg().unwrap_or(value));
+            },
+            crate::ast::StatementKind::Input => {
+                // Read a byte, or zero once input runs out
+                let mut byte = [0u8];
+                let read = match self.unread.pop() {
+                    Some(byte) => Some(byte),
+                    None => matches!(self.input.read(&mut byte), Ok(1)).then_some(byte[0]),
+                };
+                if let Some(byte) = read {
+                    self.log(Change::Read(byte));
+                }
+                self.store(read.map_or(0, |byte| byte as isize));
             },
             _ => {
-                // Unknown statement kind, do nothing.
+                // Definitions, continues, returns and ifs that hold only move control,
+                // which `next` already does. Anything else does nothing.
             }
         }
+
+        next
+    }
+
+    /// Run one instruction. Returns whether there's anything left to run.
+    pub fn step(&mut self) -> bool {
+        let Some(pc) = self.pc else { return false; };
+
+        let curr = self.program.table.curr;
+        let target = self.execute(pc);
+        self.steps += 1;
+        self.jump(target);
+
+        if let Some(history) = &mut self.history {
+            history.push(Undo { pc, curr, changes: std::mem::take(&mut self.changes) });
+        }
+
+        self.pc.is_some()
+    }
+
+    /// Keep what it takes to undo each step from here on, so they can be stepped back through.
+    pub(crate) fn record(&mut self) {
+        self.history.get_or_insert_with(Vec::new);
     }
 
-    pub(crate) fn run(&mut self, show_registers: bool) {
-        // Iterate over all statements of the program and run them
-        let mut pc = 0usize;
-        while pc < self.program.statements.len() {
-            let statement = self.program.statements.get(pc).unwrap().clone();
-            self.execute(&statement);
-            pc += 1;
-            // println!("{:?} / {:?} ", pc, self.program.statements.len());
+    /// Undo the last step recorded. Returns whether there was one. Output isn't taken back,
+    /// but input is, and is read again by the steps that read it.
+    pub(crate) fn step_back(&mut self) -> bool {
+        let Some(undo) = self.history.as_mut().and_then(Vec::pop) else { return false; };
+
+        let table = &mut self.program.table;
+        for change in undo.changes.into_iter().rev() {
+            match change {
+                Change::Cell(idx, value) => table[idx] = value,
+                Change::Registers(saved) => table.saved = saved,
+                Change::Pushed(len) => table.array.truncate(len),
+                Change::Popped(cells) => table.array.extend(cells),
+                Change::Called => {
+                    self.calls.pop();
+                },
+                Change::Returned(call) => self.calls.push(call),
+                Change::Entered => {
+                    self.loops.pop();
+                },
+                Change::Left(loops) => self.loops.extend(loops),
+                Change::Read(byte) => self.unread.push(byte),
+            }
         }
+        table.curr = undo.curr;
+        self.pc = Some(undo.pc);
+        self.steps -= 1;
+
+        true
+    }
+
+    /// Print the table, as `--show-registers` asks for once the program is done.
+    pub fn show_registers(&mut self) {
+        let array = format!("{:?}", self.program.table.array);
+        self.write(format_args!("{}\n", array));
+    }
+
+    pub fn run(&mut self, show_registers: bool) {
+        while self.step() {}
 
         if show_registers {
-            println!("{:?}", self.program.table.array);
+            self.show_registers();
         }
+
+        self.output.flush().expect("Unable to write program output");
     }
-}
\ No newline at end of file
+}
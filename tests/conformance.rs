@@ -0,0 +1,261 @@
+//! Golden-output conformance tests.
+//!
+//! Every `.mini` file under `examples/` and `tests/conformance/` is run through each backend in
+//! [`backends`], and what it printed plus the final state of its table are compared against the
+//! files checked in under `tests/expected/`, mirroring the source path:
+//!
+//! - `tests/expected/<path>.stdout` holds the exact bytes the program printed
+//! - `tests/expected/<path>.table` holds the table size, cursor, save registers and non-zero cells
+//!
+//! Run with `cargo test --test conformance`. After an intended behavior change, regenerate the
+//! expectations with `cargo test --test conformance -- --bless` (or `BLESS=1`) and review the diff.
+//! Blessing always uses the first backend, the reference VM.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use minlang2::bytecode;
+use minlang2::debugger::Debugger;
+use minlang2::dump;
+use minlang2::include;
+use minlang2::json;
+use minlang2::lexer::Lexer;
+use minlang2::macros;
+use minlang2::parser::Parser;
+use minlang2::program::Program;
+use minlang2::semantics;
+use minlang2::snapshot::Snapshot;
+use minlang2::vm::Vm;
+
+const SOURCE_DIRS: [&str; 2] = ["examples", "tests/conformance"];
+const EXPECTED_DIR: &str = "tests/expected";
+/// Searched for included files, after the including file's own directory.
+const LIBRARY_DIR: &str = "lib";
+
+/// What running a program left behind.
+struct Outcome {
+    stdout: Vec<u8>,
+    table: String,
+}
+
+/// Something that can run a minlang program. The VM is the reference; code generators should
+/// add an implementation here that compiles, runs, and reports back the same `Outcome`.
+trait Backend {
+    fn name(&self) -> &'static str;
+    fn run(&self, program: Program) -> Result<Outcome, String>;
+}
+
+struct VmBackend;
+
+impl Backend for VmBackend {
+    fn name(&self) -> &'static str {
+        "vm"
+    }
+
+    fn run(&self, program: Program) -> Result<Outcome, String> {
+        let mut vm = Vm::with_output(program, Vec::new());
+        vm.run(false);
+
+        let table = describe_table(&vm.program);
+        Ok(Outcome { stdout: vm.output, table })
+    }
+}
+
+/// The VM again, on the program after a trip through its AST JSON form, which has to lose
+/// nothing that changes how it runs.
+struct AstJsonBackend;
+
+impl Backend for AstJsonBackend {
+    fn name(&self) -> &'static str {
+        "ast-json"
+    }
+
+    fn run(&self, program: Program) -> Result<Outcome, String> {
+        let text = json::to_json(&program).to_string();
+        let program = json::load(&text).map_err(|err| format!("AST JSON error: {}", err))?;
+        semantics::check(&program).map_err(|err| format!("Semantic error after AST JSON: {}", err))?;
+
+        VmBackend.run(program)
+    }
+}
+
+/// The VM again, on the program loaded back from bytecode without its debug table.
+struct BytecodeBackend;
+
+impl Backend for BytecodeBackend {
+    fn name(&self) -> &'static str {
+        "bytecode"
+    }
+
+    fn run(&self, program: Program) -> Result<Outcome, String> {
+        let program = bytecode::read(&bytecode::write(&program, false)).map_err(|err| format!("Bytecode error: {}", err))?;
+        semantics::check(&program).map_err(|err| format!("Semantic error after bytecode: {}", err))?;
+
+        VmBackend.run(program)
+    }
+}
+
+/// The VM stopped partway, saved to a snapshot, and picked up again by a fresh VM.
+struct SnapshotBackend;
+
+/// How many steps the snapshot backend runs before stopping.
+const SNAPSHOT_STEPS: u64 = 50;
+
+impl Backend for SnapshotBackend {
+    fn name(&self) -> &'static str {
+        "snapshot"
+    }
+
+    fn run(&self, program: Program) -> Result<Outcome, String> {
+        let bytes = bytecode::write(&program, false);
+        let mut first = Vm::with_output(program, Vec::new());
+        while first.steps < SNAPSHOT_STEPS && first.step() {}
+        let snapshot = Snapshot::take(&first, Vec::new()).to_bytes();
+
+        let program = bytecode::read(&bytes).map_err(|err| format!("Bytecode error: {}", err))?;
+        let mut second = Vm::with_output(program, first.output);
+        Snapshot::from_bytes(&snapshot)
+            .and_then(|snapshot| snapshot.restore(&mut second))
+            .map_err(|err| format!("Snapshot error: {}", err))?;
+        second.run(false);
+
+        let table = describe_table(&second.program);
+        Ok(Outcome { stdout: second.output, table })
+    }
+}
+
+/// The debugger run to the end, back to the start, and forward again, which only comes out the
+/// same if every step undoes exactly what it did.
+struct ReverseBackend;
+
+impl Backend for ReverseBackend {
+    fn name(&self) -> &'static str {
+        "reverse"
+    }
+
+    fn run(&self, program: Program) -> Result<Outcome, String> {
+        let start = describe_table(&program);
+        let mut debugger = Debugger::new(program, std::io::empty(), None);
+        debugger.run_forward();
+        let steps = debugger.vm.steps;
+
+        debugger.goto(0);
+        if debugger.vm.steps != 0 || describe_table(&debugger.vm.program) != start || !debugger.vm.output.is_empty() {
+            return Err(format!("Stepping back through {} steps didn't get back to the start", steps));
+        }
+        debugger.run_forward();
+
+        let table = describe_table(&debugger.vm.program);
+        Ok(Outcome { stdout: debugger.vm.output, table })
+    }
+}
+
+fn backends() -> Vec<Box<dyn Backend>> {
+    vec![Box::new(VmBackend), Box::new(AstJsonBackend), Box::new(BytecodeBackend), Box::new(SnapshotBackend), Box::new(ReverseBackend)]
+}
+
+fn describe_table(program: &Program) -> String {
+    dump::compact(&program.table)
+}
+
+fn compile(path: &Path) -> Result<Program, String> {
+    let source = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let tokens = Lexer::new(source).lex().map_err(|err| err.to_string())?.tokens;
+    let tokens = include::include(tokens, Some(path), &[PathBuf::from(LIBRARY_DIR)]).map_err(|err| err.to_string())?;
+    let tokens = macros::expand(tokens).map_err(|err| err.to_string())?;
+
+    let program = Parser::new(tokens).parse_program().map_err(|err| err.to_string())?;
+    semantics::check(&program).map_err(|err| err.to_string())?;
+
+    Ok(program)
+}
+
+fn discover() -> Vec<PathBuf> {
+    fn walk(dir: &Path, found: &mut Vec<PathBuf>) {
+        let Ok(entries) = fs::read_dir(dir) else { return; };
+        for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+            if path.is_dir() {
+                walk(&path, found);
+            } else if path.extension().is_some_and(|ext| ext == "mini") {
+                found.push(path);
+            }
+        }
+    }
+
+    let mut found = Vec::new();
+    for dir in SOURCE_DIRS {
+        walk(Path::new(dir), &mut found);
+    }
+    found.sort();
+    found
+}
+
+fn expected_path(source: &Path, extension: &str) -> PathBuf {
+    let mut name = source.file_name().unwrap().to_os_string();
+    name.push(".");
+    name.push(extension);
+    Path::new(EXPECTED_DIR).join(source.with_file_name(name))
+}
+
+fn bless(source: &Path, outcome: &Outcome) -> Result<(), String> {
+    let stdout_path = expected_path(source, "stdout");
+    fs::create_dir_all(stdout_path.parent().unwrap()).map_err(|err| err.to_string())?;
+    fs::write(&stdout_path, &outcome.stdout).map_err(|err| err.to_string())?;
+    fs::write(expected_path(source, "table"), &outcome.table).map_err(|err| err.to_string())
+}
+
+fn check(source: &Path, outcome: &Outcome) -> Result<(), String> {
+    let stdout_path = expected_path(source, "stdout");
+    let table_path = expected_path(source, "table");
+
+    let stdout = fs::read(&stdout_path).map_err(|_| format!("missing {}; run with --bless", stdout_path.display()))?;
+    let table = fs::read_to_string(&table_path).map_err(|_| format!("missing {}; run with --bless", table_path.display()))?;
+
+    if stdout != outcome.stdout {
+        return Err(format!(
+            "output differs from {}\n--- expected\n{}\n--- actual\n{}",
+            stdout_path.display(), String::from_utf8_lossy(&stdout), String::from_utf8_lossy(&outcome.stdout)
+        ));
+    }
+    if table != outcome.table {
+        return Err(format!(
+            "table differs from {}\n--- expected\n{}--- actual\n{}",
+            table_path.display(), table, outcome.table
+        ));
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let blessing = std::env::args().any(|arg| arg == "--bless") || std::env::var_os("BLESS").is_some();
+    let backends = backends();
+    let mut failures = 0;
+    let mut total = 0;
+
+    for source in discover() {
+        for (idx, backend) in backends.iter().enumerate() {
+            // Blessing only records what the reference backend does
+            if blessing && idx > 0 {
+                continue;
+            }
+            total += 1;
+
+            let result = compile(&source)
+                .and_then(|program| backend.run(program))
+                .and_then(|outcome| if blessing { bless(&source, &outcome) } else { check(&source, &outcome) });
+
+            match result {
+                Ok(()) => println!("{} [{}] ... ok", source.display(), backend.name()),
+                Err(err) => {
+                    failures += 1;
+                    println!("{} [{}] ... FAILED\n{}", source.display(), backend.name(), err);
+                }
+            }
+        }
+    }
+
+    println!("\nconformance: {} passed; {} failed", total - failures, failures);
+    if failures == 0 { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
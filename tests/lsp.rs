@@ -0,0 +1,225 @@
+//! Scripted JSON-RPC sessions against the language server.
+
+use std::fs;
+use std::io::Cursor;
+
+use serde_json::{json, Value};
+
+use minlang2::lsp::{read_message, write_message, Server};
+
+const URI: &str = "file:///example.mini";
+
+const SOURCE: &str = "[32]\n# helpers\n:(0)\n    ++>?(0)-<;\n;\n:(1) &_._ ;\n++^(0)^(1)^(1)\n";
+
+/// Run a whole session through `Server::serve` and collect everything it wrote back.
+fn session(messages: &[Value]) -> Vec<Value> {
+    let mut input = Vec::new();
+    for message in messages {
+        write_message(&mut input, message).unwrap();
+    }
+
+    let mut output = Vec::new();
+    Server::new().serve(Cursor::new(input), &mut output).unwrap();
+
+    let mut reader = Cursor::new(output);
+    let mut replies = Vec::new();
+    while let Some(reply) = read_message(&mut reader).unwrap() {
+        replies.push(reply);
+    }
+    replies
+}
+
+fn request(id: u64, method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+fn open(text: &str) -> Value {
+    notification("textDocument/didOpen", json!({
+        "textDocument": { "uri": URI, "languageId": "minlang", "version": 1, "text": text }
+    }))
+}
+
+fn at(line: u64, character: u64) -> Value {
+    json!({ "textDocument": { "uri": URI }, "position": { "line": line, "character": character } })
+}
+
+fn response(replies: &[Value], id: u64) -> &Value {
+    replies.iter().find(|reply| reply["id"] == id).expect("no response to request")
+}
+
+fn diagnostics(replies: &[Value]) -> Vec<&Value> {
+    replies.iter().filter(|reply| reply["method"] == "textDocument/publishDiagnostics").collect()
+}
+
+#[test]
+fn test_initialize_and_shutdown() {
+    let replies = session(&[
+        request(1, "initialize", json!({ "capabilities": {} })),
+        notification("initialized", json!({})),
+        request(2, "shutdown", Value::Null),
+        request(3, "textDocument/hover", at(0, 0)),
+        notification("exit", Value::Null),
+        // Nothing after exit is read
+        request(4, "shutdown", Value::Null),
+    ]);
+
+    let capabilities = &response(&replies, 1)["result"]["capabilities"];
+    assert_eq!(capabilities["hoverProvider"], true);
+    assert_eq!(capabilities["documentFormattingProvider"], true);
+    assert_eq!(response(&replies, 2)["result"], Value::Null);
+    assert!(response(&replies, 3)["error"].is_object());
+    assert_eq!(replies.len(), 3);
+}
+
+#[test]
+fn test_diagnostics() {
+    let replies = session(&[
+        open(SOURCE),
+        notification("textDocument/didChange", json!({
+            "textDocument": { "uri": URI, "version": 2 },
+            "contentChanges": [{ "text": "[8]\n+=( )" }],
+        })),
+        notification("textDocument/didChange", json!({
+            "textDocument": { "uri": URI, "version": 3 },
+            "contentChanges": [{ "text": "[8]\n^(7)" }],
+        })),
+        notification("textDocument/didClose", json!({ "textDocument": { "uri": URI } })),
+    ]);
+
+    let published = diagnostics(&replies);
+    assert_eq!(published.len(), 4);
+    assert_eq!(published[0]["params"]["diagnostics"], json!([]));
+
+    let parse_error = &published[1]["params"]["diagnostics"][0];
+    assert_eq!(parse_error["range"]["start"], json!({ "line": 1, "character": 4 }));
+    assert!(parse_error["message"].as_str().unwrap().contains("Expected a number or '$'"));
+
+    let undefined = &published[2]["params"]["diagnostics"][0];
+    assert_eq!(undefined["range"], json!({ "start": { "line": 1, "character": 0 }, "end": { "line": 1, "character": 4 } }));
+    assert_eq!(undefined["message"], "Function 7 is never defined.");
+
+    assert_eq!(published[3]["params"]["diagnostics"], json!([]));
+}
+
+#[test]
+fn test_includes() {
+    let dir = env!("CARGO_MANIFEST_DIR");
+    let uri = format!("file://{}/tests/example.mini", dir);
+    let replies = session(&[
+        request(1, "initialize", json!({ "capabilities": {}, "initializationOptions": { "includePaths": [format!("{}/lib", dir)] } })),
+        notification("textDocument/didOpen", json!({
+            "textDocument": { "uri": uri, "languageId": "minlang", "version": 1, "text": "[8]\n\"print.mini\"\n[4]^(print_digit)" }
+        })),
+        request(2, "textDocument/hover", json!({ "textDocument": { "uri": uri }, "position": { "line": 2, "character": 5 } })),
+        notification("textDocument/didChange", json!({
+            "textDocument": { "uri": uri, "version": 2 },
+            "contentChanges": [{ "text": "[8]\n\"print.mini\"\n\"missing.mini\"" }],
+        })),
+    ]);
+
+    // Functions from the included file are defined, and hovering a call shows where
+    let published = diagnostics(&replies);
+    assert_eq!(published[0]["params"]["diagnostics"], json!([]));
+    let hover = response(&replies, 2)["result"]["contents"]["value"].as_str().unwrap();
+    assert!(hover.contains(&format!("of {}/lib/print.mini", dir)), "{}", hover);
+
+    let missing = &published[1]["params"]["diagnostics"][0];
+    assert_eq!(missing["range"], json!({ "start": { "line": 2, "character": 0 }, "end": { "line": 2, "character": 14 } }));
+    assert!(missing["message"].as_str().unwrap().starts_with("Can't find \"missing.mini\""));
+}
+
+#[test]
+fn test_includes_next_to_escaped_path() {
+    // A directory whose name has to be percent-encoded in a URI
+    let dir = std::env::temp_dir().join(format!("minlang2 lsp {}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("helper.mini"), ":(7) . ;").unwrap();
+
+    let uri = format!("file://{}/main.mini", dir.display()).replace(' ', "%20");
+    let replies = session(&[notification("textDocument/didOpen", json!({
+        "textDocument": { "uri": uri, "languageId": "minlang", "version": 1, "text": "[4]\n\"helper.mini\"\n^(7)" }
+    }))]);
+    let _ = fs::remove_dir_all(&dir);
+
+    assert_eq!(diagnostics(&replies)[0]["params"]["diagnostics"], json!([]));
+}
+
+#[test]
+fn test_hover() {
+    let replies = session(&[
+        open(SOURCE),
+        request(1, "textDocument/hover", at(6, 0)),
+        request(2, "textDocument/hover", at(6, 4)),
+        request(3, "textDocument/hover", at(2, 1)),
+        request(4, "textDocument/hover", at(1, 3)),
+    ]);
+
+    let hover = |id| response(&replies, id)["result"]["contents"]["value"].as_str().unwrap_or("").to_string();
+    assert_eq!(hover(1), "`+` adds one to the current cell.");
+    assert!(hover(2).starts_with("`^(0)` calls function 0.\n\nDefined on line 3:\n```minlang\n:(0)\n    ++>?(0)-<;\n;\n```"));
+    assert_eq!(hover(3), "`:(0)` defines function 0, called 1 time(s).");
+    // Nothing to say about comments
+    assert_eq!(response(&replies, 4)["result"], Value::Null);
+}
+
+#[test]
+fn test_definition_and_references() {
+    let mut references = at(6, 8);
+    references["context"] = json!({ "includeDeclaration": false });
+
+    let replies = session(&[
+        open(SOURCE),
+        request(1, "textDocument/definition", at(6, 8)),
+        request(2, "textDocument/references", references),
+        request(3, "textDocument/references", at(5, 1)),
+    ]);
+
+    assert_eq!(response(&replies, 1)["result"], json!({
+        "uri": URI,
+        "range": { "start": { "line": 5, "character": 0 }, "end": { "line": 5, "character": 4 } },
+    }));
+
+    let calls = response(&replies, 2)["result"].as_array().unwrap().clone();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[0]["range"]["start"], json!({ "line": 6, "character": 6 }));
+    assert_eq!(calls[1]["range"]["start"], json!({ "line": 6, "character": 10 }));
+
+    assert_eq!(response(&replies, 3)["result"].as_array().unwrap().len(), 3);
+}
+
+#[test]
+fn test_document_symbols() {
+    let replies = session(&[open(SOURCE), request(1, "textDocument/documentSymbol", json!({ "textDocument": { "uri": URI } }))]);
+
+    let symbols = response(&replies, 1)["result"].as_array().unwrap().clone();
+    assert_eq!(symbols.len(), 2);
+    assert_eq!(symbols[0]["name"], "function 0");
+    // The body's nested `?(0)...;` doesn't end the function early
+    assert_eq!(symbols[0]["range"]["end"], json!({ "line": 4, "character": 1 }));
+    assert_eq!(symbols[1]["name"], "function 1");
+}
+
+#[test]
+fn test_formatting() {
+    let replies = session(&[
+        open("[32]\n# helpers\n:(0)++>?(0)-<;;  ++ ^(0)"),
+        request(1, "textDocument/formatting", json!({ "textDocument": { "uri": URI }, "options": { "tabSize": 4, "insertSpaces": true } })),
+    ]);
+
+    let edits = response(&replies, 1)["result"].as_array().unwrap().clone();
+    assert_eq!(edits.len(), 1);
+    assert_eq!(edits[0]["newText"], "[32]\n# helpers\n:(0)\n    ++>?(0)-<;\n;\n++^(0)\n");
+    assert_eq!(edits[0]["range"]["end"], json!({ "line": 2, "character": 24 }));
+}
+
+#[test]
+fn test_unknown_method() {
+    let replies = session(&[request(1, "workspace/symbol", json!({ "query": "" })), notification("$/cancelRequest", json!({ "id": 1 }))]);
+
+    assert_eq!(replies.len(), 1);
+    assert_eq!(response(&replies, 1)["error"]["code"], -32601);
+}